@@ -29,7 +29,7 @@ pub enum TextDirection {
     Vertical,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NamedFontPrediction {
     pub index: usize,
     pub name: String,
@@ -38,7 +38,7 @@ pub struct NamedFontPrediction {
     pub serif: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FontPrediction {
     pub top_fonts: Vec<(usize, f32)>,
     pub named_fonts: Vec<NamedFontPrediction>,
@@ -1,11 +1,15 @@
 mod model;
 pub mod prompt;
+mod quantized_gemma2;
 mod quantized_hunyuan_dense;
 mod quantized_lfm2;
 mod tokenizer;
 
-pub use model::{GenerateOptions, Llm};
-pub use prompt::{ChatMessage, ChatRole, set_default_locale, set_locale};
+pub use model::{GenerateOptions, Llm, LocalModel};
+pub use prompt::{
+    ChatMessage, ChatRole, GlossaryEntry, PromptTemplates, TranslationContext, TranslationPair,
+    get_default_locale, set_default_locale, set_locale, set_prompt_dir,
+};
 
 macro_rules! define_languages {
     ( $( $code:literal => $name:literal ),* $(,)? ) => {
@@ -1,22 +1,33 @@
-use std::io::Seek;
+use std::io::{Read, Seek};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use candle_core::quantized::gguf_file;
 use candle_core::{Device, Tensor};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
-use candle_transformers::models::{quantized_llama, quantized_qwen2};
+use candle_transformers::models::{
+    quantized_gemma3, quantized_llama, quantized_phi3, quantized_qwen2, quantized_qwen3,
+};
+use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
 use crate::device;
-use crate::llm::prompt::PromptRenderer;
+use crate::llm::prompt::{PromptRenderer, PromptTemplates, TranslationContext, TranslationPair};
 use crate::llm::tokenizer::TokenizerFromGguf;
-use crate::llm::{ModelId, quantized_hunyuan_dense, quantized_lfm2};
+use crate::llm::{ModelId, quantized_gemma2, quantized_hunyuan_dense, quantized_lfm2};
 
+#[derive(Clone)]
 pub enum Model {
     Llama(quantized_llama::ModelWeights),
     Qwen2(quantized_qwen2::ModelWeights),
     Lfm2(quantized_lfm2::ModelWeights),
     HunyuanDense(quantized_hunyuan_dense::ModelWeights),
+    Gemma2(quantized_gemma2::ModelWeights),
+    Gemma3(quantized_gemma3::ModelWeights),
+    Qwen3(quantized_qwen3::ModelWeights),
+    Phi3(quantized_phi3::ModelWeights),
 }
 
 impl Model {
@@ -26,27 +37,196 @@ impl Model {
             Model::Qwen2(m) => m.forward(input, pos),
             Model::Lfm2(m) => m.forward(input, pos),
             Model::HunyuanDense(m) => m.forward(input, pos),
+            Model::Gemma2(m) => m.forward(input, pos),
+            Model::Gemma3(m) => m.forward(input, pos),
+            Model::Qwen3(m) => m.forward(input, pos),
+            Model::Phi3(m) => m.forward(input, pos),
         }
     }
+
+    /// Whether a prompt can be fed in one pass after earlier tokens are in the
+    /// KV cache. The other implementations only mask prompts starting at
+    /// position 0 correctly, or keep state that a multi-token pass resets.
+    fn prefills_at_offset(&self) -> bool {
+        matches!(
+            self,
+            Model::HunyuanDense(_) | Model::Gemma2(_) | Model::Qwen3(_)
+        )
+    }
+
+    /// Loads the weights with the implementation for `arch`, the
+    /// `general.architecture` of the GGUF file.
+    fn from_gguf<R: Read + Seek>(
+        arch: &str,
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        Ok(match arch {
+            "llama" => Model::Llama(quantized_llama::ModelWeights::from_gguf(
+                ct, reader, device,
+            )?),
+            "hunyuan-dense" => Model::HunyuanDense(
+                quantized_hunyuan_dense::ModelWeights::from_gguf(ct, reader, device)?,
+            ),
+            "qwen2" => Model::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
+                ct, reader, device,
+            )?),
+            "lfm2" => Model::Lfm2(quantized_lfm2::ModelWeights::from_gguf(ct, reader, device)?),
+            "gemma2" => Model::Gemma2(quantized_gemma2::ModelWeights::from_gguf(
+                ct, reader, device,
+            )?),
+            "gemma3" => Model::Gemma3(quantized_gemma3::ModelWeights::from_gguf(
+                ct, reader, device,
+            )?),
+            "qwen3" => Model::Qwen3(quantized_qwen3::ModelWeights::from_gguf(
+                ct, reader, device,
+            )?),
+            "phi3" => Model::Phi3(quantized_phi3::ModelWeights::from_gguf(
+                false, ct, reader, device,
+            )?),
+            _ => anyhow::bail!("unsupported model architecture: {arch}"),
+        })
+    }
+}
+
+/// Token the chat models of `arch` close their turn with, when it differs from
+/// the EOS token in the GGUF metadata.
+fn end_of_turn_token(arch: &str) -> Option<&'static str> {
+    match arch {
+        "gemma2" | "gemma3" => Some("<end_of_turn>"),
+        "phi3" => Some("<|end|>"),
+        "qwen3" => Some("<|im_end|>"),
+        _ => None,
+    }
+}
+
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Drops the reasoning block Qwen3 models may open their reply with.
+fn strip_thinking(text: &str) -> &str {
+    let trimmed = text.trim_start();
+    match (trimmed.strip_prefix("<think>"), trimmed.find("</think>")) {
+        (Some(_), Some(end)) => trimmed[end + "</think>".len()..].trim_start(),
+        _ => text,
+    }
+}
+
+/// Decodes the generated tokens as they come in. Text ending in an incomplete
+/// UTF-8 sequence is held back until the next token completes it. Only the
+/// tokens since the last piece are decoded, starting one piece earlier so
+/// decoders that treat the first token specially see the same context.
+#[derive(Default)]
+struct TextStream {
+    /// Start of the tokens decoded for context only.
+    prefix: usize,
+    /// Start of the tokens not handed out yet.
+    read: usize,
+}
+
+impl TextStream {
+    fn next(&mut self, tokenizer: &Tokenizer, tokens: &[u32]) -> Result<Option<String>> {
+        let decode = |tokens: &[u32]| tokenizer.decode(tokens, true).map_err(anyhow::Error::msg);
+        let before = decode(&tokens[self.prefix..self.read])?;
+        let text = decode(&tokens[self.prefix..])?;
+        if text.len() <= before.len()
+            || text.ends_with(char::REPLACEMENT_CHARACTER)
+            || !text.is_char_boundary(before.len())
+        {
+            return Ok(None);
+        }
+        self.prefix = self.read;
+        self.read = tokens.len();
+        Ok(Some(text[before.len()..].to_string()))
+    }
+}
+
+/// A GGUF file registered by the user, loaded like the bundled models.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalModel {
+    pub id: String,
+    pub path: PathBuf,
+    /// Name shown instead of the id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Target languages shown in the UI.
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Id of the bundled model whose prompt layout to follow, e.g.
+    /// `sakura-galtransl-7b-v3.7` for a Sakura fine-tune. A generic instruction
+    /// prompt is used when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_format: Option<String>,
+    /// Prompt templates, files in the prompt directory take precedence.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub prompt: PromptTemplates,
+    /// Chat template for files without one in their metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_template: Option<String>,
+}
+
+fn is_default(templates: &PromptTemplates) -> bool {
+    *templates == PromptTemplates::default()
+}
+
+impl LocalModel {
+    fn format(&self) -> Result<Option<ModelId>> {
+        self.prompt_format
+            .as_deref()
+            .map(|format| {
+                ModelId::from_str(format)
+                    .with_context(|| format!("unknown prompt format `{format}`"))
+            })
+            .transpose()
+    }
+}
+
+/// Where the weights of an [`Llm`] come from.
+enum Source<'a> {
+    Bundled(ModelId),
+    Local(&'a LocalModel),
+}
+
+/// The model right after feeding a prompt prefix, with its KV cache.
+struct PrefixCache {
+    tokens: Vec<u32>,
+    model: Model,
 }
 
+/// Context window assumed when the GGUF metadata does not state one.
+const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+
 /// Minimal quantized LLM wrapper
 pub struct Llm {
     device: Device,
     model: Model,
     tokenizer: Tokenizer,
     prompt_renderer: PromptRenderer,
-    eos_token_id: u32,
+    /// Tokens that end the reply: the EOS token and the end-of-turn token of
+    /// the chat format, if any.
+    stop_token_ids: Vec<u32>,
+    /// Most tokens the model attends to, prompt and generated tokens together.
+    context_length: usize,
+    /// State after the prefix the last prompts had in common.
+    prefix_cache: Option<PrefixCache>,
+    last_prompt: Vec<u32>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct GenerateOptions {
     pub max_tokens: usize,
+    /// Sampling temperature, 0 always picks the most likely token, which makes
+    /// the output reproducible.
     pub temperature: f64,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub seed: u64,
     pub split_prompt: bool,
+    /// Penalty for tokens among the last `repeat_last_n` ones, 1 disables it.
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
 }
@@ -67,14 +247,38 @@ impl Default for GenerateOptions {
     }
 }
 
+impl GenerateOptions {
+    /// Rejects settings the sampler cannot work with.
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(self.max_tokens > 0, "maxTokens must be positive");
+        anyhow::ensure!(self.temperature >= 0.0, "temperature must not be negative");
+        anyhow::ensure!(self.top_k != Some(0), "topK must be positive");
+        anyhow::ensure!(
+            self.top_p.is_none_or(|p| p > 0.0 && p <= 1.0),
+            "topP must be in (0, 1]"
+        );
+        anyhow::ensure!(self.repeat_penalty > 0.0, "repeatPenalty must be positive");
+        Ok(())
+    }
+}
+
 impl Llm {
     /// Constructs a new LLM instance from a quantized GGUF model (including tokenizer metadata).
     pub async fn load(id: ModelId, use_cpu: bool) -> Result<Self> {
         let model_path = id.get().await?;
+        Self::from_gguf(&model_path, Source::Bundled(id), use_cpu)
+    }
 
+    /// Constructs an LLM from a GGUF file registered by the user.
+    pub async fn load_local(model: &LocalModel, use_cpu: bool) -> Result<Self> {
+        Self::from_gguf(&model.path, Source::Local(model), use_cpu)
+    }
+
+    fn from_gguf(model_path: &Path, source: Source<'_>, use_cpu: bool) -> Result<Self> {
         // Peek GGUF metadata to choose device/loader
-        let mut file = std::fs::File::open(&model_path)?;
-        let ct = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&model_path))?;
+        let mut file = std::fs::File::open(model_path)
+            .with_context(|| format!("failed to open {}", model_path.display()))?;
+        let ct = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
         let tokenizer = Tokenizer::from_gguf(&ct)?;
         let metadata = ct.metadata.clone();
         let md_get = |s: &str| {
@@ -83,15 +287,30 @@ impl Llm {
                 .ok_or_else(|| anyhow::anyhow!("missing GGUF metadata key `{s}`"))
         };
         let arch = md_get("general.architecture")?.to_string()?;
-        let chat_template = md_get("tokenizer.ggml.chat_template")
-            .or_else(|_| md_get("tokenizer.chat_template"))?
-            .to_string()?;
+        let chat_template = match &source {
+            Source::Local(LocalModel {
+                chat_template: Some(template),
+                ..
+            }) => template.clone(),
+            _ => md_get("tokenizer.ggml.chat_template")
+                .or_else(|_| md_get("tokenizer.chat_template"))?
+                .to_string()?
+                .clone(),
+        };
         let bos_token_id = md_get("tokenizer.ggml.bos_token_id")?.to_u32()?;
+        let context_length = md_get(&format!("{arch}.context_length"))
+            .and_then(|value| Ok(value.to_u32()? as usize))
+            .unwrap_or(DEFAULT_CONTEXT_LENGTH);
+        // the llama implementation precomputes its rotary tables for a fixed length
+        let context_length = match arch.as_str() {
+            "llama" => context_length.min(quantized_llama::MAX_SEQ_LEN),
+            _ => context_length,
+        };
         let eos_token_id = md_get("tokenizer.ggml.eos_token_id")?.to_u32()?;
 
         // The gguf metadata for Sakura1.5bQwen2.5v1.0 has wrong eos_token_id, override it here
-        let eos_token_id = match id {
-            ModelId::Sakura1_5bQwen2_5v1_0 => 151645,
+        let eos_token_id = match source {
+            Source::Bundled(ModelId::Sakura1_5bQwen2_5v1_0) => 151645,
             _ => eos_token_id,
         };
 
@@ -103,51 +322,78 @@ impl Llm {
         let eos_token = tokenizer
             .id_to_token(eos_token_id)
             .unwrap_or_else(|| eos_token_id.to_string());
-        let prompt_renderer = PromptRenderer::new(id, chat_template.clone(), bos_token, eos_token);
+        let prompt_renderer = match source {
+            Source::Bundled(id) => PromptRenderer::new(id, chat_template, bos_token, eos_token),
+            Source::Local(model) => PromptRenderer::custom(
+                model.id.clone(),
+                model.format()?,
+                model.prompt.clone(),
+                chat_template,
+                bos_token,
+                eos_token,
+            ),
+        };
 
         // Rewind reader before loading tensors
         file.rewind()?;
 
         // Load quantized model for the chosen architecture
-        let model = match arch.as_str() {
-            "llama" => Model::Llama(quantized_llama::ModelWeights::from_gguf(
-                ct, &mut file, &device,
-            )?),
-            "hunyuan-dense" => Model::HunyuanDense(
-                quantized_hunyuan_dense::ModelWeights::from_gguf(ct, &mut file, &device)?,
-            ),
-            "qwen2" => Model::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
-                ct, &mut file, &device,
-            )?),
-            "lfm2" => Model::Lfm2(quantized_lfm2::ModelWeights::from_gguf(
-                ct, &mut file, &device,
-            )?),
-            _ => anyhow::bail!("unsupported model architecture: {arch}"),
-        };
+        let model = Model::from_gguf(arch, ct, &mut file, &device)?;
+
+        let mut stop_token_ids = vec![eos_token_id];
+        stop_token_ids.extend(
+            end_of_turn_token(arch)
+                .and_then(|token| tokenizer.token_to_id(token))
+                .filter(|id| *id != eos_token_id),
+        );
 
         Ok(Self {
             device,
             model,
             tokenizer,
             prompt_renderer,
-            eos_token_id,
+            stop_token_ids,
+            context_length,
+            prefix_cache: None,
+            last_prompt: Vec::new(),
         })
     }
 
+    /// Whether the prompt of this model asks to keep the numbers of batched lines.
+    pub fn keeps_line_numbers(&self) -> bool {
+        self.prompt_renderer.keeps_line_numbers()
+    }
+
     /// Generate up to `max_tokens` following `prompt` using temperature/top-k/p settings.
     /// Logs simple performance metrics via `tracing`.
     pub fn generate(&mut self, prompt: &str, opts: &GenerateOptions) -> Result<String> {
-        let prompt = self
-            .prompt_renderer
-            .format_chat_prompt(prompt.to_string())?;
-        tracing::info!("Generating with prompt:\n{}", prompt);
+        self.generate_with_context(prompt, &TranslationContext::default(), opts)
+    }
 
-        // Encode prompt
-        let enc = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?;
-        let prompt_tokens: Vec<u32> = enc.get_ids().to_vec();
+    /// Like [`Llm::generate`], with earlier translations and the glossary as
+    /// context. The oldest pairs are dropped until the prompt and `max_tokens`
+    /// fit the context window.
+    pub fn generate_with_context(
+        &mut self,
+        prompt: &str,
+        context: &TranslationContext,
+        opts: &GenerateOptions,
+    ) -> Result<String> {
+        self.generate_streaming(prompt, context, opts, |_| ControlFlow::Continue(()))
+    }
+
+    /// Like [`Llm::generate_with_context`], passing each piece of decoded text to
+    /// `on_text` as soon as it is generated. Generation ends early, returning the
+    /// text so far, when `on_text` breaks.
+    pub fn generate_streaming(
+        &mut self,
+        prompt: &str,
+        context: &TranslationContext,
+        opts: &GenerateOptions,
+        mut on_text: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> Result<String> {
+        let (prompt, prompt_tokens) = self.fit_context(prompt, context, opts.max_tokens)?;
+        tracing::info!("Generating with prompt:\n{}", prompt);
         let mut all_tokens: Vec<u32> = Vec::new();
 
         // Build sampler
@@ -168,19 +414,10 @@ impl Llm {
 
         // Process prompt (all at once or token by token)
         let start_prompt_processing = std::time::Instant::now();
-        let mut next_token = if !opts.split_prompt {
-            let input = Tensor::new(prompt_tokens.as_slice(), &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, 0)?.squeeze(0)?;
-            logits_processor.sample(&logits)?
-        } else {
-            let mut next_token = 0u32;
-            for (pos, token) in prompt_tokens.iter().enumerate() {
-                let input = Tensor::new(&[*token], &self.device)?.unsqueeze(0)?;
-                let logits = self.model.forward(&input, pos)?.squeeze(0)?;
-                next_token = logits_processor.sample(&logits)?;
-            }
-            next_token
-        };
+        let logits = self
+            .prefill(&prompt_tokens, opts.split_prompt)?
+            .squeeze(0)?;
+        let mut next_token = logits_processor.sample(&logits)?;
         let prompt_dt = start_prompt_processing.elapsed();
 
         tracing::info!(
@@ -193,16 +430,24 @@ impl Llm {
             }
         );
 
-        if next_token == self.eos_token_id {
+        if self.stop_token_ids.contains(&next_token) {
             tracing::warn!("Early stopping: EOS token generated at end of prompt");
             return Ok("".to_string());
         }
 
         all_tokens.push(next_token);
+        let mut stream = TextStream::default();
+        let mut stopped = stream
+            .next(&self.tokenizer, &all_tokens)?
+            .is_some_and(|text| on_text(&text).is_break());
         // Generate tokens autoregressively
         let start_post_prompt = std::time::Instant::now();
         let mut sampled = 0usize;
         for index in 0..opts.max_tokens.saturating_sub(1) {
+            if stopped {
+                tracing::info!("Generation stopped by the caller");
+                break;
+            }
             let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
             let logits = self
                 .model
@@ -221,9 +466,12 @@ impl Llm {
             next_token = logits_processor.sample(&logits)?;
             all_tokens.push(next_token);
             sampled += 1;
-            if next_token == self.eos_token_id {
+            if self.stop_token_ids.contains(&next_token) {
                 break;
             }
+            stopped = stream
+                .next(&self.tokenizer, &all_tokens)?
+                .is_some_and(|text| on_text(&text).is_break());
         }
         let gen_dt = start_post_prompt.elapsed();
 
@@ -237,8 +485,448 @@ impl Llm {
             }
         );
 
-        self.tokenizer
+        let text = self
+            .tokenizer
             .decode(&all_tokens, true)
-            .map_err(anyhow::Error::msg)
+            .map_err(anyhow::Error::msg)?;
+        Ok(strip_thinking(&text).to_string())
+    }
+
+    /// Formats and tokenizes `prompt`, dropping the oldest context pairs until it
+    /// leaves room for `max_tokens`. Each pair is tokenized once and dropped by
+    /// its token count, plus its share of the chat markup around the pairs.
+    fn fit_context(
+        &self,
+        prompt: &str,
+        context: &TranslationContext,
+        max_tokens: usize,
+    ) -> Result<(String, Vec<u32>)> {
+        let templates = self.prompt_renderer.templates();
+        let history = context.history.as_slice();
+        let format = |history: &[TranslationPair]| -> Result<(String, Vec<u32>)> {
+            let formatted = self.prompt_renderer.format_with_templates(
+                prompt.to_string(),
+                history,
+                &context.glossary,
+                &templates,
+            )?;
+            let tokens = self.tokenize(&formatted, true)?;
+            Ok((formatted, tokens))
+        };
+
+        let full = format(history)?;
+        let budget = self.context_length.saturating_sub(max_tokens);
+        if full.1.len() <= budget || history.is_empty() {
+            return Ok(full);
+        }
+
+        let bare = format(&[])?;
+        let mut costs = history
+            .iter()
+            .map(|pair| {
+                Ok(self.tokenize(&pair.source, false)?.len()
+                    + self.tokenize(&pair.translation, false)?.len())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let markup = full
+            .1
+            .len()
+            .saturating_sub(bare.1.len() + costs.iter().sum::<usize>());
+        for (index, cost) in costs.iter_mut().enumerate() {
+            // spread the markup evenly, the first pairs take the remainder
+            *cost += markup / history.len() + usize::from(index < markup % history.len());
+        }
+
+        let mut length = full.1.len();
+        let mut skip = 0;
+        while length > budget && skip < history.len() {
+            length = length.saturating_sub(costs[skip]);
+            skip += 1;
+        }
+        tracing::info!("Dropped {skip} context lines to fit the context window");
+        match skip == history.len() {
+            true => Ok(bare),
+            false => format(&history[skip..]),
+        }
+    }
+
+    fn tokenize(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    /// Feeds the prompt and returns the logits for the first generated token.
+    ///
+    /// Prompts translating the blocks of a page one by one only differ at the
+    /// end, after the system prompt and context. The state after the
+    /// part a prompt shares with the previous one is kept, so the next prompt
+    /// continues from there instead of starting over.
+    fn prefill(&mut self, tokens: &[u32], split: bool) -> Result<Tensor> {
+        // the last token is always fed, its logits are needed
+        let shared =
+            common_prefix_len(&self.last_prompt, tokens).min(tokens.len().saturating_sub(1));
+        self.last_prompt = tokens.to_vec();
+
+        let mut pos = 0;
+        if let Some(cache) = &self.prefix_cache
+            && cache.tokens.len() < tokens.len()
+            && tokens.starts_with(&cache.tokens)
+        {
+            self.model = cache.model.clone();
+            pos = cache.tokens.len();
+            tracing::info!("Reusing the cached state of {pos} prompt tokens");
+        }
+        if shared > pos {
+            self.forward_tokens(&tokens[pos..shared], pos, split)?;
+            pos = shared;
+            self.prefix_cache = Some(PrefixCache {
+                tokens: tokens[..shared].to_vec(),
+                model: self.model.clone(),
+            });
+        }
+        self.forward_tokens(&tokens[pos..], pos, split)
+    }
+
+    /// Feeds `tokens` from position `pos` on, returning the logits after the last.
+    fn forward_tokens(&mut self, tokens: &[u32], pos: usize, split: bool) -> Result<Tensor> {
+        if !split && (pos == 0 || self.model.prefills_at_offset()) {
+            let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+            return Ok(self.model.forward(&input, pos)?);
+        }
+        let mut logits = None;
+        for (offset, token) in tokens.iter().enumerate() {
+            let input = Tensor::new(&[*token], &self.device)?.unsqueeze(0)?;
+            logits = Some(self.model.forward(&input, pos + offset)?);
+        }
+        logits.context("empty prompt")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use candle_core::quantized::{GgmlDType, QTensor, gguf_file::Value};
+
+    use super::*;
+
+    const VOCAB: usize = 32;
+    const EMBD: usize = 16;
+    const HEADS: usize = 2;
+    const KV_HEADS: usize = 1;
+    const HEAD_DIM: usize = 8;
+    const FF: usize = 32;
+    const LAYERS: usize = 2;
+
+    /// A tiny GGUF file with random weights laid out like `arch` checkpoints.
+    fn fixture(arch: &str) -> candle_core::Result<Vec<u8>> {
+        let device = Device::Cpu;
+        let count = |n: usize| Value::U32(n as u32);
+        let metadata = vec![
+            (
+                "general.architecture".to_string(),
+                Value::String(arch.to_string()),
+            ),
+            (format!("{arch}.block_count"), count(LAYERS)),
+            (format!("{arch}.context_length"), count(64)),
+            (format!("{arch}.embedding_length"), count(EMBD)),
+            (format!("{arch}.feed_forward_length"), count(FF)),
+            (format!("{arch}.attention.head_count"), count(HEADS)),
+            (format!("{arch}.attention.head_count_kv"), count(KV_HEADS)),
+            (format!("{arch}.attention.key_length"), count(HEAD_DIM)),
+            (format!("{arch}.attention.value_length"), count(HEAD_DIM)),
+            (format!("{arch}.attention.sliding_window"), count(2)),
+            (
+                format!("{arch}.attention.layer_norm_rms_epsilon"),
+                Value::F32(1e-6),
+            ),
+            (format!("{arch}.rope.dimension_count"), count(HEAD_DIM)),
+            (format!("{arch}.rope.freq_base"), Value::F32(10000.)),
+        ];
+
+        let mut shapes = vec![
+            ("token_embd.weight".to_string(), vec![VOCAB, EMBD]),
+            ("output_norm.weight".to_string(), vec![EMBD]),
+            ("output.weight".to_string(), vec![VOCAB, EMBD]),
+        ];
+        for layer in 0..LAYERS {
+            let name = |tensor: &str| format!("blk.{layer}.{tensor}.weight");
+            shapes.extend([
+                (name("attn_norm"), vec![EMBD]),
+                (name("attn_output"), vec![EMBD, HEADS * HEAD_DIM]),
+                (name("ffn_norm"), vec![EMBD]),
+                (name("ffn_down"), vec![EMBD, FF]),
+            ]);
+            if arch == "phi3" {
+                let qkv = (HEADS + 2 * KV_HEADS) * HEAD_DIM;
+                shapes.extend([
+                    (name("attn_qkv"), vec![qkv, EMBD]),
+                    (name("ffn_up"), vec![2 * FF, EMBD]),
+                ]);
+                continue;
+            }
+            shapes.extend([
+                (name("attn_q"), vec![HEADS * HEAD_DIM, EMBD]),
+                (name("attn_k"), vec![KV_HEADS * HEAD_DIM, EMBD]),
+                (name("attn_v"), vec![KV_HEADS * HEAD_DIM, EMBD]),
+                (name("ffn_gate"), vec![FF, EMBD]),
+                (name("ffn_up"), vec![FF, EMBD]),
+            ]);
+            if matches!(arch, "gemma3" | "qwen3") {
+                shapes.extend([
+                    (name("attn_q_norm"), vec![HEAD_DIM]),
+                    (name("attn_k_norm"), vec![HEAD_DIM]),
+                ]);
+            }
+            if matches!(arch, "gemma2" | "gemma3") {
+                shapes.extend([
+                    (name("post_attention_norm"), vec![EMBD]),
+                    (name("post_ffw_norm"), vec![EMBD]),
+                ]);
+            }
+        }
+        let tensors = shapes
+            .into_iter()
+            .map(|(name, shape)| {
+                let tensor = Tensor::randn(0f32, 0.02, shape, &device)?;
+                Ok((name, QTensor::quantize(&tensor, GgmlDType::F32)?))
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+
+        let mut file = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut file,
+            &metadata
+                .iter()
+                .map(|(key, value)| (key.as_str(), value))
+                .collect::<Vec<_>>(),
+            &tensors
+                .iter()
+                .map(|(name, tensor)| (name.as_str(), tensor))
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(file.into_inner())
+    }
+
+    fn load(arch: &str) -> Result<Model> {
+        let mut reader = Cursor::new(fixture(arch)?);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        Model::from_gguf(arch, ct, &mut reader, &Device::Cpu)
+            .with_context(|| format!("failed to load {arch}"))
+    }
+
+    fn input(tokens: &[u32]) -> candle_core::Result<Tensor> {
+        Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)
+    }
+
+    #[test]
+    fn loads_new_architectures() -> Result<()> {
+        for arch in ["gemma2", "gemma3", "qwen3", "phi3"] {
+            let mut model = load(arch)?;
+
+            // the prompt, then a decoding step past the sliding window
+            let logits = model.forward(&input(&[1, 2, 3])?, 0)?;
+            assert_eq!(logits.dims().last(), Some(&VOCAB), "{arch}");
+            let logits = model.forward(&input(&[4])?, 3)?;
+            assert_eq!(logits.dims().last(), Some(&VOCAB), "{arch}");
+        }
+        assert!(load("mamba").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn continues_from_cached_prefix() -> Result<()> {
+        for arch in ["gemma2", "gemma3", "qwen3", "phi3"] {
+            let mut model = load(arch)?;
+            let expected = model.forward(&input(&[1, 2, 3, 4, 5])?, 0)?;
+
+            model.forward(&input(&[1, 2, 3])?, 0)?;
+            let prefix = model.clone();
+            model.forward(&input(&[9])?, 3)?;
+
+            // like `Llm::forward_tokens` after restoring the prefix
+            let mut model = prefix;
+            let logits = match model.prefills_at_offset() {
+                true => model.forward(&input(&[4, 5])?, 3)?,
+                false => {
+                    model.forward(&input(&[4])?, 3)?;
+                    model.forward(&input(&[5])?, 4)?
+                }
+            };
+            let diff = (expected - logits)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_dtype(candle_core::DType::F32)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-3, "{arch}: {diff}");
+        }
+        Ok(())
+    }
+
+    /// An [`Llm`] around `model` with the generic prompt, listing each message.
+    fn llm(model: Model, tokenizer: Tokenizer) -> Llm {
+        Llm {
+            device: Device::Cpu,
+            model,
+            tokenizer,
+            prompt_renderer: PromptRenderer::custom(
+                "custom".to_string(),
+                None,
+                PromptTemplates::default(),
+                "{% for message in messages %}{{ message['content'] }} {% endfor %}".to_string(),
+                String::new(),
+                String::new(),
+            ),
+            stop_token_ids: Vec::new(),
+            context_length: DEFAULT_CONTEXT_LENGTH,
+            prefix_cache: None,
+            last_prompt: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn prefills_from_shared_prefix() -> Result<()> {
+        use tokenizers::models::bpe::BPE;
+
+        for arch in ["gemma2", "gemma3"] {
+            let model = load(arch)?;
+            let mut llm = llm(model.clone(), Tokenizer::new(BPE::default()));
+
+            // the blocks of a page: same system prompt and context, then the text
+            let prompts: [(&[u32], Option<&[u32]>); 3] = [
+                (&[1, 2, 3, 4, 5], None),
+                (&[1, 2, 3, 6, 7], Some(&[1, 2, 3])),
+                (&[1, 2, 3, 6, 8], Some(&[1, 2, 3, 6])),
+            ];
+            for (prompt, cached) in prompts {
+                let logits = llm.prefill(prompt, false)?;
+                let expected = model.clone().forward(&input(prompt)?, 0)?;
+                let diff = (expected - logits)?
+                    .abs()?
+                    .flatten_all()?
+                    .max(0)?
+                    .to_dtype(candle_core::DType::F32)?
+                    .to_scalar::<f32>()?;
+                assert!(diff < 1e-3, "{arch} {prompt:?}: {diff}");
+                assert_eq!(
+                    llm.prefix_cache
+                        .as_ref()
+                        .map(|cache| cache.tokens.as_slice()),
+                    cached,
+                    "{arch} {prompt:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn drops_oldest_context_to_fit() -> Result<()> {
+        use tokenizers::models::bpe::{BPE, Vocab};
+
+        // every character is one unknown token
+        let vocab: Vocab = [("[UNK]".to_string(), 0)].into_iter().collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, Vec::new())
+            .unk_token("[UNK]".to_string())
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        let mut llm = llm(load("qwen3")?, Tokenizer::new(bpe));
+
+        let history = ["おはよう", "こんばんは、凛さん", "またね"]
+            .into_iter()
+            .map(|source| TranslationPair {
+                source: source.to_string(),
+                translation: format!("{source} (translated)"),
+            })
+            .collect::<Vec<_>>();
+        let context = TranslationContext {
+            history: history.clone(),
+            ..Default::default()
+        };
+        let lengths = (0..=history.len())
+            .map(|skip| {
+                let prompt = llm.prompt_renderer.format_chat_prompt_with_context(
+                    "こんにちは".to_string(),
+                    &history[skip..],
+                    &[],
+                )?;
+                let length = llm.tokenize(&prompt, true)?.len();
+                Ok((prompt, length))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (skip, (expected, length)) in lengths.iter().enumerate() {
+            llm.context_length = length + 10;
+            let (prompt, tokens) = llm.fit_context("こんにちは", &context, 10)?;
+            assert_eq!(&prompt, expected, "{skip}");
+            assert_eq!(tokens.len(), *length, "{skip}");
+        }
+        // nothing is left to drop
+        llm.context_length = 0;
+        let (prompt, _) = llm.fit_context("こんにちは", &context, 10)?;
+        assert_eq!(prompt, lengths[history.len()].0);
+        Ok(())
+    }
+
+    #[test]
+    fn holds_back_incomplete_characters() -> Result<()> {
+        use tokenizers::decoders::byte_level::ByteLevel;
+        use tokenizers::models::bpe::{BPE, Vocab};
+
+        // byte-level tokens: "a", "b" and the three bytes of "あ"
+        let vocab: Vocab = ["a", "b", "ã", "ģ", "Ĥ"]
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, Vec::new())
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        let mut tokenizer = Tokenizer::new(bpe);
+        tokenizer.with_decoder(Some(ByteLevel::default()));
+
+        let mut stream = TextStream::default();
+        let mut tokens = Vec::new();
+        let mut emitted = Vec::new();
+        for token in [0, 2, 3, 4, 1] {
+            tokens.push(token);
+            emitted.push(stream.next(&tokenizer, &tokens)?);
+        }
+        assert_eq!(
+            emitted,
+            [Some("a"), None, None, Some("あ"), Some("b")].map(|text| text.map(str::to_string))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn strips_thinking() {
+        assert_eq!(strip_thinking("<think>\n\n</think>\n\nHello"), "Hello");
+        assert_eq!(strip_thinking("Hello <think>"), "Hello <think>");
+    }
+
+    #[test]
+    fn validates_options() -> Result<()> {
+        let options: GenerateOptions = serde_json::from_str(r#"{"temperature": 0, "topP": 0.9}"#)?;
+        options.validate()?;
+        assert_eq!(options.max_tokens, GenerateOptions::default().max_tokens);
+        assert_eq!(options.top_p, Some(0.9));
+
+        for invalid in [
+            r#"{"maxTokens": 0}"#,
+            r#"{"temperature": -1}"#,
+            r#"{"topK": 0}"#,
+            r#"{"topP": 1.5}"#,
+        ] {
+            let options: GenerateOptions = serde_json::from_str(invalid)?;
+            assert!(options.validate().is_err(), "{invalid}");
+        }
+        Ok(())
     }
 }
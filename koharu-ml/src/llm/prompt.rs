@@ -1,13 +1,14 @@
-use minijinja::{Environment, context};
-use once_cell::sync::Lazy;
-use serde::Serialize;
-use std::sync::RwLock;
+use minijinja::{Environment, Error, ErrorKind, State, Value, context};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::RwLock};
 use strum::{Display, EnumString};
 use sys_locale::get_locale;
 
-use crate::llm::{ModelId, language_from_tag};
+use crate::llm::{GenerateOptions, ModelId, language_from_tag};
 
 static LOCALE: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(system_locale_name()));
+static PROMPT_DIR: OnceCell<PathBuf> = OnceCell::new();
 
 fn system_locale_name() -> String {
     get_locale()
@@ -15,7 +16,8 @@ fn system_locale_name() -> String {
         .unwrap_or_else(|| "English".to_string())
 }
 
-fn get_default_locale() -> String {
+/// Name of the language translations are generated in, e.g. `English`.
+pub fn get_default_locale() -> String {
     LOCALE
         .read()
         .map(|locale| locale.clone())
@@ -32,6 +34,69 @@ pub fn set_locale(locale: String) {
     }
 }
 
+/// Directory of user prompt templates, see [`PromptTemplates`].
+pub fn set_prompt_dir(path: PathBuf) -> anyhow::Result<()> {
+    PROMPT_DIR
+        .set(path)
+        .map_err(|_| anyhow::anyhow!("prompt dir has already been set"))
+}
+
+/// User prompt templates of a model, read from `<prompt dir>/<model id>/` with
+/// `system.jinja` for the system prompt and `user.jinja` for the message with
+/// the source text. A missing file keeps the built-in prompt.
+///
+/// Templates are MiniJinja and get `language`, `source`, `glossary` (entries
+/// with `source`, `target` and `note`) and `context` (earlier
+/// `source`/`translation` pairs). The user template gets the glossary entries
+/// found in the text, the system template the whole glossary, so the start of
+/// the prompt stays the same from block to block and can be reused. Earlier lines are only sent as separate
+/// exchanges while the built-in user prompt is used, a custom one places
+/// `context` itself.
+///
+/// Batches of text blocks are sent as lines numbered like `[1]`, custom prompts
+/// should ask the model to keep those numbers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptTemplates {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl PromptTemplates {
+    /// Read the templates of the model `id` from the prompt directory.
+    pub fn load(id: &str) -> Self {
+        let Some(dir) = PROMPT_DIR.get() else {
+            return Self::default();
+        };
+        let dir = dir.join(id);
+        let read = |name: &str| {
+            let path = dir.join(name);
+            match std::fs::read_to_string(&path) {
+                Ok(template) => Some(template),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => {
+                    tracing::warn!("Failed to read prompt template {path:?}: {err}");
+                    None
+                }
+            }
+        };
+        Self {
+            system: read("system.jinja"),
+            user: read("user.jinja"),
+        }
+    }
+
+    /// Fill the templates missing here from `other`.
+    fn or(self, other: &PromptTemplates) -> Self {
+        Self {
+            system: self.system.or_else(|| other.system.clone()),
+            user: self.user.or_else(|| other.user.clone()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Display, EnumString)]
 #[strum(serialize_all = "lowercase")]
 pub enum ChatRole {
@@ -74,10 +139,62 @@ impl ChatMessage {
     }
 }
 
+/// An earlier source text and its accepted translation, given to the model as
+/// context for consistent names and tone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TranslationPair {
+    pub source: String,
+    pub translation: String,
+}
+
+/// A project glossary term with the translation the model has to use.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GlossaryEntry {
+    pub source: String,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl GlossaryEntry {
+    /// Entries whose source term occurs in `text`, the rest only cost tokens.
+    pub fn relevant<'a>(glossary: &'a [GlossaryEntry], text: &str) -> Vec<&'a GlossaryEntry> {
+        glossary
+            .iter()
+            .filter(|entry| !entry.source.is_empty() && text.contains(entry.source.as_str()))
+            .collect()
+    }
+}
+
+/// Everything given to a translation besides the source text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TranslationContext {
+    /// Earlier lines and their accepted translations, oldest first.
+    pub history: Vec<TranslationPair>,
+    pub glossary: Vec<GlossaryEntry>,
+    /// Sampling settings, each translator uses its own defaults when `None`.
+    pub options: Option<GenerateOptions>,
+    /// Name of the language to translate into, the default locale when `None`.
+    pub language: Option<String>,
+}
+
+impl TranslationContext {
+    /// Name of the language to translate into, e.g. `English`.
+    pub fn language(&self) -> String {
+        self.language.clone().unwrap_or_else(get_default_locale)
+    }
+}
+
 // Chat template renderer using MiniJinja
 pub struct PromptRenderer {
     env: Environment<'static>,
-    model_id: ModelId,
+    /// Id the user prompt templates are looked up by.
+    id: String,
+    /// Bundled model whose prompt layout is used, a generic one when `None`.
+    format: Option<ModelId>,
+    /// Templates used when the prompt directory has none for `id`.
+    templates: PromptTemplates,
     template: String,
     bos_token: String,
     eos_token: String,
@@ -85,27 +202,153 @@ pub struct PromptRenderer {
 
 impl PromptRenderer {
     pub fn new(model_id: ModelId, template: String, bos_token: String, eos_token: String) -> Self {
+        Self::custom(
+            model_id.to_string(),
+            Some(model_id),
+            PromptTemplates::default(),
+            template,
+            bos_token,
+            eos_token,
+        )
+    }
+
+    /// Renderer for a model registered by the user, following the prompt layout
+    /// of `format` and its own `templates`.
+    pub fn custom(
+        id: String,
+        format: Option<ModelId>,
+        templates: PromptTemplates,
+        template: String,
+        bos_token: String,
+        eos_token: String,
+    ) -> Self {
         let mut env = Environment::new();
 
         // Add custom filters that are commonly used in chat templates
         env.add_filter("trim", |s: String| s.trim().to_string());
+        env.set_unknown_method_callback(string_method);
 
         Self {
             env,
-            model_id,
+            id,
+            format,
+            templates,
             template,
             bos_token,
             eos_token,
         }
     }
 
-    fn messages(&self, text: impl Into<String>) -> Vec<ChatMessage> {
-        match self.model_id {
+    fn messages(
+        &self,
+        text: impl Into<String>,
+        context: &[TranslationPair],
+        glossary: &[GlossaryEntry],
+        templates: &PromptTemplates,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let text = text.into();
+        let relevant = GlossaryEntry::relevant(glossary, &text);
+        let vars = context! {
+            language => get_default_locale(),
+            source => text,
+            glossary => relevant,
+            context => context,
+        };
+        let mut messages = self.model_messages(text.clone(), glossary, &relevant);
+
+        if let Some(template) = &templates.system {
+            let system = self
+                .env
+                .render_str(template, context! { glossary => glossary, ..vars.clone() })?;
+            match messages.first_mut() {
+                Some(first) if first.role == ChatRole::System => first.content = system,
+                _ => messages.insert(0, ChatMessage::new(ChatRole::System, system)),
+            }
+        }
+
+        let (source_role, target_role, tail) = match self.format {
+            Some(ModelId::VntlLlama3_8Bv2) => (
+                ChatRole::Name("Japanese".to_string()),
+                ChatRole::Name("English".to_string()),
+                2,
+            ),
+            _ => (ChatRole::User, ChatRole::Assistant, 1),
+        };
+        let at = messages.len() - tail;
+        if let Some(template) = &templates.user {
+            messages[at].content = self.env.render_str(template, &vars)?;
+            return Ok(messages);
+        }
+
+        // earlier lines go in as finished exchanges right before the current one
+        messages.splice(
+            at..at,
+            context.iter().flat_map(|pair| {
+                [
+                    ChatMessage::new(source_role.clone(), pair.source.clone()),
+                    ChatMessage::new(target_role.clone(), pair.translation.clone()),
+                ]
+            }),
+        );
+        Ok(messages)
+    }
+
+    /// Whether the built-in prompt asks the model to keep the `[n]` numbers of
+    /// batched lines. VNTL and LFM2 are trained on fixed prompts that cannot ask
+    /// for it, so their texts are translated one at a time.
+    pub fn keeps_line_numbers(&self) -> bool {
+        !matches!(
+            self.format,
+            Some(ModelId::VntlLlama3_8Bv2 | ModelId::Lfm2_350mEnjpMt)
+        )
+    }
+
+    /// The built-in prompt for `text`. Terms go after the earlier exchanges,
+    /// which are spliced in before the last message, so prompts of one page
+    /// share everything up to the current text; only VNTL's metadata block comes
+    /// first and lists the whole `glossary` instead of the `relevant` terms.
+    fn model_messages(
+        &self,
+        text: String,
+        glossary: &[GlossaryEntry],
+        relevant: &[&GlossaryEntry],
+    ) -> Vec<ChatMessage> {
+        let Some(format) = self.format else {
+            return generic_messages(text, relevant);
+        };
+        let glossary = match format {
+            ModelId::VntlLlama3_8Bv2 => glossary.iter().collect(),
+            _ => relevant.to_vec(),
+        };
+        match format {
             // refer: https://huggingface.co/lmg-anon/vntl-llama3-8b-v2-gguf#translation-prompt
-            ModelId::VntlLlama3_8Bv2 => vec![
-                ChatMessage::new(ChatRole::Name("Japanese".to_string()), text),
-                ChatMessage::new(ChatRole::Name("English".to_string()), String::new()),
-            ],
+            // terms go in the metadata block, like the character lines of the dataset
+            ModelId::VntlLlama3_8Bv2 => {
+                let mut messages = Vec::new();
+                if !glossary.is_empty() {
+                    let metadata = glossary
+                        .iter()
+                        .map(|entry| match &entry.note {
+                            Some(note) => {
+                                format!("[term] {} ({}) | {note}", entry.target, entry.source)
+                            }
+                            None => format!("[term] {} ({})", entry.target, entry.source),
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    messages.push(ChatMessage::new(ChatRole::System, metadata));
+                }
+                messages.push(ChatMessage::new(
+                    ChatRole::Name("Japanese".to_string()),
+                    text,
+                ));
+                messages.push(ChatMessage::new(
+                    ChatRole::Name("English".to_string()),
+                    String::new(),
+                ));
+                messages
+            }
+            // the model is trained on the bare system prompt only, a glossary would derail it
             ModelId::Lfm2_350mEnjpMt => vec![
                 ChatMessage::new(
                     ChatRole::System,
@@ -113,39 +356,117 @@ impl PromptRenderer {
                 ),
                 ChatMessage::new(ChatRole::User, text),
             ],
-            ModelId::SakuraGalTransl7Bv3_7 | ModelId::Sakura1_5bQwen2_5v1_0 => vec![
-                ChatMessage::new(
-                    ChatRole::System,
-                    "你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。",
-                ),
-                ChatMessage::new(ChatRole::User, text),
-            ],
-            ModelId::HunyuanMT7B => vec![ChatMessage::new(
-                ChatRole::User,
-                format!(
-                    "Translate the following light novel dialog into {}, without additional explanation.\n\n{}",
-                    get_default_locale(),
-                    text.into(),
-                ),
-            )],
+            ModelId::SakuraGalTransl7Bv3_7 | ModelId::Sakura1_5bQwen2_5v1_0 => {
+                let user = if glossary.is_empty() {
+                    text
+                } else {
+                    // refer: https://github.com/SakuraLLM/SakuraLLM#推理 and the GalTransl prompt
+                    let dict = glossary
+                        .iter()
+                        .map(|entry| match &entry.note {
+                            Some(note) => format!("{}->{} #{note}", entry.source, entry.target),
+                            None => format!("{}->{}", entry.source, entry.target),
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    match format {
+                        ModelId::SakuraGalTransl7Bv3_7 => format!(
+                            "参考以下术语表（可为空，格式为src->dst #备注）：\n{dict}\n\n根据以上术语表的对应关系和备注，结合历史剧情和上下文，将下面的文本从日文翻译成简体中文：\n{text}"
+                        ),
+                        _ => format!(
+                            "根据以下术语表（可以为空）：\n{dict}\n将下面的日文文本根据对应关系和备注翻译成中文：{text}"
+                        ),
+                    }
+                };
+                vec![
+                    ChatMessage::new(
+                        ChatRole::System,
+                        "你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。如果每行以[1]这样的编号开头，请逐行翻译并保留编号。",
+                    ),
+                    ChatMessage::new(ChatRole::User, user),
+                ]
+            }
+            // terminology intervention as in the Hunyuan-MT prompt templates
+            ModelId::HunyuanMT7B => {
+                let terms = glossary
+                    .iter()
+                    .map(|entry| match &entry.note {
+                        Some(note) => {
+                            format!("{} translates to {} ({note})\n", entry.source, entry.target)
+                        }
+                        None => format!("{} translates to {}\n", entry.source, entry.target),
+                    })
+                    .collect::<String>();
+                let terms = match terms.is_empty() {
+                    true => terms,
+                    false => format!("Refer to the following translations:\n{terms}\n"),
+                };
+                vec![ChatMessage::new(
+                    ChatRole::User,
+                    format!(
+                        "{terms}Translate the following light novel dialog into {}, without additional explanation. When the lines are numbered like [1], keep the number of each line.\n\n{}",
+                        get_default_locale(),
+                        text,
+                    ),
+                )]
+            }
         }
     }
 
     pub fn format_chat_prompt(&self, prompt: String) -> anyhow::Result<String> {
-        let messages = self.messages(prompt);
-        let tmpl = self.env.template_from_str(&self.template)?;
+        self.format_chat_prompt_with_context(prompt, &[], &[])
+    }
 
-        let prompt = tmpl
-            .render(context! {
+    /// Format `prompt` after the `context` exchanges, with the `glossary` terms
+    /// that occur in it placed where the model expects them.
+    pub fn format_chat_prompt_with_context(
+        &self,
+        prompt: String,
+        context: &[TranslationPair],
+        glossary: &[GlossaryEntry],
+    ) -> anyhow::Result<String> {
+        self.format_with_templates(prompt, context, glossary, &self.templates())
+    }
+
+    /// The user prompt templates of the model, read again on every call so
+    /// edits apply to the next translation.
+    pub fn templates(&self) -> PromptTemplates {
+        PromptTemplates::load(&self.id).or(&self.templates)
+    }
+
+    /// Like [`Self::format_chat_prompt_with_context`], with `templates` already
+    /// read by [`Self::templates`].
+    pub fn format_with_templates(
+        &self,
+        prompt: String,
+        context: &[TranslationPair],
+        glossary: &[GlossaryEntry],
+        templates: &PromptTemplates,
+    ) -> anyhow::Result<String> {
+        let messages = self.messages(prompt, context, glossary, templates)?;
+        let tmpl = self.env.template_from_str(&self.template)?;
+        let render = |messages: &[ChatMessage]| {
+            tmpl.render(context! {
                 messages => messages,
                 bos_token => self.bos_token,
                 eos_token => self.eos_token,
-                add_generation_prompt => !matches!(self.model_id, ModelId::VntlLlama3_8Bv2),
+                add_generation_prompt => self.format != Some(ModelId::VntlLlama3_8Bv2),
+                enable_thinking => false,
             })
-            .map_err(anyhow::Error::msg);
+        };
+
+        let prompt = match render(&messages) {
+            // Some templates, e.g. Gemma 2's, reject system messages
+            Err(err) if messages.len() > 1 && messages[0].role == ChatRole::System => {
+                tracing::debug!("retrying without a system message: {err}");
+                render(&fold_system_message(messages))
+            }
+            prompt => prompt,
+        }
+        .map_err(anyhow::Error::msg);
 
         // hotfix the vntl-llama3-8b-v2 extra eos_token issue
-        if self.model_id == ModelId::VntlLlama3_8Bv2 {
+        if self.format == Some(ModelId::VntlLlama3_8Bv2) {
             prompt.map(|s| s.trim_end_matches("<|eot_id|>").to_string())
         } else {
             prompt
@@ -153,8 +474,80 @@ impl PromptRenderer {
     }
 }
 
+/// Moves the leading system message into the message after it.
+fn fold_system_message(mut messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+    let system = messages.remove(0);
+    messages[0].content = format!("{}\n\n{}", system.content, messages[0].content);
+    messages
+}
+
+/// Python string methods that chat templates call, e.g. Qwen3's, and MiniJinja
+/// lacks.
+fn string_method(_: &State, value: &Value, method: &str, args: &[Value]) -> Result<Value, Error> {
+    let Some(s) = value.as_str() else {
+        return Err(Error::from(ErrorKind::UnknownMethod));
+    };
+    let arg = |index: usize| args.get(index).and_then(Value::as_str);
+    let required = |index: usize| {
+        arg(index).ok_or_else(|| {
+            Error::new(
+                ErrorKind::MissingArgument,
+                format!("{method} expects a string argument"),
+            )
+        })
+    };
+    let value = match method {
+        "startswith" => Value::from(s.starts_with(required(0)?)),
+        "endswith" => Value::from(s.ends_with(required(0)?)),
+        "strip" => Value::from(match arg(0) {
+            Some(chars) => s.trim_matches(|c| chars.contains(c)),
+            None => s.trim(),
+        }),
+        "lstrip" => Value::from(match arg(0) {
+            Some(chars) => s.trim_start_matches(|c| chars.contains(c)),
+            None => s.trim_start(),
+        }),
+        "rstrip" => Value::from(match arg(0) {
+            Some(chars) => s.trim_end_matches(|c| chars.contains(c)),
+            None => s.trim_end(),
+        }),
+        "split" => Value::from(match arg(0) {
+            Some(separator) => s.split(separator).map(Value::from).collect::<Vec<_>>(),
+            None => s.split_whitespace().map(Value::from).collect(),
+        }),
+        _ => return Err(Error::from(ErrorKind::UnknownMethod)),
+    };
+    Ok(value)
+}
+
+/// Plain instruction prompt for models without a bundled prompt layout.
+fn generic_messages(text: String, glossary: &[&GlossaryEntry]) -> Vec<ChatMessage> {
+    let system = format!(
+        "Translate the text into {}. Reply with the translation only, without explanations, and keep the line breaks. When the lines are numbered like `[1]`, translate each line on its own and keep its number.",
+        get_default_locale()
+    );
+    let mut user = String::new();
+    if !glossary.is_empty() {
+        user.push_str("Always translate these terms as given:");
+        for entry in glossary {
+            user.push_str(&format!("\n- {} -> {}", entry.source, entry.target));
+            if let Some(note) = &entry.note {
+                user.push_str(&format!(" ({note})"));
+            }
+        }
+        user.push_str("\n\n");
+    }
+    user.push_str(&text);
+    vec![
+        ChatMessage::new(ChatRole::System, system),
+        ChatMessage::new(ChatRole::User, user),
+    ]
+}
+
 #[cfg(test)]
 mod tests {
+    use strum::IntoEnumIterator;
+
     use super::*;
 
     #[test]
@@ -189,6 +582,202 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn numbered_prompts_ask_to_keep_numbers() -> anyhow::Result<()> {
+        let template = "{% for message in messages %}{{ message['content'] }} {% endfor %}";
+        let renderers = ModelId::iter()
+            .map(|id| PromptRenderer::new(id, template.to_string(), String::new(), String::new()))
+            .chain([PromptRenderer::custom(
+                "custom".to_string(),
+                None,
+                PromptTemplates::default(),
+                template.to_string(),
+                String::new(),
+                String::new(),
+            )]);
+        for renderer in renderers {
+            let prompt =
+                renderer.format_chat_prompt("[1] こんにちは\n[2] さようなら".to_string())?;
+            // once in the source text, once more in the instruction
+            assert_eq!(
+                prompt.matches("[1]").count() > 1,
+                renderer.keeps_line_numbers(),
+                "{}",
+                renderer.id
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn qwen25_prompt_format_with_context() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            ModelId::Sakura1_5bQwen2_5v1_0,
+            r#"{% for message in messages %}{{ message['role'] + ': ' + message['content'] + ' ' }}{% endfor %}"#.to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let context = [TranslationPair {
+            source: "おはよう".to_string(),
+            translation: "早上好".to_string(),
+        }];
+        let formatted =
+            renderer.format_chat_prompt_with_context("こんにちは".to_string(), &context, &[])?;
+        assert!(formatted.ends_with("user: おはよう assistant: 早上好 user: こんにちは "));
+
+        let glossary = [
+            GlossaryEntry {
+                source: "こんにちは".to_string(),
+                target: "你好".to_string(),
+                note: Some("问候".to_string()),
+            },
+            GlossaryEntry {
+                source: "さようなら".to_string(),
+                target: "再见".to_string(),
+                note: None,
+            },
+        ];
+        let formatted =
+            renderer.format_chat_prompt_with_context("こんにちは".to_string(), &[], &glossary)?;
+        assert!(formatted.ends_with(
+            "user: 根据以下术语表（可以为空）：\nこんにちは->你好 #问候\n将下面的日文文本根据对应关系和备注翻译成中文：こんにちは "
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn terms_follow_the_shared_prefix() -> anyhow::Result<()> {
+        let template = "{% for message in messages %}{{ message['role'] + ': ' + message['content'] + ' ' }}{% endfor %}";
+        let context = [TranslationPair {
+            source: "おはよう".to_string(),
+            translation: "Morning".to_string(),
+        }];
+        let glossary = [
+            GlossaryEntry {
+                source: "凛".to_string(),
+                target: "Rin".to_string(),
+                note: None,
+            },
+            GlossaryEntry {
+                source: "渚".to_string(),
+                target: "Nagisa".to_string(),
+                note: None,
+            },
+        ];
+        let renderers = ModelId::iter()
+            .map(|id| PromptRenderer::new(id, template.to_string(), String::new(), String::new()))
+            .chain([PromptRenderer::custom(
+                "custom".to_string(),
+                None,
+                PromptTemplates {
+                    system: Some(
+                        "{% for term in glossary %}{{ term.source }}={{ term.target }};{% endfor %}"
+                            .to_string(),
+                    ),
+                    user: None,
+                },
+                template.to_string(),
+                String::new(),
+                String::new(),
+            )]);
+        for renderer in renderers {
+            let first = renderer.format_chat_prompt_with_context(
+                "凛さん".to_string(),
+                &context,
+                &glossary,
+            )?;
+            let second = renderer.format_chat_prompt_with_context(
+                "渚さん".to_string(),
+                &context,
+                &glossary,
+            )?;
+            let shared = first
+                .char_indices()
+                .zip(second.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(first.len(), |((index, _), _)| index);
+            assert!(
+                first[..shared].contains("Morning"),
+                "{}: {first}",
+                renderer.id
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn custom_prompt_templates() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            ModelId::HunyuanMT7B,
+            r#"{% for message in messages %}{{ message['role'] + ': ' + message['content'] + ' ' }}{% endfor %}"#.to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let templates = PromptTemplates {
+            system: Some("Casual {{ language }}, keep honorifics.".to_string()),
+            user: Some(
+                "{% for term in glossary %}{{ term.source }}={{ term.target }};{% endfor %}{% for line in context %}{{ line.translation }}|{% endfor %}{{ source }}"
+                    .to_string(),
+            ),
+        };
+        let context = [TranslationPair {
+            source: "おはよう".to_string(),
+            translation: "Morning".to_string(),
+        }];
+        let glossary = [GlossaryEntry {
+            source: "凛".to_string(),
+            target: "Rin".to_string(),
+            note: None,
+        }];
+        let messages = renderer.messages("凛さん", &context, &glossary, &templates)?;
+        assert_eq!(
+            messages,
+            vec![
+                ChatMessage::new(
+                    ChatRole::System,
+                    format!("Casual {}, keep honorifics.", get_default_locale())
+                ),
+                ChatMessage::new(ChatRole::User, "凛=Rin;Morning|凛さん"),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn generic_prompt_format() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::custom(
+            "my-finetune".to_string(),
+            None,
+            PromptTemplates::default(),
+            r#"{% for message in messages %}{{ message['role'] + ': ' + message['content'] + ' ' }}{% endfor %}"#.to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string())?;
+        assert!(formatted.starts_with("system: Translate the text into "));
+        assert!(formatted.ends_with("user: こんにちは "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn system_message_folded_for_strict_templates() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::custom(
+            "gemma-2-finetune".to_string(),
+            None,
+            PromptTemplates::default(),
+            r#"{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if message.content.strip().startswith('Translate') %}{{ '<start_of_turn>' + message['role'] + ' ' + message['content'].split(' ')[0] + '<end_of_turn> ' }}{% endif %}{% endfor %}"#.to_string(),
+            "<bos>".to_string(),
+            "<eos>".to_string(),
+        );
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string())?;
+        assert_eq!(formatted, "<start_of_turn>user Translate<end_of_turn> ");
+
+        Ok(())
+    }
+
     #[test]
     fn qwen25_prompt_format() -> anyhow::Result<()> {
         let model_id = ModelId::SakuraGalTransl7Bv3_7;
@@ -199,7 +788,7 @@ mod tests {
             "</s>".to_string(),
         );
         let formatted = renderer.format_chat_prompt("こんにちは".to_string())?;
-        let expected = "<|im_start|>system 你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。<|im_end|> <|im_start|>user こんにちは<|im_end|> <|im_start|>assistant ";
+        let expected = "<|im_start|>system 你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。如果每行以[1]这样的编号开头，请逐行翻译并保留编号。<|im_end|> <|im_start|>user こんにちは<|im_end|> <|im_start|>assistant ";
         assert_eq!(formatted, expected);
 
         Ok(())
@@ -0,0 +1,375 @@
+//! Quantized Gemma 2 model implementation.
+//!
+//! Gemma 2 alternates sliding window and global attention layers, normalizes the
+//! output of both the attention and the feed-forward blocks, and soft-caps the
+//! attention scores and the final logits.
+//!
+//! - 💻 [GH Link](https://github.com/google/gemma_pytorch)
+//! - 📝 [Paper](https://arxiv.org/abs/2408.00118)
+
+use candle_core::quantized::QTensor;
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use candle_transformers::quantized_nn::RmsNorm;
+
+pub const MAX_SEQ_LEN: usize = 8192;
+
+const DEFAULT_SLIDING_WINDOW: usize = 4096;
+const DEFAULT_ATTN_LOGIT_SOFTCAPPING: f64 = 50.;
+const DEFAULT_FINAL_LOGIT_SOFTCAPPING: f64 = 30.;
+
+// QMatMul wrapper adding some tracing.
+#[derive(Debug, Clone)]
+struct QMatMul {
+    inner: candle_core::quantized::QMatMul,
+    span: tracing::Span,
+}
+
+impl QMatMul {
+    fn from_qtensor(qtensor: QTensor) -> Result<Self> {
+        let inner = candle_core::quantized::QMatMul::from_qtensor(qtensor)?;
+        let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    feed_forward_gate: QMatMul,
+    feed_forward_up: QMatMul,
+    feed_forward_down: QMatMul,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = self.feed_forward_gate.forward(xs)?.gelu()?;
+        let up = self.feed_forward_up.forward(xs)?;
+        self.feed_forward_down.forward(&(gate * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    post_attention_norm: RmsNorm,
+    ffn_norm: RmsNorm,
+    post_ffn_norm: RmsNorm,
+    mlp: Mlp,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    query_scale: f64,
+    attn_logit_softcapping: f64,
+    /// Even layers only attend to the last `sliding_window` tokens.
+    sliding: bool,
+    cos: Tensor,
+    sin: Tensor,
+    neg_inf: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+    span_attn: tracing::Span,
+    span_rot: tracing::Span,
+    span_mlp: tracing::Span,
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+    let shape = mask.shape();
+    let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
+    Ok(m)
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let _enter = self.span_rot.enter();
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (b_sz, seq_len, _n_embd) = x.dims3()?;
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => {
+                let k = Tensor::cat(&[k_cache, &k], 2)?;
+                let v = Tensor::cat(&[v_cache, &v], 2)?;
+                (k, v)
+            }
+            _ => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let k = candle_transformers::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
+        let v = candle_transformers::utils::repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+        let att = (q * self.query_scale)?.matmul(&k.t()?)?;
+        let att = ((att / self.attn_logit_softcapping)?.tanh()? * self.attn_logit_softcapping)?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, &self.neg_inf)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y = att.matmul(&v.contiguous()?)?;
+
+        let y = y
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.n_head * self.head_dim))?;
+        self.attention_wo.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    embedding_length: usize,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    final_logit_softcapping: f64,
+    sliding_window: usize,
+    span: tracing::Span,
+    span_output: tracing::Span,
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    context_len: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = Tensor::arange(0, context_len as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((context_len, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+/// Mask of the cached and new keys each of the `seq_len` new queries may not
+/// attend to, `None` when nothing is masked.
+fn mask(
+    seq_len: usize,
+    index_pos: usize,
+    window: Option<usize>,
+    device: &Device,
+) -> Result<Option<Tensor>> {
+    let kv_len = index_pos + seq_len;
+    let window = window.unwrap_or(usize::MAX);
+    if seq_len == 1 && kv_len <= window {
+        return Ok(None);
+    }
+    let mask: Vec<_> = (0..seq_len)
+        .flat_map(|i| {
+            let pos = index_pos + i;
+            (0..kv_len).map(move |j| u8::from(j > pos || pos - j >= window))
+        })
+        .collect();
+    Ok(Some(Tensor::from_slice(&mask, (seq_len, kv_len), device)?))
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        // Parameter extraction from metadata.
+        let head_count = md_get("gemma2.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("gemma2.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("gemma2.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("gemma2.embedding_length")?.to_u32()? as usize;
+        let head_dim = md_get("gemma2.attention.key_length")
+            .and_then(|v| v.to_u32())
+            .map(|v| v as usize)
+            .unwrap_or(embedding_length / head_count);
+        let rms_norm_eps = md_get("gemma2.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_freq_base = md_get("gemma2.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let sliding_window = md_get("gemma2.attention.sliding_window")
+            .and_then(|v| v.to_u32())
+            .map(|v| v as usize)
+            .unwrap_or(DEFAULT_SLIDING_WINDOW);
+        let attn_logit_softcapping = md_get("gemma2.attn_logit_softcapping")
+            .and_then(|v| v.to_f32())
+            .map(f64::from)
+            .unwrap_or(DEFAULT_ATTN_LOGIT_SOFTCAPPING);
+        let final_logit_softcapping = md_get("gemma2.final_logit_softcapping")
+            .and_then(|v| v.to_f32())
+            .map(f64::from)
+            .unwrap_or(DEFAULT_FINAL_LOGIT_SOFTCAPPING);
+        let context_len = md_get("gemma2.context_length")
+            .and_then(|v| v.to_u32())
+            .map(|v| v as usize)
+            .unwrap_or(MAX_SEQ_LEN);
+        // The 27B model scales queries by the hidden size per head rather than by
+        // the head dimension, see `query_pre_attn_scalar` in the reference config.
+        let query_scale = match block_count {
+            46 => 1. / ((embedding_length / head_count) as f64).sqrt(),
+            _ => 1. / (head_dim as f64).sqrt(),
+        };
+        let (cos, sin) = precomput_freqs_cis(head_dim, rope_freq_base, context_len, device)?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+        let tok_embeddings_q = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        let output = match ct.tensor(reader, "output.weight", device) {
+            Ok(tensor) => tensor,
+            Err(_) => tok_embeddings_q,
+        };
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
+            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
+            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
+            let attention_wo =
+                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+            let feed_forward_gate =
+                ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
+            let feed_forward_up = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+            let feed_forward_down =
+                ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
+            let attention_norm =
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
+            let post_attention_norm = ct.tensor(
+                reader,
+                &format!("{prefix}.post_attention_norm.weight"),
+                device,
+            )?;
+            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+            let post_ffn_norm =
+                ct.tensor(reader, &format!("{prefix}.post_ffw_norm.weight"), device)?;
+            let span_attn = tracing::span!(tracing::Level::TRACE, "attn");
+            let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
+            let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(attention_wq)?,
+                attention_wk: QMatMul::from_qtensor(attention_wk)?,
+                attention_wv: QMatMul::from_qtensor(attention_wv)?,
+                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
+                post_attention_norm: RmsNorm::from_qtensor(post_attention_norm, rms_norm_eps)?,
+                ffn_norm: RmsNorm::from_qtensor(ffn_norm, rms_norm_eps)?,
+                post_ffn_norm: RmsNorm::from_qtensor(post_ffn_norm, rms_norm_eps)?,
+                mlp: Mlp {
+                    feed_forward_gate: QMatMul::from_qtensor(feed_forward_gate)?,
+                    feed_forward_up: QMatMul::from_qtensor(feed_forward_up)?,
+                    feed_forward_down: QMatMul::from_qtensor(feed_forward_down)?,
+                },
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                query_scale,
+                attn_logit_softcapping,
+                sliding: layer_idx % 2 == 0,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                neg_inf: neg_inf.clone(),
+                kv_cache: None,
+                span_attn,
+                span_rot,
+                span_mlp,
+            })
+        }
+        let span = tracing::span!(tracing::Level::TRACE, "model");
+        let span_output = tracing::span!(tracing::Level::TRACE, "output");
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            embedding_length,
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            final_logit_softcapping,
+            sliding_window,
+            span,
+            span_output,
+        })
+    }
+
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let global_mask = mask(seq_len, index_pos, None, x.device())?;
+        let sliding_mask = mask(seq_len, index_pos, Some(self.sliding_window), x.device())?;
+        let _enter = self.span.enter();
+        let mut layer_in =
+            (self.tok_embeddings.forward(x)? * (self.embedding_length as f64).sqrt())?;
+        for layer in self.layers.iter_mut() {
+            let mask = match layer.sliding {
+                true => sliding_mask.as_ref(),
+                false => global_mask.as_ref(),
+            };
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(&x, mask, index_pos)?;
+            let attn = layer.post_attention_norm.forward(&attn)?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let _enter = layer.span_mlp.enter();
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = layer.post_ffn_norm.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let _enter = self.span_output.enter();
+        let logits = self.output.forward(&x)?;
+        (logits / self.final_logit_softcapping)?.tanh()? * self.final_logit_softcapping
+    }
+}
@@ -572,17 +572,19 @@ impl ModelWeights {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
+            return Ok(mask.clone());
+        }
+        // tokens after a cached prefix see all of it
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > index_pos + i)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+        if index_pos == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
@@ -590,7 +592,7 @@ impl ModelWeights {
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.mask(seq_len, index_pos, x.device())?)
         };
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
@@ -255,6 +255,7 @@ impl ShortConvLayer {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct ModelWeights {
     tok_embeddings: Embedding,
     layers: Vec<LayerWeights>,
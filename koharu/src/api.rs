@@ -1,4 +1,6 @@
 use std::{
+    collections::HashMap,
+    convert::Infallible,
     io::{Cursor, Write},
     path::{Path, PathBuf},
     sync::Arc,
@@ -6,13 +8,18 @@ use std::{
 
 use anyhow::Result;
 use axum::{
-    Json, Router,
+    Json, Router, async_trait,
     body::Body,
-    extract::{DefaultBodyLimit, Multipart, State},
-    http::{HeaderValue, StatusCode, header},
-    response::{IntoResponse, Response},
-    routing::{get, post},
+    extract::{DefaultBodyLimit, FromRequestParts, Multipart, Path as UrlPath, State},
+    http::{HeaderValue, StatusCode, header, request::Parts},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{delete, get, post},
 };
+use futures::{Stream, stream};
+use koharu_ml::llm::{GenerateOptions, GlossaryEntry};
 use koharu_renderer::renderer::TextShaderEffect;
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
@@ -23,10 +30,16 @@ use tower_http::services::{ServeDir, ServeFile};
 use crate::{
     api_crs,
     app::AppResources,
+    book::BookOptions,
+    glossary::GlossaryIssue,
+    jobs::{JobInfo, JobRequest, Jobs},
     llm, ml,
     operations::{self, DocumentInput, ExportedDocument, InpaintRegion},
+    project::{DEFAULT_PROJECT, Project, ProjectInfo, Projects},
     renderer::Renderer,
-    state::{AppState, Document, TextBlock},
+    script::{ImportOptions, ImportReport, ScriptOptions},
+    sort::PageOrder,
+    state::{Document, DocumentRef, TextBlock},
     version,
 };
 
@@ -36,10 +49,6 @@ pub struct ApiState {
 }
 
 impl ApiState {
-    pub fn app_state(&self) -> &AppState {
-        &self.resources.state
-    }
-
     pub fn ml(&self) -> &Arc<ml::Model> {
         &self.resources.ml
     }
@@ -51,6 +60,48 @@ impl ApiState {
     pub fn renderer(&self) -> &Arc<Renderer> {
         &self.resources.renderer
     }
+
+    pub fn jobs(&self) -> &Arc<Jobs> {
+        &self.resources.jobs
+    }
+
+    pub fn projects(&self) -> &Arc<Projects> {
+        &self.resources.projects
+    }
+}
+
+/// Project addressed by the `:project` route segment, the default project on
+/// unscoped routes.
+pub struct CurrentProject(pub Arc<Project>);
+
+impl std::ops::Deref for CurrentProject {
+    type Target = Project;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<ApiState> for CurrentProject {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApiState) -> ApiResult<Self> {
+        let params = UrlPath::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map(|UrlPath(params)| params)
+            .unwrap_or_default();
+        let id = params
+            .get("project")
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_PROJECT);
+        let project = state
+            .projects()
+            .get(id)
+            .await
+            .ok_or_else(|| ApiError::not_found(format!("Project {id} not found")))?;
+        Ok(CurrentProject(project))
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -72,6 +123,13 @@ impl ApiError {
         }
     }
 
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: message.into(),
+        }
+    }
+
     pub fn internal(message: impl Into<String>) -> Self {
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR,
@@ -112,15 +170,26 @@ impl IntoResponse for ApiError {
 
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
+/// Selects a document by id, or by index for older clients.
 #[derive(Debug, Deserialize)]
-struct IndexPayload {
-    index: usize,
+#[serde(rename_all = "camelCase")]
+struct DocumentPayload {
+    document_id: Option<String>,
+    index: Option<usize>,
+}
+
+impl DocumentPayload {
+    fn to_ref(&self) -> ApiResult<DocumentRef> {
+        DocumentRef::new(self.document_id.clone(), self.index)
+            .map_err(|err| ApiError::bad_request(err.to_string()))
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct InpaintMaskPayload {
-    index: usize,
+    #[serde(flatten)]
+    document: DocumentPayload,
     mask: Vec<u8>,
     region: Option<InpaintRegion>,
 }
@@ -128,7 +197,8 @@ struct InpaintMaskPayload {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct BrushPayload {
-    index: usize,
+    #[serde(flatten)]
+    document: DocumentPayload,
     patch: Vec<u8>,
     region: InpaintRegion,
 }
@@ -136,14 +206,16 @@ struct BrushPayload {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct InpaintPartialPayload {
-    index: usize,
+    #[serde(flatten)]
+    document: DocumentPayload,
     region: InpaintRegion,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct RenderPayload {
-    index: usize,
+    #[serde(flatten)]
+    document: DocumentPayload,
     text_block_index: Option<usize>,
     shader_effect: Option<TextShaderEffect>,
 }
@@ -151,10 +223,17 @@ struct RenderPayload {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct TextBlocksPayload {
-    index: usize,
+    #[serde(flatten)]
+    document: DocumentPayload,
     text_blocks: Vec<TextBlock>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReorderPayload {
+    document_ids: Vec<String>,
+}
+
 #[derive(Debug, Deserialize)]
 struct LlmLoadPayload {
     id: String,
@@ -163,9 +242,14 @@ struct LlmLoadPayload {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct LlmGeneratePayload {
-    index: usize,
+    #[serde(flatten)]
+    document: DocumentPayload,
     text_block_index: Option<usize>,
     language: Option<String>,
+    /// Number of earlier pages given to the model as context.
+    context_pages: Option<usize>,
+    /// Sampling settings, the project's when omitted.
+    options: Option<GenerateOptions>,
 }
 
 #[cfg(not(debug_assertions))]
@@ -244,28 +328,27 @@ pub async fn serve(bind: String, resources: AppResources) -> Result<()> {
 
     let mut router = Router::new()
         .route("/api/app_version", get(app_version).post(app_version))
-        .route("/api/get_documents", get(get_documents).post(get_documents))
-        .route("/api/open_documents", post(open_documents))
-        .route("/api/save_documents", post(save_documents))
-        .route("/api/export_document", post(export_document))
-        .route("/api/export_all_documents", post(export_all_documents))
-        .route("/api/detect", post(detect))
-        .route("/api/ocr", post(ocr))
-        .route("/api/inpaint", post(inpaint))
-        .route("/api/inpaint_partial", post(inpaint_partial))
-        .route("/api/render", post(render))
-        .route("/api/update_brush_layer", post(update_brush_layer))
-        .route("/api/update_inpaint_mask", post(update_inpaint_mask))
-        .route("/api/update_text_blocks", post(update_text_blocks))
         .route(
             "/api/list_font_families",
             get(list_font_families).post(list_font_families),
         )
         .route("/api/llm_list", get(llm_list).post(llm_list))
         .route("/api/llm_load", post(llm_load))
+        .route(
+            "/api/llm_translators",
+            get(llm_translators).post(llm_add_translator),
+        )
+        .route("/api/llm_translators/:id", delete(llm_remove_translator))
         .route("/api/llm_offload", post(llm_offload))
         .route("/api/llm_ready", get(llm_ready).post(llm_ready))
-        .route("/api/llm_generate", post(llm_generate))
+        .route("/api/llm/events", get(llm_events))
+        .route("/api/jobs/events", get(job_events))
+        .route("/api/jobs/:id", get(get_job))
+        .route("/api/jobs/:id/cancel", post(cancel_job))
+        .route("/api/projects", get(list_projects).post(create_project))
+        .route("/api/projects/:project", delete(close_project))
+        .nest("/api", project_routes())
+        .nest("/api/projects/:project", project_routes())
         .route(
             "/translate/with-form/image/stream",
             post(api_crs::translate_with_form_image_stream),
@@ -294,21 +377,156 @@ pub async fn serve(bind: String, resources: AppResources) -> Result<()> {
     Ok(())
 }
 
+/// Routes working on the documents of one project, served both unscoped for the
+/// default project and under `/api/projects/:project`.
+fn project_routes() -> Router<ApiState> {
+    Router::new()
+        .route("/get_documents", get(get_documents).post(get_documents))
+        .route("/open_documents", post(open_documents))
+        .route("/insert_documents", post(insert_documents))
+        .route("/add_documents", post(add_documents))
+        .route("/remove_document", post(remove_document))
+        .route("/reorder_documents", post(reorder_documents))
+        .route("/save_documents", post(save_documents))
+        .route("/export_document", post(export_document))
+        .route("/export_all_documents", post(export_all_documents))
+        .route("/export_cbz", post(export_cbz))
+        .route("/export_psd", post(export_psd))
+        .route("/export_epub", post(export_epub))
+        .route("/export_pdf", post(export_pdf))
+        .route("/export_script", post(export_script))
+        .route("/import_script", post(import_script))
+        .route("/detect", post(detect))
+        .route("/ocr", post(ocr))
+        .route("/inpaint", post(inpaint))
+        .route("/inpaint_partial", post(inpaint_partial))
+        .route("/render", post(render))
+        .route("/update_brush_layer", post(update_brush_layer))
+        .route("/update_inpaint_mask", post(update_inpaint_mask))
+        .route("/update_text_blocks", post(update_text_blocks))
+        .route("/undo", post(undo))
+        .route("/redo", post(redo))
+        .route("/llm_generate", post(llm_generate))
+        .route("/llm_cancel", post(llm_cancel))
+        .route("/get_glossary", get(get_glossary).post(get_glossary))
+        .route("/set_glossary", post(set_glossary))
+        .route("/check_glossary", post(check_glossary))
+        .route(
+            "/get_generate_options",
+            get(get_generate_options).post(get_generate_options),
+        )
+        .route("/set_generate_options", post(set_generate_options))
+        .route("/jobs", get(list_jobs).post(submit_job))
+}
+
 async fn app_version() -> impl IntoResponse {
     Json(version::current().to_string())
 }
 
-async fn get_documents(State(state): State<ApiState>) -> ApiResult<Json<Vec<Document>>> {
-    let docs = operations::get_documents(state.app_state()).await?;
+async fn get_documents(project: CurrentProject) -> ApiResult<Json<Vec<Document>>> {
+    let docs = operations::get_documents(&project.state).await?;
     Ok(Json(docs))
 }
 
 async fn open_documents(
-    State(state): State<ApiState>,
-    mut multipart: Multipart,
+    project: CurrentProject,
+    multipart: Multipart,
 ) -> ApiResult<Json<Vec<Document>>> {
+    let upload = read_upload(multipart).await?;
+    let docs = operations::load_documents(upload.inputs, &upload.order).map_err(ApiError::from)?;
+    // nothing could be read, keep the current session
+    if docs.documents.is_empty() {
+        return Ok(Json(operations::get_documents(&project.state).await?));
+    }
+    let docs = operations::set_documents(&project.state, docs)
+        .await
+        .map_err(ApiError::from)?;
+    project.autosave.reset().await?;
+    Ok(Json(docs))
+}
+
+async fn insert_documents(
+    project: CurrentProject,
+    multipart: Multipart,
+) -> ApiResult<Json<Vec<Document>>> {
+    let upload = read_upload(multipart).await?;
+    let docs = operations::load_documents(upload.inputs, &upload.order).map_err(ApiError::from)?;
+    let docs = operations::insert_documents(&project.state, upload.position, docs)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(docs))
+}
+
+async fn add_documents(
+    project: CurrentProject,
+    multipart: Multipart,
+) -> ApiResult<Json<Vec<Document>>> {
+    let upload = read_upload(multipart).await?;
+    let docs = operations::load_documents(upload.inputs, &upload.order).map_err(ApiError::from)?;
+    let docs = operations::add_documents(&project.state, docs)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(docs))
+}
+
+async fn remove_document(
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
+) -> ApiResult<Json<Vec<Document>>> {
+    let docs = operations::remove_document(&project.state, payload.to_ref()?)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(docs))
+}
+
+async fn reorder_documents(
+    project: CurrentProject,
+    Json(payload): Json<ReorderPayload>,
+) -> ApiResult<Json<Vec<Document>>> {
+    let docs = operations::reorder_documents(&project.state, payload.document_ids)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(docs))
+}
+
+struct Upload {
+    inputs: Vec<DocumentInput>,
+    /// Optional `position` form field, where to insert the uploaded pages.
+    position: Option<usize>,
+    /// Optional `sortBy` and `order` form fields, `order` takes the content of an
+    /// order file, either as text or as an uploaded file.
+    order: PageOrder,
+}
+
+async fn read_upload(mut multipart: Multipart) -> ApiResult<Upload> {
     let mut inputs = Vec::new();
+    let mut position = None;
+    let mut order = PageOrder::default();
     while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("position") if field.file_name().is_none() => {
+                let value = field.text().await?;
+                position =
+                    Some(value.trim().parse().map_err(|_| {
+                        ApiError::bad_request(format!("Invalid position: {value}"))
+                    })?);
+                continue;
+            }
+            Some("sortBy") if field.file_name().is_none() => {
+                let value = field.text().await?;
+                order.sort_by = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| ApiError::bad_request(format!("Invalid sortBy: {value}")))?;
+                continue;
+            }
+            Some("order") => {
+                order.order = PageOrder::parse_order(&field.text().await?);
+                continue;
+            }
+            _ => {}
+        }
+
         let file_name = field
             .file_name()
             .map(|s| s.to_string())
@@ -320,6 +538,8 @@ async fn open_documents(
         inputs.push(DocumentInput {
             path: PathBuf::from(file_name),
             bytes: data.to_vec(),
+            modified: None,
+            khr_file: false,
         });
     }
 
@@ -327,29 +547,30 @@ async fn open_documents(
         return Err(ApiError::bad_request("No files uploaded"));
     }
 
-    let docs = operations::load_documents(inputs).map_err(ApiError::from)?;
-    let docs = operations::set_documents(state.app_state(), docs)
-        .await
-        .map_err(ApiError::from)?;
-    Ok(Json(docs))
+    Ok(Upload {
+        inputs,
+        position,
+        order,
+    })
 }
 
-async fn save_documents(State(state): State<ApiState>) -> ApiResult<Response> {
-    let filename = operations::default_khr_filename(state.app_state())
+async fn save_documents(project: CurrentProject) -> ApiResult<Response> {
+    let filename = operations::default_khr_filename(&project.state)
         .await
         .ok_or_else(|| ApiError::bad_request("No documents to save"))?;
-    let bytes = operations::serialize_state(state.app_state())
+    let bytes = operations::serialize_state(&project.state)
         .await
         .map_err(ApiError::from)?;
+    project.autosave.mark_saved().await?;
 
     attachment_response(&filename, bytes, "application/octet-stream")
 }
 
 async fn export_document(
-    State(state): State<ApiState>,
-    Json(payload): Json<IndexPayload>,
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
 ) -> ApiResult<Response> {
-    let export = operations::export_document(state.app_state(), payload.index)
+    let export = operations::export_document(&project.state, payload.to_ref()?)
         .await
         .map_err(ApiError::from)?;
     let ext = Path::new(&export.filename)
@@ -359,19 +580,100 @@ async fn export_document(
     attachment_response(&export.filename, export.bytes, mime_from_ext(ext))
 }
 
-async fn export_all_documents(State(state): State<ApiState>) -> ApiResult<Response> {
-    let exports = operations::export_all_documents(state.app_state())
+async fn export_all_documents(project: CurrentProject) -> ApiResult<Response> {
+    let exports = operations::export_all_documents(&project.state)
         .await
         .map_err(ApiError::from)?;
     let zip_bytes = zip_exports(exports).map_err(ApiError::from)?;
     attachment_response("export.zip", zip_bytes, "application/zip")
 }
 
+async fn export_cbz(project: CurrentProject) -> ApiResult<Response> {
+    let export = operations::export_cbz(&project.state)
+        .await
+        .map_err(ApiError::from)?;
+    attachment_response(
+        &export.filename,
+        export.bytes,
+        "application/vnd.comicbook+zip",
+    )
+}
+
+async fn export_psd(
+    State(state): State<ApiState>,
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
+) -> ApiResult<Response> {
+    let export = operations::export_psd(&project.state, state.renderer(), payload.to_ref()?)
+        .await
+        .map_err(ApiError::from)?;
+    attachment_response(&export.filename, export.bytes, "image/vnd.adobe.photoshop")
+}
+
+async fn export_epub(
+    project: CurrentProject,
+    options: Option<Json<BookOptions>>,
+) -> ApiResult<Response> {
+    let options = options.map(|Json(options)| options).unwrap_or_default();
+    let export = operations::export_epub(&project.state, options)
+        .await
+        .map_err(ApiError::from)?;
+    attachment_response(&export.filename, export.bytes, "application/epub+zip")
+}
+
+async fn export_pdf(
+    project: CurrentProject,
+    options: Option<Json<BookOptions>>,
+) -> ApiResult<Response> {
+    let options = options.map(|Json(options)| options).unwrap_or_default();
+    let export = operations::export_pdf(&project.state, options)
+        .await
+        .map_err(ApiError::from)?;
+    attachment_response(&export.filename, export.bytes, "application/pdf")
+}
+
+async fn export_script(
+    project: CurrentProject,
+    options: Option<Json<ScriptOptions>>,
+) -> ApiResult<Response> {
+    let options = options.map(|Json(options)| options).unwrap_or_default();
+    let mime = options.format.mime();
+    let export = operations::export_script(&project.state, options)
+        .await
+        .map_err(ApiError::from)?;
+    attachment_response(&export.filename, export.bytes, mime)
+}
+
+#[derive(Deserialize)]
+struct ImportScriptPayload {
+    /// The script file as text.
+    content: String,
+    #[serde(flatten)]
+    options: ImportOptions,
+}
+
+async fn import_script(
+    State(state): State<ApiState>,
+    project: CurrentProject,
+    Json(payload): Json<ImportScriptPayload>,
+) -> ApiResult<Json<ImportReport>> {
+    let report = operations::import_script(
+        &project.state,
+        state.renderer(),
+        payload.content.as_bytes(),
+        payload.options,
+    )
+    .await
+    .map_err(ApiError::from)?;
+    Ok(Json(report))
+}
+
 async fn detect(
     State(state): State<ApiState>,
-    Json(payload): Json<IndexPayload>,
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
 ) -> ApiResult<Json<Document>> {
-    let doc = operations::detect(state.app_state(), state.ml(), payload.index)
+    let doc = operations::detect(&project.state, state.ml(), payload.to_ref()?)
         .await
         .map_err(ApiError::from)?;
     Ok(Json(doc))
@@ -379,9 +681,10 @@ async fn detect(
 
 async fn ocr(
     State(state): State<ApiState>,
-    Json(payload): Json<IndexPayload>,
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
 ) -> ApiResult<Json<Document>> {
-    let doc = operations::ocr(state.app_state(), state.ml(), payload.index)
+    let doc = operations::ocr(&project.state, state.ml(), payload.to_ref()?)
         .await
         .map_err(ApiError::from)?;
     Ok(Json(doc))
@@ -389,21 +692,22 @@ async fn ocr(
 
 async fn inpaint(
     State(state): State<ApiState>,
-    Json(payload): Json<IndexPayload>,
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
 ) -> ApiResult<Json<Document>> {
-    let doc = operations::inpaint(state.app_state(), state.ml(), payload.index)
+    let doc = operations::inpaint(&project.state, state.ml(), payload.to_ref()?)
         .await
         .map_err(ApiError::from)?;
     Ok(Json(doc))
 }
 
 async fn update_inpaint_mask(
-    State(state): State<ApiState>,
+    project: CurrentProject,
     Json(payload): Json<InpaintMaskPayload>,
 ) -> ApiResult<Json<Document>> {
     let doc = operations::update_inpaint_mask(
-        state.app_state(),
-        payload.index,
+        &project.state,
+        payload.document.to_ref()?,
         payload.mask,
         payload.region,
     )
@@ -413,12 +717,12 @@ async fn update_inpaint_mask(
 }
 
 async fn update_brush_layer(
-    State(state): State<ApiState>,
+    project: CurrentProject,
     Json(payload): Json<BrushPayload>,
 ) -> ApiResult<Json<Document>> {
     let doc = operations::update_brush_layer(
-        state.app_state(),
-        payload.index,
+        &project.state,
+        payload.document.to_ref()?,
         payload.patch,
         payload.region,
     )
@@ -429,23 +733,29 @@ async fn update_brush_layer(
 
 async fn inpaint_partial(
     State(state): State<ApiState>,
+    project: CurrentProject,
     Json(payload): Json<InpaintPartialPayload>,
 ) -> ApiResult<Json<Document>> {
-    let doc =
-        operations::inpaint_partial(state.app_state(), state.ml(), payload.index, payload.region)
-            .await
-            .map_err(ApiError::from)?;
+    let doc = operations::inpaint_partial(
+        &project.state,
+        state.ml(),
+        payload.document.to_ref()?,
+        payload.region,
+    )
+    .await
+    .map_err(ApiError::from)?;
     Ok(Json(doc))
 }
 
 async fn render(
     State(state): State<ApiState>,
+    project: CurrentProject,
     Json(payload): Json<RenderPayload>,
 ) -> ApiResult<Json<Document>> {
     let doc = operations::render(
-        state.app_state(),
+        &project.state,
         state.renderer(),
-        payload.index,
+        payload.document.to_ref()?,
         payload.text_block_index,
         payload.shader_effect,
     )
@@ -455,10 +765,34 @@ async fn render(
 }
 
 async fn update_text_blocks(
-    State(state): State<ApiState>,
+    project: CurrentProject,
     Json(payload): Json<TextBlocksPayload>,
 ) -> ApiResult<Json<Document>> {
-    let doc = operations::update_text_blocks(state.app_state(), payload.index, payload.text_blocks)
+    let doc = operations::update_text_blocks(
+        &project.state,
+        payload.document.to_ref()?,
+        payload.text_blocks,
+    )
+    .await
+    .map_err(ApiError::from)?;
+    Ok(Json(doc))
+}
+
+async fn undo(
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
+) -> ApiResult<Json<Document>> {
+    let doc = operations::undo(&project.state, payload.to_ref()?)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(doc))
+}
+
+async fn redo(
+    project: CurrentProject,
+    Json(payload): Json<DocumentPayload>,
+) -> ApiResult<Json<Document>> {
+    let doc = operations::redo(&project.state, payload.to_ref()?)
         .await
         .map_err(ApiError::from)?;
     Ok(Json(doc))
@@ -484,6 +818,32 @@ async fn llm_load(
     Ok(StatusCode::NO_CONTENT)
 }
 
+async fn llm_translators(
+    State(state): State<ApiState>,
+) -> ApiResult<Json<Vec<llm::TranslatorSummary>>> {
+    Ok(Json(operations::llm_translators(state.llm())))
+}
+
+async fn llm_add_translator(
+    State(state): State<ApiState>,
+    Json(config): Json<llm::TranslatorConfig>,
+) -> ApiResult<StatusCode> {
+    operations::llm_add_translator(state.llm(), config)
+        .map_err(|err| ApiError::bad_request(err.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn llm_remove_translator(
+    State(state): State<ApiState>,
+    UrlPath(id): UrlPath<String>,
+) -> ApiResult<StatusCode> {
+    let removed = operations::llm_remove_translator(state.llm(), &id).map_err(ApiError::from)?;
+    if !removed {
+        return Err(ApiError::not_found(format!("Translator `{id}` not found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn llm_offload(State(state): State<ApiState>) -> ApiResult<StatusCode> {
     operations::llm_offload(state.llm())
         .await
@@ -498,22 +858,223 @@ async fn llm_ready(State(state): State<ApiState>) -> ApiResult<Json<bool>> {
     Ok(Json(ready))
 }
 
+/// Cancel the translations of a document, of every document of the project
+/// without a body.
+async fn llm_cancel(
+    project: CurrentProject,
+    payload: Option<Json<DocumentPayload>>,
+) -> ApiResult<StatusCode> {
+    let document = match payload {
+        Some(Json(DocumentPayload {
+            document_id: None,
+            index: None,
+        }))
+        | None => None,
+        Some(Json(payload)) => Some(payload.to_ref()?),
+    };
+    operations::llm_cancel(&project.state, document)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Server-sent events stream of translations as they are generated, one
+/// `progress` event with the text so far per change of a text block.
+async fn llm_events(
+    State(state): State<ApiState>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    progress_events(state.llm().subscribe())
+}
+
 async fn llm_generate(
     State(state): State<ApiState>,
+    project: CurrentProject,
     Json(payload): Json<LlmGeneratePayload>,
 ) -> ApiResult<Json<Document>> {
+    validate_options(payload.options.as_ref())?;
     let doc = operations::llm_generate(
-        state.app_state(),
+        &project.state,
         state.llm(),
-        payload.index,
+        payload.document.to_ref()?,
         payload.text_block_index,
         payload.language,
+        payload.context_pages,
+        payload.options,
     )
     .await
     .map_err(ApiError::from)?;
     Ok(Json(doc))
 }
 
+fn validate_options(options: Option<&GenerateOptions>) -> ApiResult<()> {
+    match options {
+        Some(options) => options
+            .validate()
+            .map_err(|err| ApiError::bad_request(err.to_string())),
+        None => Ok(()),
+    }
+}
+
+async fn get_glossary(project: CurrentProject) -> ApiResult<Json<Vec<GlossaryEntry>>> {
+    Ok(Json(operations::get_glossary(&project.state).await))
+}
+
+async fn set_glossary(
+    project: CurrentProject,
+    Json(entries): Json<Vec<GlossaryEntry>>,
+) -> ApiResult<Json<Vec<GlossaryEntry>>> {
+    Ok(Json(
+        operations::set_glossary(&project.state, entries).await,
+    ))
+}
+
+async fn get_generate_options(project: CurrentProject) -> Json<Option<GenerateOptions>> {
+    Json(operations::get_generate_options(&project.state).await)
+}
+
+/// A `null` body goes back to the translator's defaults.
+async fn set_generate_options(
+    project: CurrentProject,
+    Json(options): Json<Option<GenerateOptions>>,
+) -> ApiResult<Json<Option<GenerateOptions>>> {
+    validate_options(options.as_ref())?;
+    let options = operations::set_generate_options(&project.state, options)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(options))
+}
+
+/// Check one document when given, otherwise the whole project.
+async fn check_glossary(
+    project: CurrentProject,
+    payload: Option<Json<DocumentPayload>>,
+) -> ApiResult<Json<Vec<GlossaryIssue>>> {
+    let document = match payload {
+        Some(Json(payload)) if payload.document_id.is_some() || payload.index.is_some() => {
+            Some(payload.to_ref()?)
+        }
+        _ => None,
+    };
+    let issues = operations::check_glossary(&project.state, document)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(issues))
+}
+
+async fn submit_job(
+    State(state): State<ApiState>,
+    project: CurrentProject,
+    Json(request): Json<JobRequest>,
+) -> ApiResult<Json<JobInfo>> {
+    let job = state
+        .jobs()
+        .submit(state.resources.clone(), &project, request)
+        .await
+        .map_err(|err| ApiError::bad_request(err.to_string()))?;
+    Ok(Json(job))
+}
+
+async fn list_jobs(
+    State(state): State<ApiState>,
+    project: CurrentProject,
+) -> ApiResult<Json<Vec<JobInfo>>> {
+    let jobs = state
+        .jobs()
+        .list()
+        .into_iter()
+        .filter(|job| job.project == project.id)
+        .collect();
+    Ok(Json(jobs))
+}
+
+async fn get_job(
+    State(state): State<ApiState>,
+    UrlPath(id): UrlPath<u64>,
+) -> ApiResult<Json<JobInfo>> {
+    let job = state
+        .jobs()
+        .get(id)
+        .ok_or_else(|| ApiError::not_found(format!("Job {id} not found")))?;
+    Ok(Json(job))
+}
+
+async fn cancel_job(
+    State(state): State<ApiState>,
+    UrlPath(id): UrlPath<u64>,
+) -> ApiResult<Json<JobInfo>> {
+    let job = state
+        .jobs()
+        .cancel(id)
+        .map_err(|err| ApiError::bad_request(err.to_string()))?;
+    Ok(Json(job))
+}
+
+/// Server-sent events stream of job progress, one `progress` event per change.
+async fn job_events(
+    State(state): State<ApiState>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    progress_events(state.jobs().subscribe())
+}
+
+/// Send each broadcast value as a `progress` event. Values missed by a slow
+/// client are skipped.
+fn progress_events<T: Clone + Serialize + Send + 'static>(
+    events: tokio::sync::broadcast::Receiver<T>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let events = stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(value) => {
+                    let Ok(event) = Event::default().event("progress").json_data(&value) else {
+                        continue;
+                    };
+                    return Some((Ok(event), events));
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateProjectPayload {
+    name: String,
+}
+
+async fn list_projects(State(state): State<ApiState>) -> ApiResult<Json<Vec<ProjectInfo>>> {
+    Ok(Json(state.projects().list().await))
+}
+
+async fn create_project(
+    State(state): State<ApiState>,
+    Json(payload): Json<CreateProjectPayload>,
+) -> ApiResult<Json<ProjectInfo>> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("Project name must not be empty"));
+    }
+    let project = state
+        .projects()
+        .create(name.to_string())
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(project.info().await))
+}
+
+async fn close_project(
+    State(state): State<ApiState>,
+    project: CurrentProject,
+) -> ApiResult<StatusCode> {
+    state
+        .projects()
+        .remove(&project.id, state.jobs())
+        .await
+        .map_err(|err| ApiError::bad_request(err.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 fn attachment_response(filename: &str, bytes: Vec<u8>, content_type: &str) -> ApiResult<Response> {
     let mut response = Response::new(Body::from(bytes));
     *response.status_mut() = StatusCode::OK;
@@ -523,12 +1084,32 @@ fn attachment_response(filename: &str, bytes: Vec<u8>, content_type: &str) -> Ap
     );
     response.headers_mut().insert(
         header::CONTENT_DISPOSITION,
-        HeaderValue::from_str(&format!("attachment; filename=\"{filename}\""))
+        HeaderValue::from_str(&content_disposition(filename))
             .map_err(|err| ApiError::internal(err.to_string()))?,
     );
     Ok(response)
 }
 
+/// Header values must be ASCII, names in other scripts are sent percent-encoded
+/// as defined by RFC 6266.
+fn content_disposition(filename: &str) -> String {
+    if filename.is_ascii() {
+        return format!("attachment; filename=\"{filename}\"");
+    }
+    let fallback = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
+    let encoded = filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect::<String>();
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
 fn mime_from_ext(ext: &str) -> &'static str {
     match ext.to_ascii_lowercase().as_str() {
         "png" => "image/png",
@@ -81,11 +81,17 @@ async fn translate_request(api_state: &ApiState, mut multipart: Multipart) -> Ap
 
     let image_bytes =
         image_bytes.ok_or_else(|| ApiError::bad_request("Field \"image\" is required"))?;
-    let documents = operations::load_documents(vec![DocumentInput {
-        path: PathBuf::from(file_name.unwrap_or_else(|| "upload.png".to_string())),
-        bytes: image_bytes,
-    }])
-    .map_err(ApiError::from)?;
+    let documents = operations::load_documents(
+        vec![DocumentInput {
+            path: PathBuf::from(file_name.unwrap_or_else(|| "upload.png".to_string())),
+            bytes: image_bytes,
+            modified: None,
+            khr_file: false,
+        }],
+        &Default::default(),
+    )
+    .map_err(ApiError::from)?
+    .documents;
 
     if documents.is_empty() {
         return Err(ApiError::bad_request("Failed to decode image"));
@@ -93,41 +99,35 @@ async fn translate_request(api_state: &ApiState, mut multipart: Multipart) -> Ap
 
     let target_language = config.unwrap_or_default().target_language();
 
-    let working_state: state::AppState = Arc::new(RwLock::new(state::State { documents }));
-    let doc_index = 0usize;
+    let working_state: state::AppState = Arc::new(RwLock::new(state::State {
+        documents,
+        ..Default::default()
+    }));
+    let document = state::DocumentRef::Index(0);
 
-    // check llm ready or load the first one
-    if !(operations::llm_ready(api_state.llm()).await?) {
-        let model_id = operations::llm_list(api_state.llm()).first().cloned();
-        if model_id.is_none() {
-            return Err(ApiError::internal("No LLM model available"));
-        }
-        operations::llm_load(api_state.llm(), model_id.unwrap().id).await?;
-
-        tracing::info!("Waiting up to 60 seconds for LLM to be ready...");
-        let mut ready;
-        for _ in 0..60 {
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            ready = operations::llm_ready(api_state.llm()).await?;
-            if ready {
-                break;
-            }
-        }
-    }
+    operations::llm_ensure_ready(api_state.llm()).await?;
 
-    let _ = operations::detect(&working_state, api_state.ml(), doc_index).await?;
-    let _ = operations::ocr(&working_state, api_state.ml(), doc_index).await?;
-    let _ = operations::inpaint(&working_state, api_state.ml(), doc_index).await?;
+    let _ = operations::detect(&working_state, api_state.ml(), document.clone()).await?;
+    let _ = operations::ocr(&working_state, api_state.ml(), document.clone()).await?;
+    let _ = operations::inpaint(&working_state, api_state.ml(), document.clone()).await?;
     let _ = operations::llm_generate(
         &working_state,
         api_state.llm(),
-        doc_index,
+        document.clone(),
         None,
         target_language,
+        None,
+        None,
+    )
+    .await?;
+    let doc = operations::render(
+        &working_state,
+        api_state.renderer(),
+        document.clone(),
+        None,
+        None,
     )
     .await?;
-    let doc =
-        operations::render(&working_state, api_state.renderer(), doc_index, None, None).await?;
 
     let image = doc
         .rendered
@@ -137,7 +137,6 @@ async fn translate_request(api_state: &ApiState, mut multipart: Multipart) -> Ap
 
     let mut buf = Vec::new();
     image
-        .0
         .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
         .map_err(|err| ApiError::internal(err.to_string()))?;
 
@@ -1,20 +1,26 @@
 use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Result;
-use clap::{Parser, ValueHint};
+use clap::{Parser, Subcommand, ValueHint};
 use koharu_ml::cuda_is_available;
 use koharu_runtime::{ensure_dylibs, preload_dylibs};
 use once_cell::sync::Lazy;
 use rfd::MessageDialog;
 use tauri::{Emitter, Manager};
-use tokio::sync::RwLock;
 use tracing::warn;
 use tracing_subscriber::fmt::format::FmtSpan;
 
 use crate::{
-    api, command, llm, ml, operations,
+    api,
+    autosave::Autosave,
+    batch::{self, TranslateArgs},
+    command,
+    jobs::{self, Jobs},
+    llm, ml, operations,
+    project::Projects,
     renderer::Renderer,
-    state::{AppState, Document, State},
+    sort::PageOrder,
+    state::{AppState, LoadedDocuments},
     update,
 };
 
@@ -152,13 +158,20 @@ mod windows_magics {
 static APP_ROOT: Lazy<PathBuf> = Lazy::new(resolve_app_root);
 static LIB_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("libs"));
 static MODEL_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("models"));
+static AUTOSAVE_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("autosave"));
+static PROMPT_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("prompts"));
 
 #[derive(Clone)]
 pub struct AppResources {
+    /// Documents of the default project, used by the GUI.
     pub state: AppState,
     pub ml: Arc<ml::Model>,
     pub llm: Arc<llm::Model>,
     pub renderer: Arc<Renderer>,
+    /// Autosave journal of the default project.
+    pub autosave: Arc<Autosave>,
+    pub jobs: Arc<Jobs>,
+    pub projects: Arc<Projects>,
 }
 
 #[derive(Parser)]
@@ -173,6 +186,7 @@ struct Cli {
     download: bool,
     #[arg(
         long,
+        global = true,
         help = "Force using CPU even if GPU is available",
         default_value_t = false
     )]
@@ -186,6 +200,7 @@ struct Cli {
     bind: Option<String>,
     #[arg(
         long,
+        global = true,
         help = "Enable debug mode with console output",
         default_value_t = false
     )]
@@ -196,17 +211,23 @@ struct Cli {
         help = "Open file on startup"
     )]
     path: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn load_documents_from_path(path: PathBuf) -> Result<Vec<Document>> {
+#[derive(Subcommand)]
+enum Command {
+    /// Translate pages without the GUI and write the rendered results to a folder
+    Translate(TranslateArgs),
+}
+
+fn load_documents_from_path(path: PathBuf) -> Result<LoadedDocuments> {
     if !path.exists() {
         return Err(anyhow::anyhow!("File not found: {}", path.display()));
     }
 
-    let bytes = std::fs::read(&path)?;
-    let inputs = vec![operations::DocumentInput { path, bytes }];
-    let docs =
-        operations::load_documents(inputs).map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    let docs = operations::load_documents_from_paths(vec![path], &PageOrder::default())
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
     Ok(docs)
 }
 
@@ -231,6 +252,8 @@ fn initialize(headless: bool, _debug_flag: bool) -> Result<()> {
 
     // hook model cache dir
     koharu_ml::set_cache_dir(MODEL_ROOT.to_path_buf())?;
+    // user prompt templates, one directory per model id
+    koharu_ml::set_prompt_dir(PROMPT_ROOT.to_path_buf())?;
 
     if headless {
         std::panic::set_hook(Box::new(|info| {
@@ -287,15 +310,24 @@ async fn build_resources(use_cpu: bool, _register_file_assoc: bool) -> Result<Ap
     }
 
     let ml = Arc::new(ml::Model::new(use_cpu).await?);
-    let llm = Arc::new(llm::Model::new(use_cpu));
+    let llm = Arc::new(llm::Model::with_config(
+        use_cpu,
+        APP_ROOT.join("translators.json"),
+    ));
     let renderer = Arc::new(Renderer::new()?);
-    let state = Arc::new(RwLock::new(State::default()));
+    let projects = Arc::new(Projects::new(AUTOSAVE_ROOT.to_path_buf()));
+    let state = projects.default_project().state.clone();
+    let autosave = projects.default_project().autosave.clone();
+    let jobs = Arc::new(Jobs::new());
 
     Ok(AppResources {
         state,
         ml,
         llm,
         renderer,
+        autosave,
+        jobs,
+        projects,
     })
 }
 
@@ -306,10 +338,25 @@ async fn setup(
 ) -> Result<()> {
     let resources = build_resources(use_cpu, true).await?;
     let state = resources.state.clone();
-
+    let autosave = resources.autosave.clone();
+    let projects = resources.projects.clone();
+
+    let handle = app.clone();
+    tauri::async_runtime::spawn(jobs::forward_events(
+        resources.jobs.subscribe(),
+        move |info| Ok(handle.emit("job:progress", info)?),
+    ));
+    let handle = app.clone();
+    tauri::async_runtime::spawn(jobs::forward_events(
+        resources.llm.subscribe(),
+        move |progress| Ok(handle.emit("llm:progress", progress)?),
+    ));
+
+    app.manage(resources.clone());
     app.manage(resources.ml);
     app.manage(resources.llm);
     app.manage(resources.renderer);
+    app.manage(resources.autosave);
 
     app.get_webview_window("splashscreen").unwrap().close()?;
     let main_window = app.get_webview_window("main").unwrap();
@@ -317,8 +364,12 @@ async fn setup(
 
     if let Some(path) = startup_document {
         match load_documents_from_path(path) {
-            Ok(documents) => {
-                let _ = operations::set_documents(&state, documents.clone()).await;
+            Ok(loaded) => {
+                let documents = loaded.documents.clone();
+                let _ = operations::set_documents(&state, loaded).await;
+                if let Err(err) = autosave.reset().await {
+                    warn!(?err, "Failed to reset autosave journal");
+                }
                 if let Err(err) = main_window.emit("documents:opened", &documents) {
                     warn!(?err, "Failed to emit documents:opened event");
                 }
@@ -332,8 +383,34 @@ async fn setup(
                     .show();
             }
         }
+    } else if let Some(session) = autosave.recoverable() {
+        let recover = MessageDialog::new()
+            .set_level(rfd::MessageLevel::Warning)
+            .set_title("Recover unsaved work")
+            .set_description(format!(
+                "Koharu found {} autosaved pages from a session that was not saved. Do you want to recover them?",
+                session.documents
+            ))
+            .set_buttons(rfd::MessageButtons::YesNo)
+            .show();
+
+        if recover == rfd::MessageDialogResult::Yes {
+            match autosave.recover().await {
+                Ok(loaded) => {
+                    let documents = loaded.documents.clone();
+                    let _ = operations::set_documents(&state, loaded).await;
+                    if let Err(err) = main_window.emit("documents:opened", &documents) {
+                        warn!(?err, "Failed to emit documents:opened event");
+                    }
+                }
+                Err(err) => warn!(?err, "Failed to recover autosaved session"),
+            }
+        } else if let Err(err) = autosave.reset().await {
+            warn!(?err, "Failed to discard autosave journal");
+        }
     }
 
+    projects.default_project().spawn_autosave();
     app.manage(state);
 
     Ok(())
@@ -346,27 +423,46 @@ pub async fn run() -> Result<()> {
         path,
         bind,
         debug,
+        command,
     } = Cli::parse();
 
-    initialize(bind.is_some(), debug)?;
+    initialize(bind.is_some() || command.is_some(), debug)?;
 
     if download {
         prefetch().await?;
         return Ok(());
     }
 
+    if let Some(Command::Translate(args)) = command {
+        let resources = build_resources(cpu, false).await?;
+        batch::translate(args, resources).await?;
+        return Ok(());
+    }
+
     if let Some(bind_addr) = bind {
         let resources = build_resources(cpu, false).await?;
 
         if let Some(path) = path {
             match load_documents_from_path(path.clone()) {
-                Ok(documents) => {
-                    if let Err(err) = operations::set_documents(&resources.state, documents).await {
+                Ok(loaded) => {
+                    if let Err(err) = operations::set_documents(&resources.state, loaded).await {
                         warn!(?err, "Failed to store startup documents");
                     }
+                    resources.autosave.reset().await?;
                 }
                 Err(err) => warn!(?err, "Failed to open startup document"),
             }
+        } else if resources.autosave.recoverable().is_some() {
+            match resources.autosave.recover().await {
+                Ok(loaded) => {
+                    operations::set_documents(&resources.state, loaded).await?;
+                }
+                Err(err) => warn!(?err, "Failed to recover autosaved session"),
+            }
+        }
+        resources.projects.default_project().spawn_autosave();
+        if let Err(err) = resources.projects.restore().await {
+            warn!(?err, "Failed to restore projects");
         }
 
         api::serve(bind_addr, resources).await?;
@@ -379,9 +475,19 @@ pub async fn run() -> Result<()> {
             command::open_external,
             command::get_documents,
             command::open_documents,
+            command::insert_documents,
+            command::add_documents,
+            command::remove_document,
+            command::reorder_documents,
             command::save_documents,
             command::export_document,
             command::export_all_documents,
+            command::export_cbz,
+            command::export_psd,
+            command::export_epub,
+            command::export_pdf,
+            command::export_script,
+            command::import_script,
             command::detect,
             command::ocr,
             command::inpaint,
@@ -390,12 +496,26 @@ pub async fn run() -> Result<()> {
             command::update_brush_layer,
             command::update_text_blocks,
             command::update_inpaint_mask,
+            command::undo,
+            command::redo,
+            command::submit_job,
+            command::list_jobs,
+            command::cancel_job,
             command::list_font_families,
             command::llm_list,
             command::llm_load,
+            command::llm_translators,
+            command::llm_add_translator,
+            command::llm_remove_translator,
             command::llm_offload,
             command::llm_ready,
+            command::llm_cancel,
             command::llm_generate,
+            command::get_glossary,
+            command::set_glossary,
+            command::check_glossary,
+            command::get_generate_options,
+            command::set_generate_options,
             update::apply_available_update,
             update::get_available_update,
             update::ignore_update,
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashSet,
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp"];
+pub const COMIC_INFO: &str = "ComicInfo.xml";
+
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+/// Upper bound for the buffer reserved from an entry's declared size, the
+/// header of a crafted archive can claim anything.
+const MAX_RESERVE: u64 = 64 * 1024 * 1024;
+/// Largest decompressed entry read from an archive.
+const MAX_ENTRY_BYTES: u64 = 256 * 1024 * 1024;
+/// Largest decompressed size of all entries read from one archive, a few
+/// kilobytes of deflated zeros can otherwise fill the memory.
+const MAX_TOTAL_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Pages and metadata read from a CBZ/ZIP archive.
+#[derive(Debug, Default)]
+pub struct Archive {
+    /// Image entries in archive order, keyed by their path inside the archive.
+    pub pages: Vec<(PathBuf, Vec<u8>)>,
+    pub comic_info: Option<String>,
+}
+
+pub fn is_zip(bytes: &[u8]) -> bool {
+    bytes.starts_with(ZIP_MAGIC)
+}
+
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Read the image entries and `ComicInfo.xml` of an archive, other files are skipped.
+/// Fails when an entry or the archive decompresses to more than the limits.
+pub fn read_archive(bytes: &[u8]) -> Result<Archive> {
+    read_archive_within(bytes, MAX_ENTRY_BYTES, MAX_TOTAL_BYTES)
+}
+
+fn read_archive_within(bytes: &[u8], max_entry: u64, max_total: u64) -> Result<Archive> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+    let mut archive = Archive::default();
+    let mut remaining = max_total;
+
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let Some(path) = entry.enclosed_name() else {
+            continue;
+        };
+        // resource forks and dotfiles added by archivers on macOS
+        let hidden = path.components().any(|part| {
+            let part = part.as_os_str().to_string_lossy();
+            part.starts_with('.') || part == "__MACOSX"
+        });
+        if hidden {
+            continue;
+        }
+
+        let comic_info = path
+            .file_name()
+            .is_some_and(|name| name.eq_ignore_ascii_case(COMIC_INFO));
+        if !comic_info && !has_extension(&path, IMAGE_EXTENSIONS) {
+            continue;
+        }
+
+        // the declared size can't be trusted, read one byte past the limit
+        // to tell a full entry from a cut off one
+        let limit = max_entry.min(remaining);
+        let mut data = Vec::with_capacity(entry.size().min(limit).min(MAX_RESERVE) as usize);
+        (&mut entry).take(limit + 1).read_to_end(&mut data)?;
+        let size = data.len() as u64;
+        if size > max_entry {
+            bail!(
+                "{} decompresses to more than {max_entry} bytes",
+                path.display()
+            );
+        }
+        if size > remaining {
+            bail!("archive decompresses to more than {max_total} bytes");
+        }
+        remaining -= size;
+
+        if comic_info {
+            archive.comic_info = Some(String::from_utf8(data)?);
+        } else {
+            archive.pages.push((path, data));
+        }
+    }
+
+    Ok(archive)
+}
+
+/// Make entry names unique, repeated names get their 1-based position as a
+/// `0001_` prefix. Names given in `names` are never taken by a prefixed one.
+pub fn unique_names(names: Vec<String>) -> Vec<String> {
+    let taken: HashSet<String> = names.iter().cloned().collect();
+    let mut used = HashSet::with_capacity(names.len());
+    names
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| {
+            if used.insert(name.clone()) {
+                return name;
+            }
+            let name = (index + 1..)
+                .map(|position| format!("{position:04}_{name}"))
+                .find(|candidate| !taken.contains(candidate) && !used.contains(candidate))
+                .expect("positions are unbounded");
+            used.insert(name.clone());
+            name
+        })
+        .collect()
+}
+
+/// Write pages into a CBZ, with `ComicInfo.xml` as the first entry when given.
+pub fn write_cbz(pages: &[(String, Vec<u8>)], comic_info: Option<&str>) -> Result<Vec<u8>> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    // pages are already compressed images, storing them keeps export fast
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    if let Some(xml) = comic_info {
+        writer.start_file(COMIC_INFO, deflated)?;
+        writer.write_all(xml.as_bytes())?;
+    }
+    for (name, bytes) in pages {
+        writer.start_file(name.as_str(), stored)?;
+        writer.write_all(bytes)?;
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_written_archives() -> Result<()> {
+        let pages = vec![
+            ("001.png".to_string(), b"first".to_vec()),
+            ("002.JPG".to_string(), b"second".to_vec()),
+        ];
+        let bytes = write_cbz(&pages, Some("<ComicInfo/>"))?;
+        assert!(is_zip(&bytes));
+
+        let archive = read_archive(&bytes)?;
+        assert_eq!(archive.comic_info.as_deref(), Some("<ComicInfo/>"));
+        assert_eq!(
+            archive.pages,
+            vec![
+                (PathBuf::from("001.png"), b"first".to_vec()),
+                (PathBuf::from("002.JPG"), b"second".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skips_hidden_and_other_entries() -> Result<()> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for name in [
+            "__MACOSX/._001.png",
+            "chapter/.thumb.png",
+            "chapter/notes.txt",
+            "chapter/001.png",
+        ] {
+            writer.start_file(name, options)?;
+            writer.write_all(name.as_bytes())?;
+        }
+        let bytes = writer.finish()?.into_inner();
+
+        let archive = read_archive(&bytes)?;
+        assert_eq!(archive.comic_info, None);
+        assert_eq!(
+            archive.pages,
+            vec![(
+                PathBuf::from("chapter/001.png"),
+                b"chapter/001.png".to_vec()
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_archives_over_the_limits() -> Result<()> {
+        let pages = vec![
+            ("001.png".to_string(), vec![0; 600]),
+            ("002.png".to_string(), vec![0; 600]),
+        ];
+        let bytes = write_cbz(&pages, Some(&"x".repeat(600)))?;
+
+        assert_eq!(read_archive_within(&bytes, 600, 1800)?.pages.len(), 2);
+        assert!(read_archive_within(&bytes, 599, 1800).is_err());
+        assert!(read_archive_within(&bytes, 600, 1799).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_repeated_names() {
+        let names = ["a.png", "a.png", "0002_a.png", "b.png", "a.png"].map(str::to_string);
+        assert_eq!(
+            unique_names(names.to_vec()),
+            vec!["a.png", "0003_a.png", "0002_a.png", "b.png", "0005_a.png"]
+        );
+    }
+}
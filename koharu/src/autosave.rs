@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, task::AbortHandle};
+use tracing::{info, warn};
+
+use crate::{
+    khr::{open_khr, save_khr},
+    state::{AppState, Document, LoadedDocuments, ProjectMeta},
+};
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+const JOURNAL_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "journal.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JournalEntry {
+    id: String,
+    name: String,
+    file: String,
+}
+
+/// Sidecar journal describing the autosaved session, pages are stored as
+/// single-document KHR files next to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    version: u32,
+    /// Unix timestamp in seconds of the last journal write.
+    updated_at: u64,
+    /// Set once the session has been saved explicitly, nothing to recover then.
+    clean: bool,
+    documents: Vec<JournalEntry>,
+    #[serde(default)]
+    meta: ProjectMeta,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoverableSession {
+    pub updated_at: u64,
+    pub documents: usize,
+}
+
+#[derive(Default)]
+struct Journaled {
+    /// Revision of each document as last written to the journal.
+    revisions: HashMap<String, u64>,
+    order: Vec<String>,
+    meta: ProjectMeta,
+}
+
+/// Periodically persists documents changed since the last autosave to a journal,
+/// so an unsaved session can be recovered after a crash.
+pub struct Autosave {
+    dir: PathBuf,
+    state: AppState,
+    journaled: Mutex<Journaled>,
+}
+
+impl Autosave {
+    pub fn new(dir: PathBuf, state: AppState) -> Self {
+        Self {
+            dir,
+            state,
+            journaled: Mutex::new(Journaled::default()),
+        }
+    }
+
+    /// Start the background autosave loop.
+    pub fn spawn(self: &Arc<Self>) -> AbortHandle {
+        let autosave = self.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(AUTOSAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(err) = autosave.flush().await {
+                    warn!(?err, "Autosave failed");
+                }
+            }
+        });
+        task.abort_handle()
+    }
+
+    /// Write documents changed since the last autosave to the journal.
+    pub async fn flush(&self) -> Result<()> {
+        let mut journaled = self.journaled.lock().await;
+
+        let (changed, entries, meta) = {
+            let guard = self.state.read().await;
+            let dirty = guard
+                .documents
+                .iter()
+                .any(|doc| doc.revision > journaled.revisions.get(&doc.id).copied().unwrap_or(0));
+            let reordered = !journaled.revisions.is_empty()
+                && !guard
+                    .documents
+                    .iter()
+                    .map(|doc| &doc.id)
+                    .eq(journaled.order.iter());
+
+            let meta_changed = !journaled.revisions.is_empty() && guard.meta != journaled.meta;
+
+            // nothing has been edited since the session was opened or last journaled
+            if !dirty && !reordered && !meta_changed {
+                return Ok(());
+            }
+
+            let changed = guard
+                .documents
+                .iter()
+                .filter(|doc| journaled.revisions.get(&doc.id) != Some(&doc.revision))
+                .cloned()
+                .collect::<Vec<_>>();
+            let entries = guard
+                .documents
+                .iter()
+                .map(|doc| JournalEntry {
+                    id: doc.id.clone(),
+                    name: doc.name.clone(),
+                    file: journal_file(&doc.id),
+                })
+                .collect::<Vec<_>>();
+            (changed, entries, guard.meta.clone())
+        };
+
+        let revisions = changed
+            .iter()
+            .map(|doc| (doc.id.clone(), doc.revision))
+            .collect::<Vec<_>>();
+        let order = entries
+            .iter()
+            .map(|entry| entry.id.clone())
+            .collect::<Vec<_>>();
+
+        let dir = self.dir.clone();
+        let journal_meta = meta.clone();
+        tokio::task::spawn_blocking(move || write_journal(&dir, &changed, entries, journal_meta))
+            .await??;
+
+        journaled.revisions.extend(revisions);
+        journaled.revisions.retain(|id, _| order.contains(id));
+        journaled.order = order;
+        journaled.meta = meta;
+        Ok(())
+    }
+
+    /// Flush pending changes and mark the session as saved, so it is not offered
+    /// for recovery. This includes a recovered session that was saved before
+    /// anything was edited.
+    pub async fn mark_saved(&self) -> Result<()> {
+        self.flush().await?;
+
+        // not while a flush rewrites the manifest
+        let _journaled = self.journaled.lock().await;
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || set_clean(&dir)).await?
+    }
+
+    /// Forget the journaled session, called when a new project replaces the current one.
+    pub async fn reset(&self) -> Result<()> {
+        let mut journaled = self.journaled.lock().await;
+        *journaled = Journaled::default();
+        match tokio::fs::remove_dir_all(&self.dir).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a session was journaled, saved or not.
+    pub fn has_journal(&self) -> bool {
+        self.dir.join(MANIFEST_FILE).exists()
+    }
+
+    /// Summary of an unsaved session left behind by a previous run.
+    pub fn recoverable(&self) -> Option<RecoverableSession> {
+        let manifest = read_manifest(&self.dir).ok()?;
+        if manifest.clean || manifest.documents.is_empty() {
+            return None;
+        }
+        Some(RecoverableSession {
+            updated_at: manifest.updated_at,
+            documents: manifest.documents.len(),
+        })
+    }
+
+    /// Load the documents of the unsaved session left behind by a previous run.
+    pub async fn recover(&self) -> Result<LoadedDocuments> {
+        let dir = self.dir.clone();
+        let loaded = tokio::task::spawn_blocking(move || read_journal(&dir)).await??;
+
+        let mut journaled = self.journaled.lock().await;
+        *journaled = Journaled::default();
+        info!(
+            "Recovered {} documents from autosave",
+            loaded.documents.len()
+        );
+        Ok(loaded)
+    }
+}
+
+fn journal_file(id: &str) -> String {
+    format!("{id}.khr")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn write_journal(
+    dir: &Path,
+    changed: &[Document],
+    documents: Vec<JournalEntry>,
+    meta: ProjectMeta,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    for document in changed {
+        save_khr(
+            dir.join(journal_file(&document.id)),
+            std::slice::from_ref(document),
+            &ProjectMeta::default(),
+        )?;
+    }
+
+    let manifest = Manifest {
+        version: JOURNAL_VERSION,
+        updated_at: now(),
+        clean: false,
+        documents,
+        meta,
+    };
+    write_manifest(dir, &manifest)?;
+
+    // drop journal files of documents no longer in the session
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name != MANIFEST_FILE && !manifest.documents.iter().any(|doc| doc.file == name) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+fn set_clean(dir: &Path) -> Result<()> {
+    if !dir.join(MANIFEST_FILE).exists() {
+        return Ok(());
+    }
+    let mut manifest = read_manifest(dir)?;
+    if manifest.clean {
+        return Ok(());
+    }
+    manifest.clean = true;
+    manifest.updated_at = now();
+    write_manifest(dir, &manifest)
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let bytes = std::fs::read(dir.join(MANIFEST_FILE))?;
+    let manifest: Manifest = serde_json::from_slice(&bytes)?;
+    anyhow::ensure!(
+        manifest.version <= JOURNAL_VERSION,
+        "Unsupported autosave journal version {}",
+        manifest.version
+    );
+    Ok(manifest)
+}
+
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(manifest)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn read_journal(dir: &Path) -> Result<LoadedDocuments> {
+    let manifest = read_manifest(dir)?;
+    let mut documents = Vec::with_capacity(manifest.documents.len());
+    for entry in manifest.documents {
+        let document = open_khr(dir.join(&entry.file)).and_then(|mut khr| khr.read_document(0));
+        match document {
+            Ok(document) => documents.push(document),
+            Err(err) => warn!(?err, "Failed to recover document {}", entry.name),
+        }
+    }
+    Ok(LoadedDocuments {
+        documents,
+        meta: manifest.meta,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::state::{State, TextBlock};
+
+    fn document(id: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            name: id.to_string(),
+            image: DynamicImage::new_rgba8(8, 6).into(),
+            width: 8,
+            height: 6,
+            text_blocks: vec![TextBlock {
+                text: Some("こんにちは".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_journaled_session() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("koharu-journal-{}", std::process::id()));
+        let meta = ProjectMeta {
+            comic_info: Some("<ComicInfo/>".to_string()),
+            ..Default::default()
+        };
+        let state: AppState = Arc::new(RwLock::new(State::default()));
+        state.write().await.set_documents(LoadedDocuments {
+            documents: vec![document("page1"), document("page2")],
+            meta: meta.clone(),
+        });
+        let autosave = Autosave::new(dir.clone(), state.clone());
+
+        // an untouched session is not journaled
+        autosave.flush().await?;
+        assert!(autosave.recoverable().is_none());
+
+        let mut edited = document("page2");
+        edited.text_blocks[0].translation = Some("Hello".to_string());
+        state.write().await.update_document(edited.clone())?;
+        autosave.flush().await?;
+        assert_eq!(
+            autosave.recoverable().map(|session| session.documents),
+            Some(2)
+        );
+
+        // the next launch recovers the session and saves it right away
+        let state: AppState = Arc::new(RwLock::new(State::default()));
+        let autosave = Autosave::new(dir.clone(), state.clone());
+        let recovered = autosave.recover().await?;
+        assert_eq!(recovered.meta, meta);
+        assert_eq!(
+            recovered
+                .documents
+                .iter()
+                .map(|doc| doc.id.as_str())
+                .collect::<Vec<_>>(),
+            ["page1", "page2"]
+        );
+        assert_eq!(recovered.documents[1].text_blocks, edited.text_blocks);
+        state.write().await.set_documents(recovered);
+
+        autosave.mark_saved().await?;
+        assert!(autosave.recoverable().is_none());
+
+        autosave.reset().await?;
+        assert!(!dir.exists());
+        // nothing left to remove
+        autosave.reset().await?;
+        Ok(())
+    }
+}
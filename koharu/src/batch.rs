@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Args, ValueHint};
+use koharu_ml::language_from_tag;
+use tracing::{info, warn};
+
+use crate::{
+    app::AppResources,
+    operations,
+    sort::{PageOrder, SortBy},
+};
+
+#[derive(Debug, Args)]
+pub struct TranslateArgs {
+    #[arg(
+        value_name = "INPUT",
+        value_hint = ValueHint::AnyPath,
+        required = true,
+        help = "Image files, CBZ/ZIP archives, .khr projects or directories containing them"
+    )]
+    inputs: Vec<PathBuf>,
+    #[arg(
+        short,
+        long,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+        help = "Directory to write the translated pages to"
+    )]
+    out: PathBuf,
+    #[arg(
+        short = 'l',
+        long,
+        value_name = "TAG",
+        help = "Target language tag, e.g. en, zh, zh-Hant"
+    )]
+    target_lang: Option<String>,
+    #[arg(
+        short,
+        long,
+        value_name = "MODEL",
+        help = "LLM to translate with, defaults to the preferred model for this system"
+    )]
+    model: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        help = "Also save the translated project as a .khr file"
+    )]
+    khr: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SortBy::Natural,
+        help = "How to order the pages"
+    )]
+    sort: SortBy,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        help = "File listing page file names one per line, listed pages come first in that order"
+    )]
+    order_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PAGES",
+        help = "Give the model the translations of this many previous pages as context"
+    )]
+    context_pages: Option<usize>,
+}
+
+/// Run detect → ocr → inpaint → translate → render over every input page and
+/// write the results to the output directory.
+pub async fn translate(args: TranslateArgs, resources: AppResources) -> Result<()> {
+    if let Some(missing) = args.inputs.iter().find(|input| !input.exists()) {
+        anyhow::bail!("File not found: {}", missing.display());
+    }
+
+    let order = PageOrder {
+        sort_by: args.sort,
+        order: match &args.order_file {
+            Some(path) => PageOrder::read_order_file(path)?,
+            None => Vec::new(),
+        },
+    };
+    let loaded = operations::load_documents_from_paths(args.inputs, &order)?;
+    if loaded.documents.is_empty() {
+        anyhow::bail!("No documents could be loaded");
+    }
+    let total = loaded.documents.len();
+    operations::set_documents(&resources.state, loaded).await?;
+
+    let model_id = match args.model {
+        Some(id) => id,
+        None => operations::llm_list(&resources.llm)
+            .first()
+            .map(|model| model.id.clone())
+            .ok_or_else(|| anyhow::anyhow!("No LLM model available"))?,
+    };
+    info!("Loading LLM {model_id}");
+    operations::llm_load(&resources.llm, model_id).await?;
+    resources.llm.wait_ready().await?;
+
+    let language = args
+        .target_lang
+        .as_deref()
+        .map(|tag| language_from_tag(tag).to_string());
+
+    std::fs::create_dir_all(&args.out)?;
+
+    let mut failed = 0usize;
+    for index in 0..total {
+        info!("Translating page {}/{}", index + 1, total);
+        if let Err(err) =
+            translate_page(&resources, index, language.clone(), args.context_pages).await
+        {
+            warn!(?err, "Failed to translate page {}", index + 1);
+            failed += 1;
+            continue;
+        }
+
+        let export = operations::export_document(&resources.state, index.into()).await?;
+        std::fs::write(args.out.join(&export.filename), export.bytes)?;
+    }
+
+    if let Some(khr) = args.khr {
+        operations::save_state(&resources.state, khr.clone()).await?;
+        info!("Saved project to {}", khr.display());
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} pages failed to translate");
+    }
+
+    info!("Translated {total} pages into {}", args.out.display());
+    Ok(())
+}
+
+async fn translate_page(
+    resources: &AppResources,
+    index: usize,
+    language: Option<String>,
+    context_pages: Option<usize>,
+) -> Result<()> {
+    let state = &resources.state;
+    operations::detect(state, &resources.ml, index.into()).await?;
+    operations::ocr(state, &resources.ml, index.into()).await?;
+    operations::inpaint(state, &resources.ml, index.into()).await?;
+    operations::llm_generate(
+        state,
+        &resources.llm,
+        index.into(),
+        None,
+        language,
+        context_pages,
+        None,
+    )
+    .await?;
+    operations::render(state, &resources.renderer, index.into(), None, None).await?;
+    Ok(())
+}
@@ -0,0 +1,483 @@
+use std::{
+    fmt::Write as _,
+    io::{Cursor, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, ensure};
+use serde::Deserialize;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+// Single file exports of a whole project. Pages are embedded as JPEG, which both
+// formats can show without re-encoding: EPUB references the files directly and
+// PDF passes them through the DCTDecode filter.
+
+/// A page ready to be written into a book.
+#[derive(Debug, Clone)]
+pub struct BookPage {
+    pub name: String,
+    pub jpeg: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookOptions {
+    /// Title stored in the metadata, taken from `ComicInfo.xml` when omitted.
+    pub title: Option<String>,
+    /// Language tag of the translation, e.g. `en`.
+    pub language: Option<String>,
+    /// Put each original page right before its translated page.
+    #[serde(default)]
+    pub include_originals: bool,
+    /// Read pages right to left as in manga, taken from the `Manga` field of
+    /// `ComicInfo.xml` when omitted and right to left without one.
+    pub right_to_left: Option<bool>,
+}
+
+/// Write a fixed-layout EPUB 3 with one page per image.
+pub fn write_epub(
+    pages: &[BookPage],
+    title: &str,
+    language: &str,
+    right_to_left: bool,
+) -> Result<Vec<u8>> {
+    ensure!(!pages.is_empty(), "The project has no pages to export");
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // the mimetype has to be the first entry and must not be compressed
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+
+    let title = escape_xml(title);
+    let language = escape_xml(language);
+    let mut hasher = blake3::Hasher::new();
+    for page in pages {
+        hasher.update(&page.jpeg);
+    }
+    let identifier = hasher.finalize().to_hex();
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    let mut toc = String::new();
+    for (index, page) in pages.iter().enumerate() {
+        let number = index + 1;
+        let cover = if index == 0 {
+            r#" properties="cover-image""#
+        } else {
+            ""
+        };
+        writeln!(
+            manifest,
+            r#"    <item id="image-{number:04}" href="images/{number:04}.jpg" media-type="image/jpeg"{cover}/>"#
+        )?;
+        writeln!(
+            manifest,
+            r#"    <item id="page-{number:04}" href="pages/{number:04}.xhtml" media-type="application/xhtml+xml"/>"#
+        )?;
+        writeln!(spine, r#"    <itemref idref="page-{number:04}"/>"#)?;
+        writeln!(
+            toc,
+            r#"      <li><a href="pages/{number:04}.xhtml">{}</a></li>"#,
+            escape_xml(&page.name)
+        )?;
+
+        zip.start_file(format!("OEBPS/images/{number:04}.jpg"), stored)?;
+        zip.write_all(&page.jpeg)?;
+
+        zip.start_file(format!("OEBPS/pages/{number:04}.xhtml"), deflated)?;
+        write!(
+            zip,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="{language}">
+  <head>
+    <title>{title} - {number}</title>
+    <meta name="viewport" content="width={width}, height={height}"/>
+    <style>html, body {{ margin: 0; padding: 0; }} img {{ display: block; width: {width}px; height: {height}px; }}</style>
+  </head>
+  <body>
+    <img src="../images/{number:04}.jpg" alt=""/>
+  </body>
+</html>
+"#,
+            width = page.width,
+            height = page.height,
+        )?;
+    }
+
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{language}">
+  <head>
+    <title>{title}</title>
+  </head>
+  <body>
+    <nav epub:type="toc">
+      <ol>
+{toc}      </ol>
+    </nav>
+  </body>
+</html>
+"#
+    )?;
+
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id" prefix="rendition: http://www.idpf.org/vocab/rendition/#">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="book-id">urn:koharu:{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:language>{language}</dc:language>
+    <meta property="dcterms:modified">{modified}</meta>
+    <meta property="rendition:layout">pre-paginated</meta>
+    <meta property="rendition:spread">none</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+{manifest}  </manifest>
+  <spine page-progression-direction="{direction}">
+{spine}  </spine>
+</package>
+"#,
+        modified = utc_timestamp(),
+        direction = page_progression(right_to_left),
+    )?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn page_progression(right_to_left: bool) -> &'static str {
+    if right_to_left { "rtl" } else { "ltr" }
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// Write a PDF with one page per image, each page sized to its image.
+pub fn write_pdf(pages: &[BookPage], title: &str, right_to_left: bool) -> Result<Vec<u8>> {
+    ensure!(!pages.is_empty(), "The project has no pages to export");
+    let mut pdf = PdfWriter::new();
+
+    // objects 1 to 3 are the catalog, the page tree and the document info, pages
+    // take three objects each starting at 4
+    let page_ids = (0..pages.len())
+        .map(|index| 4 + index * 3)
+        .collect::<Vec<_>>();
+
+    // viewers that show two pages side by side put the first one on the right
+    let direction = if right_to_left {
+        " /ViewerPreferences << /Direction /R2L >>"
+    } else {
+        ""
+    };
+    pdf.object(
+        1,
+        format!("<< /Type /Catalog /Pages 2 0 R{direction} >>").as_bytes(),
+    );
+    let kids = page_ids
+        .iter()
+        .map(|id| format!("{id} 0 R"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    pdf.object(
+        2,
+        format!("<< /Type /Pages /Kids [{kids}] /Count {} >>", pages.len()).as_bytes(),
+    );
+    pdf.object(
+        3,
+        format!("<< /Title {} /Producer (Koharu) >>", pdf_text_string(title)).as_bytes(),
+    );
+
+    for (page, &id) in pages.iter().zip(&page_ids) {
+        let (contents, image) = (id + 1, id + 2);
+        pdf.object(
+            id,
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {w} {h}] /Resources << /XObject << /Page {image} 0 R >> >> /Contents {contents} 0 R >>",
+                w = page.width,
+                h = page.height,
+            )
+            .as_bytes(),
+        );
+        pdf.stream(
+            contents,
+            "",
+            format!("q {} 0 0 {} 0 0 cm /Page Do Q", page.width, page.height).as_bytes(),
+        );
+        pdf.stream(
+            image,
+            &format!(
+                "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /DCTDecode",
+                page.width, page.height
+            ),
+            &page.jpeg,
+        );
+    }
+
+    Ok(pdf.finish(3 + pages.len() * 3))
+}
+
+struct PdfWriter {
+    bytes: Vec<u8>,
+    offsets: Vec<(usize, usize)>,
+}
+
+impl PdfWriter {
+    fn new() -> Self {
+        Self {
+            // the binary comment marks the file as binary for transfer tools
+            bytes: b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec(),
+            offsets: Vec::new(),
+        }
+    }
+
+    fn object(&mut self, id: usize, body: &[u8]) {
+        self.offsets.push((id, self.bytes.len()));
+        self.bytes
+            .extend_from_slice(format!("{id} 0 obj\n").as_bytes());
+        self.bytes.extend_from_slice(body);
+        self.bytes.extend_from_slice(b"\nendobj\n");
+    }
+
+    fn stream(&mut self, id: usize, dictionary: &str, data: &[u8]) {
+        let mut body = format!("<< {dictionary} /Length {} >>\nstream\n", data.len()).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\nendstream");
+        self.object(id, &body);
+    }
+
+    /// Write the cross-reference table and trailer, `last_id` is the highest object id.
+    fn finish(mut self, last_id: usize) -> Vec<u8> {
+        self.offsets.sort_unstable();
+        let xref = self.bytes.len();
+
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", last_id + 1);
+        for (_, offset) in &self.offsets {
+            table.push_str(&format!("{offset:010} 00000 n \n"));
+        }
+        table.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info 3 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            last_id + 1
+        ));
+        self.bytes.extend_from_slice(table.as_bytes());
+        self.bytes
+    }
+}
+
+/// PDF text string in UTF-16BE, so titles in any script survive.
+fn pdf_text_string(text: &str) -> String {
+    let mut hex = String::from("<FEFF");
+    for unit in text.encode_utf16() {
+        let _ = write!(hex, "{unit:04X}");
+    }
+    hex.push('>');
+    hex
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Value of a top-level element of a `ComicInfo.xml`, e.g. `Title`.
+pub fn comic_info_field(xml: &str, field: &str) -> Option<String> {
+    let start = xml.find(&format!("<{field}>"))? + field.len() + 2;
+    let end = start + xml[start..].find(&format!("</{field}>"))?;
+    let value = xml[start..end]
+        .trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    (!value.is_empty()).then_some(value)
+}
+
+/// Current time as `YYYY-MM-DDThh:mm:ssZ`, as required by `dcterms:modified`.
+fn utc_timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format_timestamp(secs)
+}
+
+fn format_timestamp(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+/// Civil date from days since the epoch, see Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Read};
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    fn pages() -> Vec<BookPage> {
+        ["001", "002", "003"]
+            .iter()
+            .enumerate()
+            .map(|(index, name)| BookPage {
+                name: name.to_string(),
+                jpeg: vec![0xFF, 0xD8, index as u8, 0xFF, 0xD9],
+                width: 800 + index as u32,
+                height: 1200,
+            })
+            .collect()
+    }
+
+    fn read_entry(zip: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        zip.by_name(name)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn epub_lists_every_page() -> Result<()> {
+        let bytes = write_epub(&pages(), "Koharu & co", "en", true)?;
+        let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+
+        let mimetype = zip.by_index(0)?;
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        drop(mimetype);
+        assert_eq!(read_entry(&mut zip, "mimetype")?, b"application/epub+zip");
+
+        let opf = String::from_utf8(read_entry(&mut zip, "OEBPS/content.opf")?)?;
+        let opf = roxmltree::Document::parse(&opf)?;
+        let manifest = opf
+            .descendants()
+            .filter(|node| node.has_tag_name("item"))
+            .map(|item| {
+                (
+                    item.attribute("id").unwrap(),
+                    item.attribute("href").unwrap(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        for href in manifest.values() {
+            assert!(
+                zip.by_name(&format!("OEBPS/{href}")).is_ok(),
+                "{href} is missing"
+            );
+        }
+
+        let spine = opf
+            .descendants()
+            .find(|node| node.has_tag_name("spine"))
+            .unwrap();
+        assert_eq!(spine.attribute("page-progression-direction"), Some("rtl"));
+        let hrefs = spine
+            .children()
+            .filter(|node| node.has_tag_name("itemref"))
+            .map(|itemref| manifest[itemref.attribute("idref").unwrap()])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hrefs,
+            ["pages/0001.xhtml", "pages/0002.xhtml", "pages/0003.xhtml"]
+        );
+        assert_eq!(
+            read_entry(&mut zip, "OEBPS/images/0002.jpg")?,
+            pages()[1].jpeg
+        );
+        Ok(())
+    }
+
+    fn position(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+        bytes
+            .windows(needle.len())
+            .rposition(|window| window == needle)
+    }
+
+    #[test]
+    fn pdf_xref_points_at_objects() -> Result<()> {
+        let pages = pages();
+        let bytes = write_pdf(&pages, "Koharu", false)?;
+
+        let startxref = position(&bytes, b"startxref\n").unwrap() + "startxref\n".len();
+        let xref: usize = std::str::from_utf8(&bytes[startxref..])?
+            .lines()
+            .next()
+            .unwrap()
+            .parse()?;
+        let mut lines = std::str::from_utf8(&bytes[xref..])?.lines();
+        assert_eq!(lines.next(), Some("xref"));
+        assert_eq!(lines.next(), Some("0 13"));
+        assert_eq!(lines.next(), Some("0000000000 65535 f "));
+        for id in 1..13 {
+            let offset: usize = lines.next().unwrap()[..10].parse()?;
+            assert!(
+                bytes[offset..].starts_with(format!("{id} 0 obj\n").as_bytes()),
+                "object {id}"
+            );
+        }
+        assert_eq!(lines.next(), Some("trailer"));
+
+        // every image is passed through unchanged
+        for page in &pages {
+            assert!(position(&bytes, &page.jpeg).is_some());
+        }
+        assert!(position(&bytes, b"/Count 3").is_some());
+        assert!(position(&bytes, b"/R2L").is_none());
+        assert!(position(&write_pdf(&pages, "Koharu", true)?, b"/R2L").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_empty_books() {
+        assert!(write_epub(&[], "Koharu", "en", true).is_err());
+        assert!(write_pdf(&[], "Koharu", true).is_err());
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(
+            format_timestamp(951_782_400 + 3_723),
+            "2000-02-29T01:02:03Z"
+        );
+    }
+}
@@ -1,15 +1,22 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
+use koharu_ml::llm::{GenerateOptions, GlossaryEntry};
 use koharu_renderer::renderer::TextShaderEffect;
 use tauri::State;
-use tracing::warn;
 
 use crate::{
+    app::AppResources,
+    autosave::Autosave,
+    book::BookOptions,
+    glossary::GlossaryIssue,
+    jobs::{JobInfo, JobRequest},
     llm, ml,
-    operations::{self, DocumentInput, InpaintRegion},
+    operations::{self, ExportedDocument, InpaintRegion},
     renderer::Renderer,
     result::Result,
-    state::{AppState, Document, TextBlock},
+    script::{ImportOptions, ImportReport, ScriptOptions},
+    sort::{PageOrder, SortBy},
+    state::{AppState, Document, DocumentRef, TextBlock},
     version,
 };
 
@@ -21,30 +28,97 @@ pub fn open_external(url: &str) -> Result<()> {
 }
 
 #[tauri::command]
-pub async fn open_documents(state: State<'_, AppState>) -> Result<Vec<Document>> {
-    let paths = rfd::FileDialog::new()
-        .add_filter("Supported Files", &["khr", "png", "jpg", "jpeg", "webp"])
-        .set_title("Pick Files")
-        .pick_files()
-        .unwrap_or_default();
-
-    let inputs: Vec<DocumentInput> = paths
-        .into_iter()
-        .filter_map(|path| match std::fs::read(&path) {
-            Ok(bytes) => Some(DocumentInput { path, bytes }),
-            Err(err) => {
-                warn!(?err, "Failed to read document");
-                None
-            }
-        })
-        .collect();
-
-    let documents = operations::load_documents(inputs)?;
-    operations::set_documents(&state, documents.clone()).await?;
+pub async fn open_documents(
+    state: State<'_, AppState>,
+    autosave: State<'_, Arc<Autosave>>,
+    folder: Option<bool>,
+    sort_by: Option<SortBy>,
+    order: Option<Vec<String>>,
+) -> Result<Vec<Document>> {
+    let loaded = operations::load_documents_from_paths(
+        pick_documents(folder.unwrap_or_default()),
+        &page_order(sort_by, order),
+    )?;
+    // the dialog was cancelled or nothing could be read, keep the current session
+    if loaded.documents.is_empty() {
+        return operations::get_documents(&state).await;
+    }
+    let documents = operations::set_documents(&state, loaded).await?;
+    autosave.reset().await?;
 
     Ok(documents)
 }
 
+#[tauri::command]
+pub async fn insert_documents(
+    state: State<'_, AppState>,
+    position: Option<usize>,
+    folder: Option<bool>,
+    sort_by: Option<SortBy>,
+    order: Option<Vec<String>>,
+) -> Result<Vec<Document>> {
+    let loaded = operations::load_documents_from_paths(
+        pick_documents(folder.unwrap_or_default()),
+        &page_order(sort_by, order),
+    )?;
+    operations::insert_documents(&state, position, loaded).await
+}
+
+#[tauri::command]
+pub async fn add_documents(
+    state: State<'_, AppState>,
+    folder: Option<bool>,
+    sort_by: Option<SortBy>,
+    order: Option<Vec<String>>,
+) -> Result<Vec<Document>> {
+    let loaded = operations::load_documents_from_paths(
+        pick_documents(folder.unwrap_or_default()),
+        &page_order(sort_by, order),
+    )?;
+    operations::add_documents(&state, loaded).await
+}
+
+#[tauri::command]
+pub async fn remove_document(
+    state: State<'_, AppState>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<Vec<Document>> {
+    operations::remove_document(&state, DocumentRef::new(document_id, index)?).await
+}
+
+#[tauri::command]
+pub async fn reorder_documents(
+    state: State<'_, AppState>,
+    document_ids: Vec<String>,
+) -> Result<Vec<Document>> {
+    operations::reorder_documents(&state, document_ids).await
+}
+
+fn page_order(sort_by: Option<SortBy>, order: Option<Vec<String>>) -> PageOrder {
+    PageOrder {
+        sort_by: sort_by.unwrap_or_default(),
+        order: order.unwrap_or_default(),
+    }
+}
+
+/// Ask for files to open, or for a folder of pages when `folder` is set.
+fn pick_documents(folder: bool) -> Vec<PathBuf> {
+    if folder {
+        return rfd::FileDialog::new()
+            .set_title("Pick Folder")
+            .pick_folder()
+            .into_iter()
+            .collect();
+    }
+
+    rfd::FileDialog::new()
+        .add_filter("Supported Files", operations::SUPPORTED_EXTENSIONS)
+        .set_title("Pick Files")
+        .pick_files()
+        .unwrap_or_default()
+}
+
 #[tauri::command]
 pub fn app_version() -> String {
     version::current().to_string()
@@ -56,8 +130,12 @@ pub async fn get_documents(state: State<'_, AppState>) -> Result<Vec<Document>>
 }
 
 #[tauri::command]
-pub async fn export_document(state: State<'_, AppState>, index: usize) -> Result<()> {
-    let export = operations::export_document(&state, index).await?;
+pub async fn export_document(
+    state: State<'_, AppState>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<()> {
+    let export = operations::export_document(&state, DocumentRef::new(document_id, index)?).await?;
     let dest = rfd::FileDialog::new()
         .set_title("Select Export Destinition")
         .set_file_name(&export.filename)
@@ -85,7 +163,87 @@ pub async fn export_all_documents(state: State<'_, AppState>) -> Result<()> {
 }
 
 #[tauri::command]
-pub async fn save_documents(state: State<'_, AppState>) -> Result<()> {
+pub async fn export_cbz(state: State<'_, AppState>) -> Result<()> {
+    let export = operations::export_cbz(&state).await?;
+    save_export(export, "Comic Book Archive", "cbz")
+}
+
+#[tauri::command]
+pub async fn export_psd(
+    state: State<'_, AppState>,
+    renderer: State<'_, Arc<Renderer>>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<()> {
+    let export =
+        operations::export_psd(&state, &renderer, DocumentRef::new(document_id, index)?).await?;
+    save_export(export, "Photoshop Document", "psd")
+}
+
+#[tauri::command]
+pub async fn export_epub(state: State<'_, AppState>, options: Option<BookOptions>) -> Result<()> {
+    let export = operations::export_epub(&state, options.unwrap_or_default()).await?;
+    save_export(export, "EPUB", "epub")
+}
+
+#[tauri::command]
+pub async fn export_pdf(state: State<'_, AppState>, options: Option<BookOptions>) -> Result<()> {
+    let export = operations::export_pdf(&state, options.unwrap_or_default()).await?;
+    save_export(export, "PDF", "pdf")
+}
+
+#[tauri::command]
+pub async fn export_script(
+    state: State<'_, AppState>,
+    options: Option<ScriptOptions>,
+) -> Result<()> {
+    let options = options.unwrap_or_default();
+    let format = options.format;
+    let export = operations::export_script(&state, options).await?;
+    save_export(export, "Translation Script", format.extension())
+}
+
+#[tauri::command]
+pub async fn import_script(
+    state: State<'_, AppState>,
+    renderer: State<'_, Arc<Renderer>>,
+    options: Option<ImportOptions>,
+) -> Result<Option<ImportReport>> {
+    let Some(path) = rfd::FileDialog::new()
+        .add_filter(
+            "Translation Script",
+            &["json", "csv", "xlf", "xliff", "txt"],
+        )
+        .set_title("Pick Script")
+        .pick_file()
+    else {
+        return Ok(None);
+    };
+
+    let bytes = std::fs::read(path)?;
+    let report =
+        operations::import_script(&state, &renderer, &bytes, options.unwrap_or_default()).await?;
+    Ok(Some(report))
+}
+
+fn save_export(export: ExportedDocument, filter: &str, extension: &str) -> Result<()> {
+    let dest = rfd::FileDialog::new()
+        .set_title("Select Export Destinition")
+        .add_filter(filter, &[extension])
+        .set_file_name(&export.filename)
+        .save_file()
+        .ok_or_else(|| anyhow::anyhow!("No file selected"))?;
+
+    std::fs::write(dest, export.bytes)?;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn save_documents(
+    state: State<'_, AppState>,
+    autosave: State<'_, Arc<Autosave>>,
+) -> Result<()> {
     let Some(default_filename) = operations::default_khr_filename(&state).await else {
         return Ok(());
     };
@@ -99,8 +257,8 @@ pub async fn save_documents(state: State<'_, AppState>) -> Result<()> {
         return Ok(());
     };
 
-    let bytes = operations::serialize_state(&state).await?;
-    std::fs::write(dest, bytes)?;
+    operations::save_state(&state, dest).await?;
+    autosave.mark_saved().await?;
 
     Ok(())
 }
@@ -109,77 +267,118 @@ pub async fn save_documents(state: State<'_, AppState>) -> Result<()> {
 pub async fn detect(
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
 ) -> Result<Document> {
-    operations::detect(&state, &model, index).await
+    operations::detect(&state, &model, DocumentRef::new(document_id, index)?).await
 }
 
 #[tauri::command]
 pub async fn ocr(
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
 ) -> Result<Document> {
-    operations::ocr(&state, &model, index).await
+    operations::ocr(&state, &model, DocumentRef::new(document_id, index)?).await
 }
 
 #[tauri::command]
 pub async fn inpaint(
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
 ) -> Result<Document> {
-    operations::inpaint(&state, &model, index).await
+    operations::inpaint(&state, &model, DocumentRef::new(document_id, index)?).await
 }
 
 #[tauri::command]
 pub async fn update_inpaint_mask(
     state: State<'_, AppState>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
     mask: Vec<u8>,
     region: Option<InpaintRegion>,
 ) -> Result<Document> {
-    operations::update_inpaint_mask(&state, index, mask, region).await
+    operations::update_inpaint_mask(&state, DocumentRef::new(document_id, index)?, mask, region)
+        .await
 }
 
 #[tauri::command]
 pub async fn update_brush_layer(
     state: State<'_, AppState>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
     patch: Vec<u8>,
     region: InpaintRegion,
 ) -> Result<Document> {
-    operations::update_brush_layer(&state, index, patch, region).await
+    operations::update_brush_layer(&state, DocumentRef::new(document_id, index)?, patch, region)
+        .await
 }
 
 #[tauri::command]
 pub async fn inpaint_partial(
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
     region: InpaintRegion,
 ) -> Result<Document> {
-    operations::inpaint_partial(&state, &model, index, region).await
+    operations::inpaint_partial(
+        &state,
+        &model,
+        DocumentRef::new(document_id, index)?,
+        region,
+    )
+    .await
 }
 
 #[tauri::command]
 pub async fn render(
     state: State<'_, AppState>,
     renderer: State<'_, Arc<Renderer>>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
     text_block_index: Option<usize>,
     shader_effect: Option<TextShaderEffect>,
 ) -> Result<Document> {
-    operations::render(&state, &renderer, index, text_block_index, shader_effect).await
+    operations::render(
+        &state,
+        &renderer,
+        DocumentRef::new(document_id, index)?,
+        text_block_index,
+        shader_effect,
+    )
+    .await
 }
 
 #[tauri::command]
 pub async fn update_text_blocks(
     state: State<'_, AppState>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
     text_blocks: Vec<TextBlock>,
 ) -> Result<Document> {
-    operations::update_text_blocks(&state, index, text_blocks).await
+    operations::update_text_blocks(&state, DocumentRef::new(document_id, index)?, text_blocks).await
+}
+
+#[tauri::command]
+pub async fn undo(
+    state: State<'_, AppState>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<Document> {
+    operations::undo(&state, DocumentRef::new(document_id, index)?).await
+}
+
+#[tauri::command]
+pub async fn redo(
+    state: State<'_, AppState>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<Document> {
+    operations::redo(&state, DocumentRef::new(document_id, index)?).await
 }
 
 #[tauri::command]
@@ -197,6 +396,24 @@ pub async fn llm_load(model: State<'_, Arc<llm::Model>>, id: String) -> Result<(
     operations::llm_load(&model, id).await
 }
 
+#[tauri::command]
+pub fn llm_translators(model: State<'_, Arc<llm::Model>>) -> Vec<llm::TranslatorSummary> {
+    operations::llm_translators(&model)
+}
+
+#[tauri::command]
+pub fn llm_add_translator(
+    model: State<'_, Arc<llm::Model>>,
+    config: llm::TranslatorConfig,
+) -> Result<()> {
+    operations::llm_add_translator(&model, config)
+}
+
+#[tauri::command]
+pub fn llm_remove_translator(model: State<'_, Arc<llm::Model>>, id: String) -> Result<bool> {
+    operations::llm_remove_translator(&model, &id)
+}
+
 #[tauri::command]
 pub async fn llm_offload(model: State<'_, Arc<llm::Model>>) -> Result<()> {
     operations::llm_offload(&model).await
@@ -207,13 +424,98 @@ pub async fn llm_ready(model: State<'_, Arc<llm::Model>>) -> Result<bool> {
     operations::llm_ready(&model).await
 }
 
+#[tauri::command]
+pub async fn llm_cancel(
+    state: State<'_, AppState>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<()> {
+    let document = match (document_id, index) {
+        (None, None) => None,
+        (document_id, index) => Some(DocumentRef::new(document_id, index)?),
+    };
+    operations::llm_cancel(&state, document).await
+}
+
 #[tauri::command]
 pub async fn llm_generate(
     state: State<'_, AppState>,
     model: State<'_, Arc<llm::Model>>,
-    index: usize,
+    document_id: Option<String>,
+    index: Option<usize>,
     text_block_index: Option<usize>,
     language: Option<String>,
+    context_pages: Option<usize>,
+    options: Option<GenerateOptions>,
 ) -> Result<Document> {
-    operations::llm_generate(&state, &model, index, text_block_index, language).await
+    operations::llm_generate(
+        &state,
+        &model,
+        DocumentRef::new(document_id, index)?,
+        text_block_index,
+        language,
+        context_pages,
+        options,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn get_glossary(state: State<'_, AppState>) -> Result<Vec<GlossaryEntry>> {
+    Ok(operations::get_glossary(&state).await)
+}
+
+#[tauri::command]
+pub async fn set_glossary(
+    state: State<'_, AppState>,
+    entries: Vec<GlossaryEntry>,
+) -> Result<Vec<GlossaryEntry>> {
+    Ok(operations::set_glossary(&state, entries).await)
+}
+
+#[tauri::command]
+pub async fn get_generate_options(state: State<'_, AppState>) -> Result<Option<GenerateOptions>> {
+    Ok(operations::get_generate_options(&state).await)
+}
+
+#[tauri::command]
+pub async fn set_generate_options(
+    state: State<'_, AppState>,
+    options: Option<GenerateOptions>,
+) -> Result<Option<GenerateOptions>> {
+    operations::set_generate_options(&state, options).await
+}
+
+#[tauri::command]
+pub async fn check_glossary(
+    state: State<'_, AppState>,
+    document_id: Option<String>,
+    index: Option<usize>,
+) -> Result<Vec<GlossaryIssue>> {
+    let document = match (document_id, index) {
+        (None, None) => None,
+        (document_id, index) => Some(DocumentRef::new(document_id, index)?),
+    };
+    operations::check_glossary(&state, document).await
+}
+
+#[tauri::command]
+pub async fn submit_job(
+    resources: State<'_, AppResources>,
+    request: JobRequest,
+) -> Result<JobInfo> {
+    let resources = resources.inner().clone();
+    let jobs = resources.jobs.clone();
+    let project = resources.projects.default_project().clone();
+    Ok(jobs.submit(resources, &project, request).await?)
+}
+
+#[tauri::command]
+pub fn list_jobs(resources: State<'_, AppResources>) -> Vec<JobInfo> {
+    resources.jobs.list()
+}
+
+#[tauri::command]
+pub fn cancel_job(resources: State<'_, AppResources>, id: u64) -> Result<JobInfo> {
+    Ok(resources.jobs.cancel(id)?)
 }
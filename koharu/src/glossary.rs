@@ -0,0 +1,121 @@
+use koharu_ml::llm::GlossaryEntry;
+use serde::Serialize;
+
+use crate::state::Document;
+
+// The project glossary is given to the translators with each prompt, but models
+// do not always follow it. Translations are checked afterwards so the blocks
+// that missed a term can be fixed by hand.
+
+/// A block whose source text has a glossary term the translation lacks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GlossaryIssue {
+    pub document_id: String,
+    /// Index of the text block in the document.
+    pub index: usize,
+    pub source: String,
+    pub target: String,
+}
+
+/// Trim the entries and drop those without a source or target. A term listed
+/// more than once keeps its last translation.
+pub fn normalize(glossary: Vec<GlossaryEntry>) -> Vec<GlossaryEntry> {
+    let mut normalized: Vec<GlossaryEntry> = Vec::with_capacity(glossary.len());
+    for entry in glossary {
+        let entry = GlossaryEntry {
+            source: entry.source.trim().to_string(),
+            target: entry.target.trim().to_string(),
+            note: entry
+                .note
+                .map(|note| note.trim().to_string())
+                .filter(|note| !note.is_empty()),
+        };
+        if entry.source.is_empty() || entry.target.is_empty() {
+            continue;
+        }
+        match normalized.iter_mut().find(|e| e.source == entry.source) {
+            Some(existing) => *existing = entry,
+            None => normalized.push(entry),
+        }
+    }
+    normalized
+}
+
+/// Translated blocks of `documents` that contain a glossary term in their source
+/// text but not its target term. Untranslated blocks are left out.
+pub fn check(documents: &[Document], glossary: &[GlossaryEntry]) -> Vec<GlossaryIssue> {
+    let mut issues = Vec::new();
+    for document in documents {
+        for (index, block) in document.text_blocks.iter().enumerate() {
+            let (Some(text), Some(translation)) = (&block.text, &block.translation) else {
+                continue;
+            };
+            if translation.trim().is_empty() {
+                continue;
+            }
+            let translation = translation.to_lowercase();
+            for entry in GlossaryEntry::relevant(glossary, text) {
+                if !translation.contains(&entry.target.to_lowercase()) {
+                    issues.push(GlossaryIssue {
+                        document_id: document.id.clone(),
+                        index,
+                        source: entry.source.clone(),
+                        target: entry.target.clone(),
+                    });
+                }
+            }
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::TextBlock;
+
+    fn entry(source: &str, target: &str) -> GlossaryEntry {
+        GlossaryEntry {
+            source: source.to_string(),
+            target: target.to_string(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn flags_missing_terms() {
+        let block = |text: &str, translation: Option<&str>| TextBlock {
+            text: Some(text.to_string()),
+            translation: translation.map(str::to_string),
+            ..Default::default()
+        };
+        let document = Document {
+            id: "doc".to_string(),
+            text_blocks: vec![
+                block("凛さん、おはよう", Some("Good morning, rin.")),
+                block("凛が来た", Some("She is here.")),
+                block("凛？", None),
+                block("おはよう", Some("Morning.")),
+            ],
+            ..Default::default()
+        };
+        let glossary = normalize(vec![
+            entry(" 凛 ", "Ren"),
+            entry("凛", "Rin"),
+            entry("", "empty"),
+        ]);
+        assert_eq!(glossary, vec![entry("凛", "Rin")]);
+
+        let issues = check(&[document], &glossary);
+        assert_eq!(
+            issues,
+            vec![GlossaryIssue {
+                document_id: "doc".to_string(),
+                index: 1,
+                source: "凛".to_string(),
+                target: "Rin".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::{HashMap, VecDeque};
+
+use image::{DynamicImage, GenericImageView, RgbaImage, imageops};
+
+use crate::{
+    image::SerializableDynamicImage,
+    state::{Document, TextBlock},
+};
+
+/// Maximum number of undo steps kept per document.
+const MAX_DEPTH: usize = 50;
+/// Upper bound of memory held by the undo stack of a single document.
+const MAX_BYTES: usize = 256 * 1024 * 1024;
+/// Upper bound of memory held by the undo and redo stacks of all documents.
+const MAX_TOTAL_BYTES: usize = 1024 * 1024 * 1024;
+
+/// Previous content of an image layer.
+#[derive(Debug, Clone)]
+enum LayerPatch {
+    Replace(Option<SerializableDynamicImage>),
+    /// Only the changed rectangle, used for RGBA layers that kept their size,
+    /// e.g. brush strokes and partial inpainting.
+    Region {
+        x: u32,
+        y: u32,
+        pixels: RgbaImage,
+    },
+}
+
+impl LayerPatch {
+    fn diff(
+        before: &Option<SerializableDynamicImage>,
+        after: &Option<SerializableDynamicImage>,
+    ) -> Option<Self> {
+        if before == after {
+            return None;
+        }
+        match (before.as_deref(), after.as_deref()) {
+            (Some(DynamicImage::ImageRgba8(old)), Some(DynamicImage::ImageRgba8(new)))
+                if old.dimensions() == new.dimensions() =>
+            {
+                let (x, y, width, height) = changed_bounds(old, new)?;
+                Some(LayerPatch::Region {
+                    x,
+                    y,
+                    pixels: old.view(x, y, width, height).to_image(),
+                })
+            }
+            _ => Some(LayerPatch::Replace(before.clone())),
+        }
+    }
+
+    /// Restore the layer and return the patch that reverts the restore.
+    fn apply(self, layer: &mut Option<SerializableDynamicImage>) -> Self {
+        match self {
+            LayerPatch::Replace(image) => LayerPatch::Replace(std::mem::replace(layer, image)),
+            LayerPatch::Region { x, y, pixels } => {
+                let Some(Ok(DynamicImage::ImageRgba8(target))) =
+                    layer.as_mut().map(|layer| layer.get_mut())
+                else {
+                    tracing::warn!(
+                        "Layer changed outside of history or failed to decode, skipping region restore"
+                    );
+                    return LayerPatch::Region { x, y, pixels };
+                };
+                let current = target
+                    .view(x, y, pixels.width(), pixels.height())
+                    .to_image();
+                imageops::replace(target, &pixels, x as i64, y as i64);
+                LayerPatch::Region {
+                    x,
+                    y,
+                    pixels: current,
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            LayerPatch::Replace(image) => image.as_deref().map(image_size).unwrap_or(0),
+            LayerPatch::Region { pixels, .. } => pixels.as_raw().len(),
+        }
+    }
+}
+
+/// The fields replaced by one mutation, applying it reverts the mutation.
+#[derive(Debug, Clone)]
+pub struct Patch {
+    text_blocks: Option<Vec<TextBlock>>,
+    segment: Option<LayerPatch>,
+    inpainted: Option<LayerPatch>,
+    rendered: Option<LayerPatch>,
+    brush_layer: Option<LayerPatch>,
+}
+
+impl Patch {
+    /// Compare the layers pixel by pixel, which is slow for large pages.
+    pub fn diff(before: &Document, after: &Document) -> Self {
+        Self {
+            text_blocks: (before.text_blocks != after.text_blocks)
+                .then(|| before.text_blocks.clone()),
+            segment: LayerPatch::diff(&before.segment, &after.segment),
+            inpainted: LayerPatch::diff(&before.inpainted, &after.inpainted),
+            rendered: LayerPatch::diff(&before.rendered, &after.rendered),
+            brush_layer: LayerPatch::diff(&before.brush_layer, &after.brush_layer),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text_blocks.is_none()
+            && self.segment.is_none()
+            && self.inpainted.is_none()
+            && self.rendered.is_none()
+            && self.brush_layer.is_none()
+    }
+
+    fn apply(self, document: &mut Document) -> Self {
+        Self {
+            text_blocks: self
+                .text_blocks
+                .map(|blocks| std::mem::replace(&mut document.text_blocks, blocks)),
+            segment: self.segment.map(|patch| patch.apply(&mut document.segment)),
+            inpainted: self
+                .inpainted
+                .map(|patch| patch.apply(&mut document.inpainted)),
+            rendered: self
+                .rendered
+                .map(|patch| patch.apply(&mut document.rendered)),
+            brush_layer: self
+                .brush_layer
+                .map(|patch| patch.apply(&mut document.brush_layer)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        let text_blocks = self
+            .text_blocks
+            .iter()
+            .flatten()
+            .map(|block| {
+                std::mem::size_of::<TextBlock>()
+                    + block.text.as_ref().map(String::len).unwrap_or(0)
+                    + block.translation.as_ref().map(String::len).unwrap_or(0)
+                    + block.rendered.as_deref().map(image_size).unwrap_or(0)
+            })
+            .sum::<usize>();
+        let layers = [
+            &self.segment,
+            &self.inpainted,
+            &self.rendered,
+            &self.brush_layer,
+        ]
+        .into_iter()
+        .flatten()
+        .map(LayerPatch::size)
+        .sum::<usize>();
+        text_blocks + layers
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    patch: Patch,
+    size: usize,
+    /// Order of the step across all documents, the oldest are evicted first.
+    sequence: u64,
+}
+
+impl Step {
+    fn new(patch: Patch, sequence: u64) -> Self {
+        Self {
+            size: patch.size(),
+            patch,
+            sequence,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct DocumentHistory {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+    bytes: usize,
+}
+
+impl DocumentHistory {
+    fn push_undo(&mut self, step: Step, max_bytes: usize) {
+        self.bytes += step.size;
+        self.undo.push_back(step);
+
+        // drop the oldest steps, but always keep the latest one
+        while self.undo.len() > 1 && (self.undo.len() > MAX_DEPTH || self.bytes > max_bytes) {
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some(step) = self.undo.pop_front() {
+            self.bytes -= step.size;
+        }
+    }
+
+    fn push_redo(&mut self, step: Step) {
+        self.bytes += step.size;
+        self.redo.push(step);
+    }
+
+    fn clear_redo(&mut self) {
+        for step in self.redo.drain(..) {
+            self.bytes -= step.size;
+        }
+    }
+}
+
+/// Per-document undo/redo stacks, keyed by document id.
+#[derive(Debug, Clone)]
+pub struct History {
+    documents: HashMap<String, DocumentHistory>,
+    /// Memory budget of the undo stack of each document.
+    max_bytes: usize,
+    /// Memory budget of the stacks of all documents together.
+    max_total_bytes: usize,
+    next_sequence: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            documents: HashMap::new(),
+            max_bytes: MAX_BYTES,
+            max_total_bytes: MAX_TOTAL_BYTES,
+            next_sequence: 0,
+        }
+    }
+}
+
+impl History {
+    /// Record the transition from `before` to `after` as one undo step.
+    pub fn record(&mut self, before: &Document, after: &Document) {
+        self.push(&before.id, Patch::diff(before, after));
+    }
+
+    /// Record a patch computed with [`Patch::diff`] as one undo step of a document.
+    pub fn push(&mut self, id: &str, patch: Patch) {
+        if patch.is_empty() {
+            return;
+        }
+        let step = self.step(patch);
+        let history = self.documents.entry(id.to_string()).or_default();
+        history.clear_redo();
+        history.push_undo(step, self.max_bytes);
+        self.evict(id);
+    }
+
+    /// Revert the last recorded step, returns false if there is nothing to undo.
+    pub fn undo(&mut self, document: &mut Document) -> bool {
+        let Some(history) = self.documents.get_mut(&document.id) else {
+            return false;
+        };
+        let Some(step) = history.undo.pop_back() else {
+            return false;
+        };
+        history.bytes -= step.size;
+        let step = Step::new(step.patch.apply(document), step.sequence);
+        history.push_redo(step);
+        self.evict(&document.id);
+        true
+    }
+
+    /// Re-apply the last undone step, returns false if there is nothing to redo.
+    pub fn redo(&mut self, document: &mut Document) -> bool {
+        let Some(history) = self.documents.get_mut(&document.id) else {
+            return false;
+        };
+        let Some(step) = history.redo.pop() else {
+            return false;
+        };
+        history.bytes -= step.size;
+        let step = Step::new(step.patch.apply(document), step.sequence);
+        history.push_undo(step, self.max_bytes);
+        self.evict(&document.id);
+        true
+    }
+
+    fn step(&mut self, patch: Patch) -> Step {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        Step::new(patch, sequence)
+    }
+
+    /// Drop the oldest undo steps of any document until all stacks fit the total
+    /// budget. The latest step of the document `changed` is kept.
+    fn evict(&mut self, changed: &str) {
+        while self
+            .documents
+            .values()
+            .map(|history| history.bytes)
+            .sum::<usize>()
+            > self.max_total_bytes
+        {
+            let oldest = self
+                .documents
+                .iter()
+                .filter(|(id, history)| *id != changed || history.undo.len() > 1)
+                .filter_map(|(id, history)| Some((history.undo.front()?.sequence, id)))
+                .min()
+                .map(|(_, id)| id.clone());
+            let Some(id) = oldest else {
+                break;
+            };
+            if let Some(history) = self.documents.get_mut(&id) {
+                history.pop_oldest();
+            }
+        }
+    }
+
+    /// Drop the history of a document removed from the project.
+    pub fn forget(&mut self, id: &str) {
+        self.documents.remove(id);
+    }
+
+    pub fn clear(&mut self) {
+        self.documents.clear();
+    }
+}
+
+fn image_size(image: &DynamicImage) -> usize {
+    image.as_bytes().len()
+}
+
+/// Bounding box `(x, y, width, height)` of the pixels that differ between two images
+/// of the same size.
+fn changed_bounds(before: &RgbaImage, after: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    let width = before.width();
+    let (mut x0, mut y0, mut x1, mut y1) = (u32::MAX, u32::MAX, 0, 0);
+    for (i, (old, new)) in before.pixels().zip(after.pixels()).enumerate() {
+        if old != new {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            x0 = x0.min(x);
+            y0 = y0.min(y);
+            x1 = x1.max(x + 1);
+            y1 = y1.max(y + 1);
+        }
+    }
+    (x0 != u32::MAX).then(|| (x0, y0, x1 - x0, y1 - y0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{DocumentRef, State};
+
+    fn document(id: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            text_blocks: vec![TextBlock {
+                text: Some("こんにちは".to_string()),
+                ..Default::default()
+            }],
+            inpainted: Some(DynamicImage::new_rgba8(8, 8).into()),
+            ..Default::default()
+        }
+    }
+
+    fn paint(document: &Document, x: u32, y: u32) -> Document {
+        let mut painted = document.clone();
+        let layer = painted
+            .inpainted
+            .as_mut()
+            .expect("inpainted")
+            .get_mut()
+            .expect("decoded");
+        layer
+            .as_mut_rgba8()
+            .expect("rgba")
+            .put_pixel(x, y, image::Rgba([255, 0, 0, 255]));
+        painted
+    }
+
+    #[test]
+    fn undo_redo_round_trip() {
+        let mut history = History::default();
+        let original = document("page");
+        let painted = paint(&original, 2, 3);
+        let mut translated = painted.clone();
+        translated.text_blocks[0].translation = Some("Hello".to_string());
+        history.record(&original, &painted);
+        history.record(&painted, &translated);
+
+        // a brush stroke keeps the changed pixel only, not the text blocks
+        let stroke = Patch::diff(&original, &painted);
+        assert!(stroke.text_blocks.is_none());
+        assert_eq!(stroke.size(), 4);
+
+        let mut current = translated.clone();
+        assert!(history.undo(&mut current));
+        assert_eq!(current.text_blocks, painted.text_blocks);
+        assert!(history.undo(&mut current));
+        assert_eq!(current.inpainted, original.inpainted);
+        assert!(!history.undo(&mut current));
+
+        assert!(history.redo(&mut current));
+        assert_eq!(current.inpainted, painted.inpainted);
+        assert!(history.redo(&mut current));
+        assert_eq!(current.text_blocks, translated.text_blocks);
+        assert!(!history.redo(&mut current));
+
+        // a new change drops what could be redone
+        assert!(history.undo(&mut current));
+        let repainted = paint(&current, 0, 0);
+        history.record(&current, &repainted);
+        assert!(!history.redo(&mut current));
+        assert_eq!(history.documents["page"].bytes, 4 + 4);
+    }
+
+    #[test]
+    fn evicts_oldest_steps_over_budget() {
+        let mut history = History {
+            max_bytes: 10,
+            ..Default::default()
+        };
+        let mut current = document("page");
+        for x in 0..4 {
+            let painted = paint(&current, x, x);
+            history.record(&current, &painted);
+            current = painted;
+        }
+        assert_eq!(history.documents["page"].undo.len(), 2);
+        assert_eq!(history.documents["page"].bytes, 8);
+
+        assert!(history.undo(&mut current));
+        assert!(history.undo(&mut current));
+        assert!(!history.undo(&mut current));
+        assert_eq!(
+            current.inpainted,
+            paint(&paint(&document("page"), 0, 0), 1, 1).inpainted
+        );
+    }
+
+    #[test]
+    fn evicts_oldest_steps_across_documents() {
+        let mut history = History {
+            max_total_bytes: 10,
+            ..Default::default()
+        };
+        let (a, b) = (document("a"), document("b"));
+        history.record(&a, &paint(&a, 0, 0));
+        history.record(&b, &paint(&b, 0, 0));
+        history.record(&paint(&a, 0, 0), &paint(&paint(&a, 0, 0), 1, 1));
+
+        // the first step of `a` is the oldest of both documents
+        assert_eq!(history.documents["a"].undo.len(), 1);
+        assert_eq!(history.documents["b"].undo.len(), 1);
+
+        history.record(&paint(&b, 0, 0), &paint(&paint(&b, 0, 0), 1, 1));
+        assert_eq!(history.documents["a"].undo.len(), 1);
+        assert_eq!(history.documents["b"].undo.len(), 1);
+        assert_eq!(history.documents["b"].undo[0].sequence, 3);
+    }
+
+    #[test]
+    fn diffs_again_after_a_concurrent_update() -> anyhow::Result<()> {
+        let mut state = State::default();
+        state.add_documents(vec![document("page")]);
+        let update = state.prepare_update(paint(&document("page"), 1, 1))?;
+        state.update_document(paint(&document("page"), 2, 2))?;
+        state.finish_update(update)?;
+
+        // undo goes back to the version stored in between
+        let undone = state.undo(&DocumentRef::Id("page".to_string()))?;
+        assert_eq!(undone.inpainted, paint(&document("page"), 2, 2).inpainted);
+        Ok(())
+    }
+
+    #[test]
+    fn forgets_removed_documents() -> anyhow::Result<()> {
+        let mut state = State::default();
+        state.add_documents(vec![document("page")]);
+        state.update_document(paint(&document("page"), 1, 1))?;
+        state.remove_document(&DocumentRef::Id("page".to_string()))?;
+        assert!(state.history.documents.is_empty());
+
+        state.add_documents(vec![document("page")]);
+        assert!(state.undo(&DocumentRef::Id("page".to_string())).is_err());
+        Ok(())
+    }
+}
@@ -1,25 +1,131 @@
-use std::ops::Deref;
+use std::{
+    io::Cursor,
+    ops::Deref,
+    sync::{Arc, OnceLock},
+};
 
-use image::{ColorType, DynamicImage, codecs::webp::WebPEncoder};
+use image::{ColorType, DynamicImage, ImageReader, codecs::webp::WebPEncoder};
 use serde::{Deserialize, Serialize, Serializer};
 
-#[derive(Debug, Default, Clone)]
-pub struct SerializableDynamicImage(pub DynamicImage);
+/// An image that may still be encoded. Layers read from a project are only
+/// decoded when first used, and keep their encoded bytes until they are changed
+/// so saving them again does not re-encode. Bytes that fail to decode are kept
+/// as they are, see [`Self::decode`].
+#[derive(Clone)]
+pub struct SerializableDynamicImage {
+    image: OnceLock<DynamicImage>,
+    encoded: Option<Encoded>,
+}
 
-impl Serialize for SerializableDynamicImage {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let rgba = self.0.to_rgba8();
+#[derive(Clone)]
+struct Encoded {
+    bytes: Arc<[u8]>,
+    width: u32,
+    height: u32,
+}
+
+impl SerializableDynamicImage {
+    /// Encode the image as lossless WebP, the format used for serialization.
+    pub fn to_webp(&self) -> image::ImageResult<Vec<u8>> {
+        if let Some(encoded) = &self.encoded {
+            return Ok(encoded.bytes.to_vec());
+        }
+
+        let rgba = self.to_rgba8();
         let (width, height) = rgba.dimensions();
         let raw = rgba.into_raw();
 
         let mut buf = Vec::new();
         let enc = WebPEncoder::new_lossless(&mut buf);
-        enc.encode(&raw, width, height, ColorType::Rgba8.into())
-            .map_err(serde::ser::Error::custom)?;
+        enc.encode(&raw, width, height, ColorType::Rgba8.into())?;
+        Ok(buf)
+    }
+
+    /// Decode an image previously produced by [`Self::to_webp`], or any other supported format.
+    pub fn from_encoded(bytes: &[u8]) -> image::ImageResult<Self> {
+        Ok(image::load_from_memory(bytes)?.into())
+    }
+
+    /// Wrap bytes produced by [`Self::to_webp`], only reading the header until the
+    /// pixels are used.
+    pub fn lazy(bytes: Vec<u8>) -> image::ImageResult<Self> {
+        let (width, height) = ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        Ok(Self {
+            image: OnceLock::new(),
+            encoded: Some(Encoded {
+                bytes: bytes.into(),
+                width,
+                height,
+            }),
+        })
+    }
+
+    /// Mutable access to the pixels, dropping the encoded bytes they came from.
+    /// The bytes stay when they fail to decode.
+    pub fn get_mut(&mut self) -> image::ImageResult<&mut DynamicImage> {
+        self.decode()?;
+        self.encoded = None;
+        Ok(self.image.get_mut().expect("image decoded above"))
+    }
+
+    /// The pixels, decoded on first use. A failed decode is not cached.
+    pub fn decode(&self) -> image::ImageResult<&DynamicImage> {
+        if let Some(image) = self.image.get() {
+            return Ok(image);
+        }
+        let encoded = self
+            .encoded
+            .as_ref()
+            .expect("an image without pixels is encoded");
+        let image = image::load_from_memory(&encoded.bytes)?;
+        Ok(self.image.get_or_init(|| image))
+    }
+}
+
+impl Default for SerializableDynamicImage {
+    fn default() -> Self {
+        DynamicImage::default().into()
+    }
+}
 
+impl std::fmt::Debug for SerializableDynamicImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.image.get(), &self.encoded) {
+            (Some(image), _) => f
+                .debug_tuple("SerializableDynamicImage")
+                .field(image)
+                .finish(),
+            (None, Some(encoded)) => f
+                .debug_struct("SerializableDynamicImage")
+                .field("width", &encoded.width)
+                .field("height", &encoded.height)
+                .field("encoded", &encoded.bytes.len())
+                .finish(),
+            (None, None) => f.write_str("SerializableDynamicImage"),
+        }
+    }
+}
+
+/// Images read from the same chunk compare equal without being decoded.
+impl PartialEq for SerializableDynamicImage {
+    fn eq(&self, other: &Self) -> bool {
+        if let (Some(a), Some(b)) = (&self.encoded, &other.encoded)
+            && Arc::ptr_eq(&a.bytes, &b.bytes)
+        {
+            return true;
+        }
+        **self == **other
+    }
+}
+
+impl Serialize for SerializableDynamicImage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let buf = self.to_webp().map_err(serde::ser::Error::custom)?;
         serde_bytes::serialize(&buf, serializer)
     }
 }
@@ -30,27 +136,38 @@ impl<'de> Deserialize<'de> for SerializableDynamicImage {
         D: serde::Deserializer<'de>,
     {
         let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
-        let img = image::load_from_memory(&bytes).map_err(serde::de::Error::custom)?;
-        Ok(SerializableDynamicImage(img))
+        Self::from_encoded(&bytes).map_err(serde::de::Error::custom)
     }
 }
 
+/// Reads of a layer that fails to decode see an empty image, use
+/// [`SerializableDynamicImage::decode`] where the error can be reported.
 impl Deref for SerializableDynamicImage {
     type Target = DynamicImage;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        static EMPTY: OnceLock<DynamicImage> = OnceLock::new();
+        self.decode().unwrap_or_else(|err| {
+            tracing::error!(?err, "Failed to decode image layer");
+            EMPTY.get_or_init(DynamicImage::default)
+        })
     }
 }
 
 impl From<DynamicImage> for SerializableDynamicImage {
     fn from(image: DynamicImage) -> Self {
-        SerializableDynamicImage(image)
+        Self {
+            image: OnceLock::from(image),
+            encoded: None,
+        }
     }
 }
 
-impl From<SerializableDynamicImage> for DynamicImage {
-    fn from(wrapper: SerializableDynamicImage) -> Self {
-        wrapper.0
+impl TryFrom<SerializableDynamicImage> for DynamicImage {
+    type Error = image::ImageError;
+
+    fn try_from(mut wrapper: SerializableDynamicImage) -> image::ImageResult<Self> {
+        wrapper.decode()?;
+        Ok(wrapper.image.take().expect("image decoded above"))
     }
 }
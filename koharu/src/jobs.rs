@@ -0,0 +1,580 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use koharu_ml::llm::GenerateOptions;
+use koharu_renderer::renderer::TextShaderEffect;
+use serde::{Deserialize, Serialize};
+use strum::Display;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::{
+    app::AppResources,
+    operations,
+    project::Project,
+    state::{AppState, DocumentRef},
+};
+
+/// Finished jobs kept around so clients can still query their outcome.
+const MAX_FINISHED_JOBS: usize = 100;
+const EVENT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "camelCase")]
+#[strum(serialize_all = "camelCase")]
+pub enum Stage {
+    Detect,
+    Ocr,
+    Inpaint,
+    LlmGenerate,
+    Render,
+}
+
+/// Stages run when a job does not specify any.
+pub const PIPELINE: &[Stage] = &[
+    Stage::Detect,
+    Stage::Ocr,
+    Stage::Inpaint,
+    Stage::LlmGenerate,
+    Stage::Render,
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRequest {
+    /// Ids of the documents to process, takes precedence over `indices`.
+    pub document_ids: Option<Vec<String>>,
+    /// Document indices to process, all documents when neither is given.
+    pub indices: Option<Vec<usize>>,
+    /// Stages to run on every document in order, the full pipeline when omitted.
+    pub stages: Option<Vec<Stage>>,
+    pub language: Option<String>,
+    /// Number of earlier pages given to the model as context.
+    pub context_pages: Option<usize>,
+    /// Sampling settings for the translation, the project's when omitted.
+    pub options: Option<GenerateOptions>,
+    pub shader_effect: Option<TextShaderEffect>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// Snapshot of a job, also sent as progress event whenever it changes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfo {
+    pub id: u64,
+    /// Project the job works on.
+    pub project: String,
+    pub status: JobStatus,
+    pub stage: Option<Stage>,
+    /// Id of the document currently being processed.
+    pub document_id: Option<String>,
+    pub completed_pages: usize,
+    pub total_pages: usize,
+    pub error: Option<String>,
+}
+
+type StageFuture = BoxFuture<'static, crate::result::Result<()>>;
+
+/// Runs one stage on a document of a job, stages that can stop early check the
+/// cancellation flag of the job.
+type StageFn = dyn Fn(Stage, String, Arc<AtomicBool>) -> StageFuture + Send + Sync;
+
+struct Job {
+    info: JobInfo,
+    /// Set once the job is cancelled, checked before every stage.
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Runs pipeline operations in the background one job at a time, reporting
+/// progress per stage and page.
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    /// Held by the running job, later jobs wait for it in submission order.
+    slot: tokio::sync::Mutex<()>,
+    events: broadcast::Sender<JobInfo>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(BTreeMap::new()),
+            slot: tokio::sync::Mutex::new(()),
+            events,
+        }
+    }
+
+    /// Subscribe to progress events of all jobs.
+    pub fn subscribe(&self) -> broadcast::Receiver<JobInfo> {
+        self.events.subscribe()
+    }
+
+    /// Queue a job on a project and return immediately.
+    pub async fn submit(
+        self: &Arc<Self>,
+        resources: AppResources,
+        project: &Project,
+        request: JobRequest,
+    ) -> Result<JobInfo> {
+        let state = project.state.clone();
+        // resolve to ids up front, so pages added or reordered meanwhile do not matter
+        let documents = {
+            let guard = state.read().await;
+            let refs: Vec<DocumentRef> = match (&request.document_ids, &request.indices) {
+                (Some(ids), _) => ids.iter().cloned().map(DocumentRef::Id).collect(),
+                (None, Some(indices)) => indices.iter().copied().map(DocumentRef::Index).collect(),
+                (None, None) => (0..guard.documents.len()).map(DocumentRef::Index).collect(),
+            };
+            refs.iter()
+                .map(|document| Ok(guard.document(document)?.id.clone()))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let stages = request
+            .stages
+            .clone()
+            .filter(|stages| !stages.is_empty())
+            .unwrap_or_else(|| PIPELINE.to_vec());
+
+        let stage_fn =
+            move |stage: Stage, document_id: String, cancelled: Arc<AtomicBool>| -> StageFuture {
+                let (resources, state, request) =
+                    (resources.clone(), state.clone(), request.clone());
+                Box::pin(async move {
+                    run_stage(
+                        &resources,
+                        &state,
+                        stage,
+                        &document_id,
+                        &request,
+                        &cancelled,
+                    )
+                    .await
+                })
+            };
+        Ok(self.spawn(project.id.clone(), documents, stages, Arc::new(stage_fn)))
+    }
+
+    fn spawn(
+        self: &Arc<Self>,
+        project: String,
+        documents: Vec<String>,
+        stages: Vec<Stage>,
+        stage_fn: Arc<StageFn>,
+    ) -> JobInfo {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = JobInfo {
+            id,
+            project,
+            status: JobStatus::Queued,
+            stage: None,
+            document_id: None,
+            completed_pages: 0,
+            total_pages: documents.len(),
+            error: None,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        // register the job before it can report progress, so updates and cancellation
+        // always find it
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(
+            id,
+            Job {
+                info: info.clone(),
+                cancelled: cancelled.clone(),
+            },
+        );
+        let runner = self.clone();
+        tokio::spawn(async move {
+            let result = runner
+                .run(id, &cancelled, &documents, &stages, &*stage_fn)
+                .await;
+            runner.update(id, |info| match result {
+                _ if cancelled.load(Ordering::Relaxed)
+                    && info.completed_pages < info.total_pages =>
+                {
+                    info.status = JobStatus::Cancelled
+                }
+                Ok(()) => info.status = JobStatus::Completed,
+                Err(err) => {
+                    info.status = JobStatus::Failed;
+                    info.error = Some(format!("{err:#}"));
+                }
+            });
+        });
+        self.emit(info.clone());
+        drop(jobs);
+
+        info
+    }
+
+    pub fn get(&self, id: u64) -> Option<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|job| job.info.clone())
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| job.info.clone())
+            .collect()
+    }
+
+    /// Cancel a job, finished jobs are left untouched. Queued jobs are cancelled
+    /// right away. A running job stops before its next stage, a translation
+    /// right away, and only reports `cancelled` once it did, so the next job
+    /// never starts while the work of this one still runs.
+    pub fn cancel(&self, id: u64) -> Result<JobInfo> {
+        let info = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs
+                .get_mut(&id)
+                .ok_or_else(|| anyhow::anyhow!("Job {id} not found"))?;
+            if job.info.status.is_finished() {
+                return Ok(job.info.clone());
+            }
+            // translations check the flag between pieces of text, the ML stages
+            // run on blocking threads until they are done
+            job.cancelled.store(true, Ordering::Relaxed);
+            if job.info.status == JobStatus::Queued {
+                job.info.status = JobStatus::Cancelled;
+            }
+            job.info.clone()
+        };
+        self.emit(info.clone());
+        Ok(info)
+    }
+
+    /// Cancel the queued and running jobs of a project, used when it is closed.
+    pub fn cancel_project(&self, project: &str) {
+        let ids = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.info.project == project && !job.info.status.is_finished())
+            .map(|job| job.info.id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            if let Err(err) = self.cancel(id) {
+                warn!(?err, "Failed to cancel job {id}");
+            }
+        }
+    }
+
+    async fn run(
+        &self,
+        id: u64,
+        cancelled: &Arc<AtomicBool>,
+        documents: &[String],
+        stages: &[Stage],
+        stage_fn: &StageFn,
+    ) -> Result<()> {
+        let _slot = self.slot.lock().await;
+        self.update(id, |info| info.status = JobStatus::Running);
+
+        for (page, document_id) in documents.iter().enumerate() {
+            for &stage in stages {
+                // operations write back to the state only once they finished, so
+                // stopping between stages never leaves a half-updated document behind
+                if cancelled.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                self.update(id, |info| {
+                    info.stage = Some(stage);
+                    info.document_id = Some(document_id.clone());
+                });
+                stage_fn(stage, document_id.clone(), cancelled.clone())
+                    .await
+                    .map_err(|err| anyhow::anyhow!("{stage} failed on page {}: {err}", page + 1))?;
+            }
+            self.update(id, |info| info.completed_pages = page + 1);
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut JobInfo)) {
+        let info = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(job) = jobs.get_mut(&id) else {
+                return;
+            };
+            // a job cancelled while queued still takes its turn, keep its status
+            if job.info.status == JobStatus::Cancelled {
+                return;
+            }
+            f(&mut job.info);
+            let info = job.info.clone();
+            prune(&mut jobs);
+            info
+        };
+        self.emit(info);
+    }
+
+    fn emit(&self, info: JobInfo) {
+        // no subscribers is fine, the job state can still be queried
+        let _ = self.events.send(info);
+    }
+}
+
+async fn run_stage(
+    resources: &AppResources,
+    state: &AppState,
+    stage: Stage,
+    document_id: &str,
+    request: &JobRequest,
+    cancelled: &AtomicBool,
+) -> crate::result::Result<()> {
+    let document = DocumentRef::Id(document_id.to_string());
+    match stage {
+        Stage::Detect => {
+            operations::detect(state, &resources.ml, document).await?;
+        }
+        Stage::Ocr => {
+            operations::ocr(state, &resources.ml, document).await?;
+        }
+        Stage::Inpaint => {
+            operations::inpaint(state, &resources.ml, document).await?;
+        }
+        Stage::LlmGenerate => {
+            operations::llm_ensure_ready(&resources.llm).await?;
+            operations::llm_generate_cancellable(
+                state,
+                &resources.llm,
+                document,
+                None,
+                request.language.clone(),
+                request.context_pages,
+                request.options.clone(),
+                cancelled,
+            )
+            .await?;
+        }
+        Stage::Render => {
+            operations::render(
+                state,
+                &resources.renderer,
+                document,
+                None,
+                request.shader_effect,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+fn prune(jobs: &mut BTreeMap<u64, Job>) {
+    let finished = jobs
+        .values()
+        .filter(|job| job.info.status.is_finished())
+        .count();
+    if finished <= MAX_FINISHED_JOBS {
+        return;
+    }
+    let stale = jobs
+        .iter()
+        .filter(|(_, job)| job.info.status.is_finished())
+        .map(|(id, _)| *id)
+        .take(finished - MAX_FINISHED_JOBS)
+        .collect::<Vec<_>>();
+    for id in stale {
+        jobs.remove(&id);
+    }
+}
+
+/// Forward events, of jobs or translation progress, to a sink until the channel
+/// closes.
+pub async fn forward_events<T: Clone>(
+    mut events: broadcast::Receiver<T>,
+    mut sink: impl FnMut(&T) -> Result<()>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if let Err(err) = sink(&event) {
+                    warn!(?err, "Failed to forward event");
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Dropped {skipped} events");
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<(Stage, String)>>>;
+
+    /// Queue a job running detection and OCR, each stage waits for a permit of
+    /// `gate` and is recorded in `log` once it is done. Like a translation, a
+    /// stage fails without being recorded when the job was cancelled meanwhile.
+    fn spawn(jobs: &Arc<Jobs>, documents: &[&str], log: &Log, gate: &Arc<Semaphore>) -> JobInfo {
+        let (log, gate) = (log.clone(), gate.clone());
+        let stage_fn =
+            move |stage: Stage, document_id: String, cancelled: Arc<AtomicBool>| -> StageFuture {
+                let (log, gate) = (log.clone(), gate.clone());
+                Box::pin(async move {
+                    gate.acquire().await.expect("gate is open").forget();
+                    if cancelled.load(Ordering::Relaxed) {
+                        return Err(anyhow::anyhow!("Generation was cancelled").into());
+                    }
+                    log.lock().unwrap().push((stage, document_id));
+                    Ok(())
+                })
+            };
+        jobs.spawn(
+            "project".to_string(),
+            documents
+                .iter()
+                .map(|document| document.to_string())
+                .collect(),
+            vec![Stage::Detect, Stage::Ocr],
+            Arc::new(stage_fn),
+        )
+    }
+
+    async fn finished(jobs: &Jobs, id: u64) -> Result<JobInfo> {
+        let wait = async {
+            loop {
+                let info = jobs.get(id).expect("job is kept");
+                if info.status.is_finished() {
+                    return info;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        Ok(tokio::time::timeout(Duration::from_secs(5), wait).await?)
+    }
+
+    fn entries(entries: &[(Stage, &str)]) -> Vec<(Stage, String)> {
+        entries
+            .iter()
+            .map(|(stage, document)| (*stage, document.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_jobs_in_submission_order() -> Result<()> {
+        let jobs = Arc::new(Jobs::new());
+        let (log, gate) = (Log::default(), Arc::new(Semaphore::new(0)));
+        let first = spawn(&jobs, &["a", "b"], &log, &gate);
+        let second = spawn(&jobs, &["c"], &log, &gate);
+        assert_eq!(first.status, JobStatus::Queued);
+
+        gate.add_permits(6);
+        let first = finished(&jobs, first.id).await?;
+        assert_eq!(first.status, JobStatus::Completed);
+        assert_eq!(first.completed_pages, 2);
+        assert_eq!(
+            finished(&jobs, second.id).await?.status,
+            JobStatus::Completed
+        );
+        assert_eq!(
+            *log.lock().unwrap(),
+            entries(&[
+                (Stage::Detect, "a"),
+                (Stage::Ocr, "a"),
+                (Stage::Detect, "b"),
+                (Stage::Ocr, "b"),
+                (Stage::Detect, "c"),
+                (Stage::Ocr, "c"),
+            ])
+        );
+
+        // finished jobs stay as they are
+        assert_eq!(jobs.cancel(first.id)?.status, JobStatus::Completed);
+        assert!(jobs.cancel(u64::MAX).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancels_queued_jobs_right_away() -> Result<()> {
+        let jobs = Arc::new(Jobs::new());
+        let (log, gate) = (Log::default(), Arc::new(Semaphore::new(0)));
+        let first = spawn(&jobs, &["a"], &log, &gate);
+        let second = spawn(&jobs, &["b"], &log, &gate);
+        tokio::task::yield_now().await;
+
+        assert_eq!(jobs.cancel(second.id)?.status, JobStatus::Cancelled);
+        gate.add_permits(4);
+        assert_eq!(
+            finished(&jobs, first.id).await?.status,
+            JobStatus::Completed
+        );
+        // the cancelled job takes its turn without running any stage
+        drop(jobs.slot.lock().await);
+        assert_eq!(jobs.get(second.id).unwrap().status, JobStatus::Cancelled);
+        assert_eq!(
+            *log.lock().unwrap(),
+            entries(&[(Stage::Detect, "a"), (Stage::Ocr, "a")])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_running_jobs_from_within_the_stage() -> Result<()> {
+        let jobs = Arc::new(Jobs::new());
+        let (log, gate) = (Log::default(), Arc::new(Semaphore::new(0)));
+        let job = spawn(&jobs, &["a", "b"], &log, &gate);
+        while jobs.get(job.id).unwrap().stage.is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        // the stage still runs, so the job is not cancelled yet
+        assert_eq!(jobs.cancel(job.id)?.status, JobStatus::Running);
+        gate.add_permits(4);
+        let job = finished(&jobs, job.id).await?;
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.completed_pages, 0);
+        // the running stage saw the flag of its job and stopped early
+        assert!(log.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
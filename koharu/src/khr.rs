@@ -1,13 +1,36 @@
-use std::io::Cursor;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
 
-use anyhow::bail;
+use anyhow::{bail, ensure};
 use image::{DynamicImage, GenericImageView, ImageFormat, RgbaImage, imageops};
 use once_cell::sync::Lazy;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
 
-use crate::state::Document;
+use crate::{
+    image::SerializableDynamicImage,
+    state::{Document, ProjectMeta, TextBlock},
+};
+
+// Layout of a KHR v2 file:
+//
+//   [thumbnail jpeg][chunk]...[table of contents json][toc offset u64][toc length u64][magic]
+//
+// Every layer is stored as its own lossless WebP chunk and the text blocks of each
+// document as a JSON chunk, so a single document or layer can be read by seeking to
+// the span recorded in the table of contents.
+//
+// Legacy files are a postcard encoded `Vec<Document>`, optionally prefixed with the
+// thumbnail and followed by `[postcard offset u64][KHR_MAGIC]`.
 
 pub const KHR_MAGIC: &[u8; 4] = b"khr!";
+pub const KHR_V2_MAGIC: &[u8; 4] = b"khr2";
+pub const KHR_VERSION: u32 = 2;
 const KHR_FOOTER_LEN: usize = KHR_MAGIC.len() + std::mem::size_of::<u64>();
+const KHR_V2_FOOTER_LEN: usize = KHR_V2_MAGIC.len() + 2 * std::mem::size_of::<u64>();
 const THUMBNAIL_HEIGHT: u32 = 300;
 const THUMBNAIL_WIDTH: u32 = THUMBNAIL_HEIGHT * 4 / 3; // 4:3 aspect for contact sheet
 const ICON_BYTES: &[u8] = include_bytes!("../icons/Square142x142Logo.png");
@@ -18,28 +41,542 @@ static ICON_IMAGE: Lazy<RgbaImage> = Lazy::new(|| {
         .to_rgba8()
 });
 
-pub fn has_khr_magic(bytes: &[u8]) -> bool {
-    bytes.len() >= KHR_MAGIC.len() && &bytes[bytes.len() - KHR_MAGIC.len()..] == KHR_MAGIC
+/// Image layers of a [`Document`] that are stored as separate chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LayerKind {
+    Image,
+    Segment,
+    Inpainted,
+    Rendered,
+    BrushLayer,
 }
 
-pub fn serialize_khr(documents: &[Document]) -> anyhow::Result<Vec<u8>> {
-    let thumbnail = thumbnail_contact_sheet(documents);
-    let mut thumbnail_bytes = Vec::new();
-    thumbnail.write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Jpeg)?;
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Span {
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Layers {
+    image: Span,
+    segment: Option<Span>,
+    inpainted: Option<Span>,
+    rendered: Option<Span>,
+    brush_layer: Option<Span>,
+    /// Per text block rendered images, indexed like the text blocks.
+    #[serde(default)]
+    text_blocks: Vec<Option<Span>>,
+}
 
-    let khr_bytes = postcard::to_allocvec(documents)?;
-    let khr_offset = thumbnail_bytes.len() as u64;
+impl Layers {
+    fn get(&self, layer: LayerKind) -> Option<Span> {
+        match layer {
+            LayerKind::Image => Some(self.image),
+            LayerKind::Segment => self.segment,
+            LayerKind::Inpainted => self.inpainted,
+            LayerKind::Rendered => self.rendered,
+            LayerKind::BrushLayer => self.brush_layer,
+        }
+    }
+}
 
-    let mut output = thumbnail_bytes;
-    output.extend_from_slice(&khr_bytes);
-    output.extend_from_slice(&khr_offset.to_le_bytes());
-    output.extend_from_slice(KHR_MAGIC);
+/// Table of contents entry describing one document in a KHR file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KhrEntry {
+    pub id: String,
+    pub path: PathBuf,
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub text_block_count: usize,
+    text_blocks: Option<Span>,
+    layers: Option<Layers>,
+}
 
-    Ok(output)
+#[derive(Debug, Serialize, Deserialize)]
+struct Toc {
+    version: u32,
+    documents: Vec<KhrEntry>,
+    #[serde(default)]
+    meta: ProjectMeta,
+}
+
+pub fn has_khr_magic(bytes: &[u8]) -> bool {
+    if bytes.len() < KHR_MAGIC.len() {
+        return false;
+    }
+    let tail = &bytes[bytes.len() - KHR_MAGIC.len()..];
+    tail == KHR_MAGIC || tail == KHR_V2_MAGIC
+}
+
+pub fn serialize_khr(documents: &[Document], meta: &ProjectMeta) -> anyhow::Result<Vec<u8>> {
+    let mut writer = KhrWriter::new(Vec::new(), &thumbnail_contact_sheet(documents))?;
+    writer.set_meta(meta.clone());
+    for document in documents {
+        writer.write_document(document)?;
+    }
+    writer.finish()
 }
 
 pub fn deserialize_khr(bytes: &[u8]) -> anyhow::Result<Vec<Document>> {
-    if bytes.len() >= KHR_FOOTER_LEN && has_khr_magic(bytes) {
+    KhrReader::new(Cursor::new(bytes))?.read_all()
+}
+
+/// Write `documents` to a KHR file at `path` without buffering the whole file in memory.
+/// The file is written next to `path` first and then moved over it, so a failed
+/// save leaves the previous version intact.
+pub fn save_khr(
+    path: impl AsRef<Path>,
+    documents: &[Document],
+    meta: &ProjectMeta,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("khr.tmp");
+    let saved = write_khr_file(&tmp, documents, meta)
+        .and_then(|()| std::fs::rename(&tmp, path).map_err(Into::into));
+    if saved.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    saved
+}
+
+fn write_khr_file(path: &Path, documents: &[Document], meta: &ProjectMeta) -> anyhow::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut writer = KhrWriter::new(file, &thumbnail_contact_sheet(documents))?;
+    writer.set_meta(meta.clone());
+    for document in documents {
+        writer.write_document(document)?;
+    }
+    writer.finish()?.into_inner()?.sync_all()?;
+    Ok(())
+}
+
+/// Whether the file at `path` is a KHR project, judged by its footer alone.
+pub fn is_khr_file(path: impl AsRef<Path>) -> std::io::Result<bool> {
+    let mut file = File::open(path)?;
+    let mut tail = [0u8; KHR_MAGIC.len()];
+    if file.seek(SeekFrom::End(0))? < tail.len() as u64 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-(tail.len() as i64)))?;
+    file.read_exact(&mut tail)?;
+    Ok(has_khr_magic(&tail))
+}
+
+/// Open a KHR file for lazy reading.
+pub fn open_khr(path: impl AsRef<Path>) -> anyhow::Result<KhrReader<BufReader<File>>> {
+    KhrReader::new(BufReader::new(File::open(path)?))
+}
+
+/// Streaming writer for the KHR v2 container.
+pub struct KhrWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    entries: Vec<KhrEntry>,
+    meta: ProjectMeta,
+}
+
+impl<W: Write> KhrWriter<W> {
+    pub fn new(writer: W, thumbnail: &DynamicImage) -> anyhow::Result<Self> {
+        let mut thumbnail_bytes = Vec::new();
+        thumbnail.write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Jpeg)?;
+
+        let mut khr = Self {
+            writer,
+            offset: 0,
+            entries: Vec::new(),
+            meta: ProjectMeta::default(),
+        };
+        khr.write_chunk(&thumbnail_bytes)?;
+        Ok(khr)
+    }
+
+    /// Project metadata to store in the table of contents.
+    pub fn set_meta(&mut self, meta: ProjectMeta) {
+        self.meta = meta;
+    }
+
+    pub fn write_document(&mut self, document: &Document) -> anyhow::Result<()> {
+        let encoded = EncodedDocument::encode(document)?;
+
+        let image = self.write_chunk(&encoded.image)?;
+        let segment = self.write_optional_chunk(encoded.segment.as_deref())?;
+        let inpainted = self.write_optional_chunk(encoded.inpainted.as_deref())?;
+        let rendered = self.write_optional_chunk(encoded.rendered.as_deref())?;
+        let brush_layer = self.write_optional_chunk(encoded.brush_layer.as_deref())?;
+        let block_layers = encoded
+            .block_layers
+            .iter()
+            .map(|bytes| self.write_optional_chunk(bytes.as_deref()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let text_blocks = self.write_chunk(&encoded.text_blocks)?;
+
+        self.entries.push(KhrEntry {
+            id: document.id.clone(),
+            path: document.path.clone(),
+            name: document.name.clone(),
+            width: document.width,
+            height: document.height,
+            text_block_count: document.text_blocks.len(),
+            text_blocks: Some(text_blocks),
+            layers: Some(Layers {
+                image,
+                segment,
+                inpainted,
+                rendered,
+                brush_layer,
+                text_blocks: block_layers,
+            }),
+        });
+        Ok(())
+    }
+
+    /// Write the table of contents and footer, returning the inner writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let toc = serde_json::to_vec(&Toc {
+            version: KHR_VERSION,
+            documents: std::mem::take(&mut self.entries),
+            meta: std::mem::take(&mut self.meta),
+        })?;
+        let span = self.write_chunk(&toc)?;
+
+        self.writer.write_all(&span.offset.to_le_bytes())?;
+        self.writer.write_all(&span.length.to_le_bytes())?;
+        self.writer.write_all(KHR_V2_MAGIC)?;
+        Ok(self.writer)
+    }
+
+    fn write_chunk(&mut self, bytes: &[u8]) -> anyhow::Result<Span> {
+        self.writer.write_all(bytes)?;
+        let span = Span {
+            offset: self.offset,
+            length: bytes.len() as u64,
+        };
+        self.offset += span.length;
+        Ok(span)
+    }
+
+    fn write_optional_chunk(&mut self, bytes: Option<&[u8]>) -> anyhow::Result<Option<Span>> {
+        bytes.map(|bytes| self.write_chunk(bytes)).transpose()
+    }
+}
+
+/// Encoded chunks of a single document.
+struct EncodedDocument {
+    image: Vec<u8>,
+    segment: Option<Vec<u8>>,
+    inpainted: Option<Vec<u8>>,
+    rendered: Option<Vec<u8>>,
+    brush_layer: Option<Vec<u8>>,
+    block_layers: Vec<Option<Vec<u8>>>,
+    text_blocks: Vec<u8>,
+}
+
+impl EncodedDocument {
+    fn encode(document: &Document) -> anyhow::Result<Self> {
+        let encode = |image: &Option<SerializableDynamicImage>| {
+            image.as_ref().map(|image| image.to_webp()).transpose()
+        };
+
+        let text_blocks = document
+            .text_blocks
+            .iter()
+            .map(|block| TextBlock {
+                rendered: None,
+                ..block.clone()
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Self {
+            image: document.image.to_webp()?,
+            segment: encode(&document.segment)?,
+            inpainted: encode(&document.inpainted)?,
+            rendered: encode(&document.rendered)?,
+            brush_layer: encode(&document.brush_layer)?,
+            block_layers: document
+                .text_blocks
+                .iter()
+                .map(|block| encode(&block.rendered))
+                .collect::<Result<_, _>>()?,
+            text_blocks: serde_json::to_vec(&text_blocks)?,
+        })
+    }
+}
+
+/// Raw chunks of a single document read from a v2 file. Only the text blocks are
+/// parsed, the layers stay encoded until they are used.
+struct RawDocument {
+    entry: KhrEntry,
+    text_blocks: Vec<u8>,
+    image: Vec<u8>,
+    segment: Option<Vec<u8>>,
+    inpainted: Option<Vec<u8>>,
+    rendered: Option<Vec<u8>>,
+    brush_layer: Option<Vec<u8>>,
+    block_layers: Vec<Option<Vec<u8>>>,
+}
+
+impl RawDocument {
+    fn decode(self) -> anyhow::Result<Document> {
+        let decode = |bytes: Option<Vec<u8>>| bytes.map(SerializableDynamicImage::lazy).transpose();
+
+        Ok(Document {
+            id: self.entry.id,
+            path: self.entry.path,
+            name: self.entry.name,
+            image: SerializableDynamicImage::lazy(self.image)?,
+            width: self.entry.width,
+            height: self.entry.height,
+            text_blocks: decode_text_blocks(&self.text_blocks, self.block_layers)?,
+            segment: decode(self.segment)?,
+            inpainted: decode(self.inpainted)?,
+            rendered: decode(self.rendered)?,
+            brush_layer: decode(self.brush_layer)?,
+            ..Default::default()
+        })
+    }
+}
+
+enum Contents {
+    V2,
+    Legacy(Vec<Document>),
+}
+
+/// Reader for KHR files giving random access to documents and their layers.
+///
+/// Legacy files have no table of contents, so they are decoded eagerly on open.
+pub struct KhrReader<R: Read + Seek> {
+    reader: R,
+    /// Length of the stream, chunks recorded in the table of contents must fit in it.
+    len: u64,
+    contents: Contents,
+    entries: Vec<KhrEntry>,
+    meta: ProjectMeta,
+}
+
+impl<R: Read + Seek> KhrReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+
+        if len >= KHR_V2_FOOTER_LEN as u64 {
+            let mut footer = [0u8; KHR_V2_FOOTER_LEN];
+            reader.seek(SeekFrom::Start(len - KHR_V2_FOOTER_LEN as u64))?;
+            reader.read_exact(&mut footer)?;
+
+            if &footer[16..] == KHR_V2_MAGIC {
+                let toc_span = Span {
+                    offset: u64::from_le_bytes(footer[..8].try_into()?),
+                    length: u64::from_le_bytes(footer[8..16].try_into()?),
+                };
+                ensure!(
+                    toc_span.offset.saturating_add(toc_span.length)
+                        <= len - KHR_V2_FOOTER_LEN as u64,
+                    "Invalid KHR table of contents offset"
+                );
+
+                let toc: Toc = serde_json::from_slice(&read_span(&mut reader, len, toc_span)?)?;
+                if toc.version > KHR_VERSION {
+                    bail!(
+                        "KHR version {} is newer than supported version {KHR_VERSION}",
+                        toc.version
+                    );
+                }
+
+                return Ok(Self {
+                    reader,
+                    len,
+                    contents: Contents::V2,
+                    entries: toc.documents,
+                    meta: toc.meta,
+                });
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(len as usize);
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut bytes)?;
+        let documents = deserialize_legacy(&bytes)?;
+
+        Ok(Self {
+            reader,
+            len,
+            entries: documents.iter().map(legacy_entry).collect(),
+            contents: Contents::Legacy(documents),
+            meta: ProjectMeta::default(),
+        })
+    }
+
+    /// Documents in the file, without decoding any layer.
+    pub fn entries(&self) -> &[KhrEntry] {
+        &self.entries
+    }
+
+    /// Project metadata, always empty for legacy files.
+    pub fn meta(&self) -> &ProjectMeta {
+        &self.meta
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Decode a single document including all of its layers.
+    pub fn read_document(&mut self, index: usize) -> anyhow::Result<Document> {
+        match &self.contents {
+            Contents::Legacy(documents) => documents
+                .get(index)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Document not found")),
+            Contents::V2 => self.read_raw(index)?.decode(),
+        }
+    }
+
+    /// Decode the text blocks of a document, including their rendered images.
+    pub fn read_text_blocks(&mut self, index: usize) -> anyhow::Result<Vec<TextBlock>> {
+        if let Contents::Legacy(_) = self.contents {
+            return Ok(self.read_document(index)?.text_blocks);
+        }
+
+        let (text_blocks, layers) = self.stored_spans(index)?;
+        let bytes = read_span(&mut self.reader, self.len, text_blocks)?;
+        let block_layers = layers
+            .text_blocks
+            .iter()
+            .map(|span| {
+                span.map(|span| read_span(&mut self.reader, self.len, span))
+                    .transpose()
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        decode_text_blocks(&bytes, block_layers)
+    }
+
+    /// Decode a single layer of a document, `None` if the document has no such layer.
+    pub fn read_layer(
+        &mut self,
+        index: usize,
+        layer: LayerKind,
+    ) -> anyhow::Result<Option<SerializableDynamicImage>> {
+        let span = match &self.contents {
+            Contents::Legacy(documents) => {
+                let document = documents
+                    .get(index)
+                    .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+                return Ok(match layer {
+                    LayerKind::Image => Some(document.image.clone()),
+                    LayerKind::Segment => document.segment.clone(),
+                    LayerKind::Inpainted => document.inpainted.clone(),
+                    LayerKind::Rendered => document.rendered.clone(),
+                    LayerKind::BrushLayer => document.brush_layer.clone(),
+                });
+            }
+            Contents::V2 => self.stored_spans(index)?.1.get(layer),
+        };
+
+        let Some(span) = span else {
+            return Ok(None);
+        };
+        let bytes = read_span(&mut self.reader, self.len, span)?;
+        Ok(Some(SerializableDynamicImage::from_encoded(&bytes)?))
+    }
+
+    /// Read every document, their layers are decoded when first used.
+    pub fn read_all(mut self) -> anyhow::Result<Vec<Document>> {
+        if let Contents::Legacy(documents) = self.contents {
+            return Ok(documents);
+        }
+
+        let raw = (0..self.len())
+            .map(|index| self.read_raw(index))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        raw.into_par_iter().map(RawDocument::decode).collect()
+    }
+
+    fn stored_spans(&self, index: usize) -> anyhow::Result<(Span, Layers)> {
+        let entry = self
+            .entries
+            .get(index)
+            .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+        match (entry.text_blocks, entry.layers.clone()) {
+            (Some(text_blocks), Some(layers)) => Ok((text_blocks, layers)),
+            _ => bail!("Document {} has no stored contents", entry.name),
+        }
+    }
+
+    fn read_raw(&mut self, index: usize) -> anyhow::Result<RawDocument> {
+        let (text_blocks, layers) = self.stored_spans(index)?;
+        let entry = self.entries[index].clone();
+
+        let (reader, len) = (&mut self.reader, self.len);
+        let mut read_optional =
+            |span: Option<Span>| span.map(|span| read_span(reader, len, span)).transpose();
+
+        Ok(RawDocument {
+            image: read_optional(Some(layers.image))?.unwrap_or_default(),
+            segment: read_optional(layers.segment)?,
+            inpainted: read_optional(layers.inpainted)?,
+            rendered: read_optional(layers.rendered)?,
+            brush_layer: read_optional(layers.brush_layer)?,
+            block_layers: layers
+                .text_blocks
+                .iter()
+                .map(|span| read_optional(*span))
+                .collect::<anyhow::Result<_>>()?,
+            text_blocks: read_optional(Some(text_blocks))?.unwrap_or_default(),
+            entry,
+        })
+    }
+}
+
+fn decode_text_blocks(
+    bytes: &[u8],
+    block_layers: Vec<Option<Vec<u8>>>,
+) -> anyhow::Result<Vec<TextBlock>> {
+    let mut text_blocks: Vec<TextBlock> = serde_json::from_slice(bytes)?;
+    for (block, rendered) in text_blocks.iter_mut().zip(block_layers) {
+        block.rendered = rendered.map(SerializableDynamicImage::lazy).transpose()?;
+    }
+    Ok(text_blocks)
+}
+
+/// Read a chunk of a stream of `len` bytes, rejecting spans past its end before
+/// allocating for them.
+fn read_span<R: Read + Seek>(reader: &mut R, len: u64, span: Span) -> anyhow::Result<Vec<u8>> {
+    ensure!(
+        span.offset
+            .checked_add(span.length)
+            .is_some_and(|end| end <= len),
+        "KHR chunk at offset {} runs past the end of the file",
+        span.offset
+    );
+    let mut buf = vec![0u8; span.length as usize];
+    reader.seek(SeekFrom::Start(span.offset))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn legacy_entry(document: &Document) -> KhrEntry {
+    KhrEntry {
+        id: document.id.clone(),
+        path: document.path.clone(),
+        name: document.name.clone(),
+        width: document.width,
+        height: document.height,
+        text_block_count: document.text_blocks.len(),
+        text_blocks: None,
+        layers: None,
+    }
+}
+
+fn deserialize_legacy(bytes: &[u8]) -> anyhow::Result<Vec<Document>> {
+    if bytes.len() >= KHR_FOOTER_LEN && bytes.ends_with(KHR_MAGIC) {
         let offset_start = bytes.len() - KHR_FOOTER_LEN;
         let offset_bytes: [u8; 8] = bytes[offset_start..offset_start + 8]
             .try_into()
@@ -142,3 +679,177 @@ fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
 
     DynamicImage::ImageRgba8(canvas)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(name: &str, color: u8) -> Document {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            8,
+            6,
+            image::Rgba([color, color, color, 255]),
+        ));
+        Document {
+            id: name.to_string(),
+            path: PathBuf::from(format!("{name}.png")),
+            name: name.to_string(),
+            image: image.clone().into(),
+            width: 8,
+            height: 6,
+            text_blocks: vec![TextBlock {
+                x: 1.0,
+                y: 2.0,
+                width: 3.0,
+                height: 4.0,
+                text: Some("こんにちは".to_string()),
+                translation: Some("Hello".to_string()),
+                rendered: Some(image.clone().into()),
+                ..Default::default()
+            }],
+            inpainted: Some(image.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn v2_roundtrip() -> anyhow::Result<()> {
+        let documents = vec![document("page1", 10), document("page2", 200)];
+        let meta = ProjectMeta {
+            comic_info: Some("<ComicInfo><Series>Koharu</Series></ComicInfo>".to_string()),
+            glossary: vec![koharu_ml::llm::GlossaryEntry {
+                source: "小春".to_string(),
+                target: "Koharu".to_string(),
+                note: None,
+            }],
+            generate_options: Some(koharu_ml::llm::GenerateOptions {
+                temperature: 0.0,
+                ..Default::default()
+            }),
+        };
+        let bytes = serialize_khr(&documents, &meta)?;
+        assert!(has_khr_magic(&bytes));
+        assert_eq!(KhrReader::new(Cursor::new(&bytes))?.meta(), &meta);
+
+        let decoded = deserialize_khr(&bytes)?;
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1].name, "page2");
+        assert_eq!(decoded[1].image.to_rgba8(), documents[1].image.to_rgba8());
+        assert_eq!(
+            decoded[0].text_blocks[0].translation.as_deref(),
+            Some("Hello")
+        );
+        assert!(decoded[0].text_blocks[0].rendered.is_some());
+        assert!(decoded[0].segment.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn v2_random_access() -> anyhow::Result<()> {
+        let documents = vec![document("page1", 10), document("page2", 200)];
+        let bytes = serialize_khr(&documents, &ProjectMeta::default())?;
+
+        let mut reader = KhrReader::new(Cursor::new(bytes))?;
+        assert_eq!(reader.entries()[1].name, "page2");
+        assert_eq!(reader.entries()[1].text_block_count, 1);
+
+        let inpainted = reader
+            .read_layer(1, LayerKind::Inpainted)?
+            .expect("inpainted");
+        assert_eq!(inpainted.to_rgba8().get_pixel(0, 0).0, [200, 200, 200, 255]);
+        assert!(reader.read_layer(1, LayerKind::BrushLayer)?.is_none());
+        assert_eq!(reader.read_document(0)?.name, "page1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn v2_reads_layers_lazily() -> anyhow::Result<()> {
+        let documents = vec![document("page1", 10)];
+        let path = std::env::temp_dir().join(format!("koharu-lazy-{}.khr", std::process::id()));
+        save_khr(&path, &documents, &ProjectMeta::default())?;
+        assert!(is_khr_file(&path)?);
+        assert!(!path.with_extension("khr.tmp").exists());
+
+        let decoded = open_khr(&path).and_then(KhrReader::read_all);
+        std::fs::remove_file(&path)?;
+        let mut decoded = decoded?;
+        // the stored chunk is handed back as is, without decoding and encoding again
+        assert_eq!(decoded[0].image.to_webp()?, documents[0].image.to_webp()?);
+        assert_eq!(decoded[0].image.to_rgba8(), documents[0].image.to_rgba8());
+
+        let inpainted = decoded[0].inpainted.as_mut().expect("inpainted");
+        inpainted
+            .get_mut()?
+            .as_mut_rgba8()
+            .expect("rgba")
+            .put_pixel(0, 0, image::Rgba([1, 2, 3, 255]));
+        let changed = SerializableDynamicImage::from_encoded(&inpainted.to_webp()?)?;
+        assert_eq!(changed.to_rgba8().get_pixel(0, 0).0, [1, 2, 3, 255]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_layers_that_fail_to_decode() -> anyhow::Result<()> {
+        let bytes = document("page1", 10).image.to_webp()?;
+        let truncated = bytes[..bytes.len() - 8].to_vec();
+        let mut layer = SerializableDynamicImage::lazy(truncated.clone())?;
+
+        assert!(layer.decode().is_err());
+        assert!(layer.get_mut().is_err());
+        // saved again as read, not as a blank image
+        assert_eq!(layer.to_webp()?, truncated);
+
+        Ok(())
+    }
+
+    #[test]
+    fn v2_rejects_spans_past_the_end() -> anyhow::Result<()> {
+        let bytes = serialize_khr(&[document("page1", 10)], &ProjectMeta::default())?;
+        let footer = bytes.len() - KHR_V2_FOOTER_LEN;
+        let toc_offset = u64::from_le_bytes(bytes[footer..footer + 8].try_into()?) as usize;
+        let mut toc: serde_json::Value = serde_json::from_slice(&bytes[toc_offset..footer])?;
+        toc["documents"][0]["layers"]["image"]["length"] = (1u64 << 40).into();
+        let toc = serde_json::to_vec(&toc)?;
+
+        let mut corrupt = bytes[..toc_offset].to_vec();
+        corrupt.extend_from_slice(&toc);
+        corrupt.extend_from_slice(&(toc_offset as u64).to_le_bytes());
+        corrupt.extend_from_slice(&(toc.len() as u64).to_le_bytes());
+        corrupt.extend_from_slice(KHR_V2_MAGIC);
+
+        let mut reader = KhrReader::new(Cursor::new(corrupt))?;
+        assert!(reader.read_document(0).is_err());
+        assert!(reader.read_layer(0, LayerKind::Image).is_err());
+        assert!(reader.read_layer(0, LayerKind::Inpainted)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_legacy_format() -> anyhow::Result<()> {
+        let documents = vec![document("page1", 10)];
+        let postcard_bytes = postcard::to_allocvec(&documents)?;
+
+        // with thumbnail and footer
+        let mut bytes = vec![0xFF, 0xD8, 0xFF];
+        let offset = bytes.len() as u64;
+        bytes.extend_from_slice(&postcard_bytes);
+        bytes.extend_from_slice(&offset.to_le_bytes());
+        bytes.extend_from_slice(KHR_MAGIC);
+        assert!(has_khr_magic(&bytes));
+        let decoded = deserialize_khr(&bytes)?;
+        assert_eq!(decoded[0].name, "page1");
+
+        // bare postcard without footer
+        let decoded = deserialize_khr(&postcard_bytes)?;
+        assert_eq!(
+            decoded[0].text_blocks[0].text.as_deref(),
+            Some("こんにちは")
+        );
+
+        Ok(())
+    }
+}
@@ -1,14 +1,29 @@
 pub mod api;
 pub mod api_crs;
 pub mod app;
+pub mod archive;
+pub mod autosave;
+pub mod batch;
+pub mod book;
 pub mod command;
+pub mod glossary;
+pub mod history;
 pub mod image;
+pub mod jobs;
 pub mod khr;
 pub mod llm;
 pub mod ml;
+#[cfg(test)]
+mod mock;
+pub mod mt;
+pub mod openai;
 pub mod operations;
+pub mod project;
+pub mod psd;
 pub mod renderer;
 pub mod result;
+pub mod script;
+pub mod sort;
 pub mod state;
 pub mod update;
 pub mod version;
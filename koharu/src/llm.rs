@@ -1,10 +1,24 @@
-use koharu_ml::llm::{GenerateOptions, Llm, ModelId};
-use serde::Serialize;
-use std::sync::Arc;
+use futures::future::BoxFuture;
+use koharu_ml::llm::{Llm, LocalModel, ModelId, TranslationContext, supported_locales};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    ops::ControlFlow,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 use strum::Display;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock, broadcast};
 
-use crate::state::{Document, TextBlock};
+use crate::{
+    mt::{MtConfig, MtService, MtTranslator},
+    openai::{OpenAiConfig, OpenAiTranslator},
+    state::{Document, TextBlock},
+};
 
 pub use koharu_ml::llm::prefetch;
 
@@ -13,6 +27,12 @@ pub use koharu_ml::llm::prefetch;
 pub struct ModelInfo {
     pub id: String,
     pub languages: Vec<String>,
+    /// Where the model runs, `local` for bundled GGUF models and `gguf` for
+    /// files registered by the user.
+    pub provider: String,
+    /// Name to show instead of the id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 impl ModelInfo {
@@ -20,26 +40,375 @@ impl ModelInfo {
         Self {
             id: id.to_string(),
             languages: id.languages(),
+            provider: "local".to_string(),
+            name: None,
         }
     }
 }
 
+/// Receives pieces of a translation as they are generated. Breaking stops the
+/// generation.
+pub type OnText<'a> = dyn FnMut(&str) -> ControlFlow<()> + Send + 'a;
+
+/// Receives the index of a source text and its translation so far, again after
+/// every change. Breaking stops the generation.
+pub type OnProgress<'a> = dyn FnMut(usize, &str) -> ControlFlow<()> + Send + 'a;
+
+/// Turns source text into a translation, implemented by the local LLM and by
+/// remote backends.
+pub trait Translator: Send + Sync {
+    /// Translate `source`, `context` holds earlier lines with their accepted
+    /// translations and the project glossary.
+    fn translate<'a>(
+        &'a mut self,
+        source: &'a str,
+        context: &'a TranslationContext,
+    ) -> BoxFuture<'a, anyhow::Result<String>>;
+
+    /// Like [`Translator::translate`], passing the translation to `on_text` while
+    /// it is generated. The default passes it in one piece once it is done.
+    fn translate_streaming<'a>(
+        &'a mut self,
+        source: &'a str,
+        context: &'a TranslationContext,
+        on_text: &'a mut OnText<'_>,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(async move {
+            let translation = self.translate(source, context).await?;
+            let _ = on_text(&translation);
+            Ok(translation)
+        })
+    }
+
+    /// Whether the translator is told to keep the `[n]` numbers of the lines
+    /// [`Translator::translate_batch`] sends, texts are translated one at a time
+    /// otherwise.
+    fn keeps_line_numbers(&self) -> bool {
+        true
+    }
+
+    /// Translate each text of a batch. The default sends the non-empty texts as
+    /// one prompt of numbered lines and maps the response back by number. Texts
+    /// whose line is missing or ambiguous in the response are translated again
+    /// one at a time, starting their progress over. Once `on_progress` breaks,
+    /// nothing more is generated and the translations so far are returned.
+    fn translate_batch<'a>(
+        &'a mut self,
+        sources: &'a [String],
+        context: &'a TranslationContext,
+        on_progress: &'a mut OnProgress<'_>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        Box::pin(async move {
+            let texts = sources
+                .iter()
+                .enumerate()
+                .filter(|(_, source)| !source.trim().is_empty())
+                .collect::<Vec<_>>();
+            let mut translations = vec![String::new(); sources.len()];
+            let mut stopped = false;
+
+            let batched = texts.len() > 1 && self.keeps_line_numbers();
+            let parsed = match texts.len() {
+                0 => return Ok(translations),
+                _ if !batched => vec![None; texts.len()],
+                _ => {
+                    let prompt = texts
+                        .iter()
+                        .map(|(_, source)| source.as_str())
+                        .collect::<Vec<_>>();
+                    let mut streamed = String::new();
+                    let mut reported = vec![None; texts.len()];
+                    let response = self
+                        .translate_streaming(&number_lines(&prompt), context, &mut |text| {
+                            streamed.push_str(text);
+                            let lines = parse_numbered_lines(&streamed, texts.len());
+                            let mut flow = ControlFlow::Continue(());
+                            for (((index, _), line), reported) in
+                                texts.iter().zip(lines).zip(&mut reported)
+                            {
+                                if line.is_some() && line != *reported {
+                                    if on_progress(*index, line.as_deref().unwrap_or_default())
+                                        .is_break()
+                                    {
+                                        stopped = true;
+                                        flow = ControlFlow::Break(());
+                                    }
+                                    *reported = line;
+                                }
+                            }
+                            flow
+                        })
+                        .await?;
+                    parse_numbered_lines(&response, texts.len())
+                }
+            };
+
+            for ((index, source), translation) in texts.into_iter().zip(parsed) {
+                translations[index] = match translation {
+                    Some(translation) => translation,
+                    // the lines cut off by a stop are not misaligned
+                    None if stopped => continue,
+                    None => {
+                        if batched {
+                            tracing::warn!("Line {} was misaligned, retrying alone", index + 1);
+                        }
+                        let mut streamed = String::new();
+                        self.translate_streaming(source, context, &mut |text| {
+                            streamed.push_str(text);
+                            let flow = on_progress(index, streamed.trim());
+                            stopped |= flow.is_break();
+                            flow
+                        })
+                        .await?
+                        .trim()
+                        .to_string()
+                    }
+                };
+            }
+            Ok(translations)
+        })
+    }
+}
+
+/// Join texts into `[1] text` lines, line breaks inside a text become spaces.
+pub fn number_lines(texts: &[&str]) -> String {
+    texts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            format!("[{}] {text}", index + 1)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Map a response to numbered lines back to `count` entries. Entries that are
+/// missing or numbered more than once are `None`. A response without any
+/// numbers is taken line by line if it has exactly `count` lines.
+pub fn parse_numbered_lines(response: &str, count: usize) -> Vec<Option<String>> {
+    let lines = response
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut entries: Vec<Option<String>> = vec![None; count];
+    let mut repeated = vec![false; count];
+    let mut current = None;
+    for line in &lines {
+        match line_number(line).filter(|(number, _)| (1..=count).contains(number)) {
+            Some((number, text)) => {
+                let index = number - 1;
+                repeated[index] |= entries[index].is_some();
+                entries[index] = Some(text.to_string());
+                current = Some(index);
+            }
+            // models sometimes wrap a long line, keep it with its number
+            None => {
+                if let Some(entry) = current.and_then(|index| entries[index].as_mut()) {
+                    entry.push('\n');
+                    entry.push_str(line);
+                }
+            }
+        }
+    }
+
+    if current.is_none() {
+        return match lines.len() == count {
+            true => lines
+                .into_iter()
+                .map(|line| Some(line.to_string()))
+                .collect(),
+            false => entries,
+        };
+    }
+    for (entry, repeated) in entries.iter_mut().zip(repeated) {
+        if repeated || entry.as_deref().is_some_and(str::is_empty) {
+            *entry = None;
+        }
+    }
+    entries
+}
+
+/// Split `[3] text`, `【3】text`, `3. text`, `3: text` or `3) text` into its
+/// number and text.
+fn line_number(line: &str) -> Option<(usize, &str)> {
+    let (number, text) = if let Some(rest) = line.strip_prefix('[') {
+        rest.split_once(']')?
+    } else if let Some(rest) = line.strip_prefix('【') {
+        rest.split_once('】')?
+    } else {
+        let end = line.find(|c: char| !c.is_ascii_digit())?;
+        let (number, rest) = line.split_at(end);
+        let rest = rest
+            .strip_prefix(['.', ':', ')', '：', '、'])
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))?;
+        (number, rest)
+    };
+    let number = number.trim().parse().ok()?;
+    Some((number, text.trim()))
+}
+
+impl Translator for Llm {
+    fn keeps_line_numbers(&self) -> bool {
+        Llm::keeps_line_numbers(self)
+    }
+
+    fn translate<'a>(
+        &'a mut self,
+        source: &'a str,
+        context: &'a TranslationContext,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(async move {
+            let options = context.options.clone().unwrap_or_default();
+            self.generate_with_context(source, context, &options)
+        })
+    }
+
+    fn translate_streaming<'a>(
+        &'a mut self,
+        source: &'a str,
+        context: &'a TranslationContext,
+        on_text: &'a mut OnText<'_>,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(async move {
+            let options = context.options.clone().unwrap_or_default();
+            self.generate_streaming(source, context, &options, on_text)
+        })
+    }
+}
+
+/// Translation of a text block so far, sent while the model generates.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranslationProgress {
+    pub document_id: String,
+    pub text_block_index: usize,
+    pub text: String,
+}
+
+const PROGRESS_CAPACITY: usize = 1024;
+
+/// A user configured translation backend, persisted in the translators file.
+/// Besides remote services this includes local GGUF files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "camelCase")]
+pub enum TranslatorConfig {
+    Gguf(LocalModel),
+    #[serde(rename = "openai")]
+    OpenAi(OpenAiConfig),
+    #[serde(rename = "deepl")]
+    DeepL(MtConfig),
+    Google(MtConfig),
+    #[serde(rename = "libretranslate")]
+    LibreTranslate(MtConfig),
+}
+
+impl TranslatorConfig {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Gguf(model) => &model.id,
+            Self::OpenAi(config) => &config.id,
+            Self::DeepL(config) | Self::Google(config) | Self::LibreTranslate(config) => &config.id,
+        }
+    }
+
+    pub fn info(&self) -> ModelInfo {
+        let (languages, provider) = match self {
+            Self::Gguf(model) => (model.languages.clone(), "gguf"),
+            Self::OpenAi(config) => (config.languages.clone(), "openai"),
+            Self::DeepL(config) => (config.languages.clone(), "deepl"),
+            Self::Google(config) => (config.languages.clone(), "google"),
+            Self::LibreTranslate(config) => (config.languages.clone(), "libretranslate"),
+        };
+        let languages = match languages.is_empty() {
+            true => supported_locales(),
+            false => languages,
+        };
+        ModelInfo {
+            id: self.id().to_string(),
+            languages,
+            provider: provider.to_string(),
+            name: match self {
+                Self::Gguf(model) => model.name.clone(),
+                _ => None,
+            },
+        }
+    }
+
+    /// The config as listed to clients, without its API key or header values.
+    pub fn redacted(&self) -> TranslatorSummary {
+        let mut config = self.clone();
+        let has_api_key = match &mut config {
+            Self::Gguf(_) => false,
+            Self::OpenAi(config) => {
+                let has_api_key = config.api_key.take().is_some();
+                for value in config.headers.values_mut() {
+                    value.clear();
+                }
+                has_api_key
+            }
+            Self::DeepL(config) | Self::Google(config) | Self::LibreTranslate(config) => {
+                config.api_key.take().is_some()
+            }
+        };
+        TranslatorSummary {
+            config,
+            has_api_key,
+        }
+    }
+
+    fn translator(&self) -> anyhow::Result<Box<dyn Translator>> {
+        match self {
+            Self::Gguf(model) => anyhow::bail!("`{}` is loaded in the background", model.id),
+            Self::OpenAi(config) => Ok(Box::new(OpenAiTranslator::new(config.clone())?)),
+            Self::DeepL(config) => Ok(Box::new(MtTranslator::new(
+                MtService::DeepL,
+                config.clone(),
+            ))),
+            Self::Google(config) => Ok(Box::new(MtTranslator::new(
+                MtService::Google,
+                config.clone(),
+            ))),
+            Self::LibreTranslate(config) => Ok(Box::new(MtTranslator::new(
+                MtService::LibreTranslate,
+                config.clone(),
+            ))),
+        }
+    }
+}
+
+/// A translation backend as listed to clients, see [`TranslatorConfig::redacted`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranslatorSummary {
+    #[serde(flatten)]
+    pub config: TranslatorConfig,
+    pub has_api_key: bool,
+}
+
 /// Load state of the LLM
-#[allow(clippy::large_enum_variant)]
 #[derive(Display)]
 #[strum(serialize_all = "lowercase")]
 pub enum State {
     Empty,
     Loading,
     #[strum(serialize = "ready")]
-    Ready(Llm),
+    Ready(Box<dyn Translator>),
     Failed(String),
 }
 
 /// Minimal owner for the LLM with non-blocking initialization.
 pub struct Model {
     state: Arc<RwLock<State>>,
+    /// Signalled whenever a pending load finishes, successfully or not.
+    loaded: Arc<Notify>,
     use_cpu: bool,
+    /// Configured translators and the JSON file they are saved to.
+    translators: std::sync::RwLock<Vec<TranslatorConfig>>,
+    config_path: Option<PathBuf>,
+    progress: broadcast::Sender<TranslationProgress>,
 }
 
 impl Default for Model {
@@ -48,43 +417,40 @@ impl Default for Model {
     }
 }
 
+/// Something with source texts to translate, one per text block.
 pub trait Translatable {
-    fn get_source(&self) -> anyhow::Result<String>;
-    fn set_translation(&mut self, translation: String) -> anyhow::Result<()>;
+    fn get_sources(&self) -> anyhow::Result<Vec<String>>;
+    fn set_translations(&mut self, translations: Vec<String>) -> anyhow::Result<()>;
 }
 
 impl Translatable for Document {
-    fn get_source(&self) -> anyhow::Result<String> {
-        let source = self
+    fn get_sources(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
             .text_blocks
-            .clone()
-            .into_iter()
-            .map(|block| block.text.unwrap_or_else(|| "<empty>".to_string()))
-            .collect::<Vec<_>>()
-            .join("\n");
-        Ok(source)
+            .iter()
+            .map(|block| block.text.clone().unwrap_or_default())
+            .collect())
     }
 
-    fn set_translation(&mut self, translation: String) -> anyhow::Result<()> {
-        let translations = translation.split("\n").collect::<Vec<_>>();
+    fn set_translations(&mut self, translations: Vec<String>) -> anyhow::Result<()> {
         for (block, translation) in self.text_blocks.iter_mut().zip(translations) {
-            block.translation = Some(translation.to_string());
+            block.translation = Some(translation);
         }
         Ok(())
     }
 }
 
 impl Translatable for TextBlock {
-    fn get_source(&self) -> anyhow::Result<String> {
+    fn get_sources(&self) -> anyhow::Result<Vec<String>> {
         let source = self
             .text
             .clone()
             .ok_or_else(|| anyhow::anyhow!("No source text found"))?;
-        Ok(source)
+        Ok(vec![source])
     }
 
-    fn set_translation(&mut self, translation: String) -> anyhow::Result<()> {
-        self.translation = Some(translation);
+    fn set_translations(&mut self, translations: Vec<String>) -> anyhow::Result<()> {
+        self.translation = translations.into_iter().next();
         Ok(())
     }
 }
@@ -93,16 +459,133 @@ impl Model {
     pub fn new(use_cpu: bool) -> Self {
         Self {
             state: Arc::new(RwLock::new(State::Empty)),
+            loaded: Arc::new(Notify::new()),
             use_cpu,
+            translators: std::sync::RwLock::new(Vec::new()),
+            config_path: None,
+            progress: broadcast::channel(PROGRESS_CAPACITY).0,
+        }
+    }
+
+    /// Create a model manager with the translators saved in `path`.
+    pub fn with_config(use_cpu: bool, path: PathBuf) -> Self {
+        let translators = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Ignoring invalid translators file {}: {err}",
+                    path.display()
+                );
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            translators: std::sync::RwLock::new(translators),
+            config_path: Some(path),
+            ..Self::new(use_cpu)
         }
     }
 
+    pub fn translators(&self) -> Vec<TranslatorConfig> {
+        self.translators.read().unwrap().clone()
+    }
+
+    pub fn translator(&self, id: &str) -> Option<TranslatorConfig> {
+        self.translators
+            .read()
+            .unwrap()
+            .iter()
+            .find(|translator| translator.id() == id)
+            .cloned()
+    }
+
+    /// Add a translator, replacing the one with the same id, and save the list.
+    pub fn add_translator(&self, config: TranslatorConfig) -> anyhow::Result<()> {
+        // the id names the prompt template directory of the translator
+        let id = config.id();
+        if id.is_empty()
+            || id.starts_with('.')
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            anyhow::bail!(
+                "`{id}` is not a valid translator id, use letters, digits, `.`, `_` and `-`"
+            );
+        }
+        if ModelId::from_str(config.id()).is_ok() {
+            anyhow::bail!("`{}` is the id of a bundled model", config.id());
+        }
+        if let TranslatorConfig::Gguf(model) = &config {
+            if !model.path.is_file() {
+                anyhow::bail!("{} is not a file", model.path.display());
+            }
+            if let Some(format) = &model.prompt_format {
+                ModelId::from_str(format)
+                    .map_err(|_| anyhow::anyhow!("`{format}` is not a bundled model"))?;
+            }
+        }
+        let mut translators = self.translators.write().unwrap();
+        match translators
+            .iter_mut()
+            .find(|translator| translator.id() == config.id())
+        {
+            Some(translator) => *translator = config,
+            None => translators.push(config),
+        }
+        self.save_translators(&translators)
+    }
+
+    /// Remove a translator, returns whether it existed.
+    pub fn remove_translator(&self, id: &str) -> anyhow::Result<bool> {
+        let mut translators = self.translators.write().unwrap();
+        let len = translators.len();
+        translators.retain(|translator| translator.id() != id);
+        if translators.len() == len {
+            return Ok(false);
+        }
+        self.save_translators(&translators)?;
+        Ok(true)
+    }
+
+    fn save_translators(&self, translators: &[TranslatorConfig]) -> anyhow::Result<()> {
+        let Some(path) = &self.config_path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // the file holds API keys, keep it readable by the current user only
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(&serde_json::to_vec_pretty(translators)?)?;
+        Ok(())
+    }
+
     pub fn is_cpu(&self) -> bool {
         self.use_cpu
     }
 
     /// Start loading the model on a blocking thread and return immediately.
     pub async fn load(&self, id: ModelId) {
+        let use_cpu = self.use_cpu;
+        self.spawn_load(async move { Llm::load(id, use_cpu).await })
+            .await;
+    }
+
+    /// Start loading a GGUF file registered by the user, like [`Model::load`].
+    pub async fn load_local(&self, model: LocalModel) {
+        let use_cpu = self.use_cpu;
+        self.spawn_load(async move { Llm::load_local(&model, use_cpu).await })
+            .await;
+    }
+
+    async fn spawn_load(&self, load: impl Future<Output = anyhow::Result<Llm>> + Send + 'static) {
         // mark as loading
         {
             let mut guard = self.state.write().await;
@@ -110,13 +593,13 @@ impl Model {
         }
 
         let state_cloned = self.state.clone();
-        let use_cpu = self.use_cpu;
+        let loaded = self.loaded.clone();
         tokio::spawn(async move {
-            let res = Llm::load(id, use_cpu).await;
+            let res = load.await;
             match res {
                 Ok(llm) => {
                     let mut guard = state_cloned.write().await;
-                    *guard = State::Ready(llm);
+                    *guard = State::Ready(Box::new(llm));
                 }
                 Err(e) => {
                     tracing::error!("LLM load join error: {e}");
@@ -124,9 +607,23 @@ impl Model {
                     *guard = State::Failed(format!("join error: {e}"));
                 }
             }
+            loaded.notify_waiters();
         });
     }
 
+    /// Connect to a configured translator, which is ready right away. GGUF files start
+    /// loading in the background instead.
+    pub async fn load_translator(&self, config: &TranslatorConfig) -> anyhow::Result<()> {
+        if let TranslatorConfig::Gguf(model) = config {
+            self.load_local(model.clone()).await;
+            return Ok(());
+        }
+        let translator = config.translator()?;
+        *self.state.write().await = State::Ready(translator);
+        self.loaded.notify_waiters();
+        Ok(())
+    }
+
     /// Returns a read guard to the internal state.
     /// Callers can inspect `State` directly while holding the guard.
     pub async fn get(&self) -> tokio::sync::RwLockReadGuard<'_, State> {
@@ -149,15 +646,66 @@ impl Model {
         matches!(*self.state.read().await, State::Ready(_))
     }
 
-    /// Generate text from the loaded model.
-    pub async fn generate(&self, doc: &mut impl Translatable) -> anyhow::Result<()> {
+    /// Wait for a pending load to finish, failing if the model could not be loaded.
+    pub async fn wait_ready(&self) -> anyhow::Result<()> {
+        loop {
+            // register before checking the state so a load finishing in between is not missed
+            let notified = self.loaded.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match &*self.state.read().await {
+                State::Ready(_) => return Ok(()),
+                State::Failed(e) => anyhow::bail!("Model failed to load: {e}"),
+                State::Empty => anyhow::bail!("No model is loaded"),
+                State::Loading => {}
+            }
+            notified.await;
+        }
+    }
+
+    /// Subscribe to the progress of translations as they are generated.
+    pub fn subscribe(&self) -> broadcast::Receiver<TranslationProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Send `progress` to the subscribers.
+    pub fn report(&self, progress: TranslationProgress) {
+        // no subscribers is fine, the result is returned at the end anyway
+        let _ = self.progress.send(progress);
+    }
+
+    /// Generate text from the loaded model, passing the translations of the
+    /// source texts to `on_progress` while they are generated. Once `cancelled`
+    /// is set, also while waiting for an earlier generation, this fails without
+    /// changing `doc`.
+    pub async fn generate(
+        &self,
+        doc: &mut impl Translatable,
+        context: &TranslationContext,
+        cancelled: &AtomicBool,
+        mut on_progress: impl FnMut(usize, &str) + Send,
+    ) -> anyhow::Result<()> {
         let mut guard = self.state.write().await;
+        if cancelled.load(Ordering::Relaxed) {
+            anyhow::bail!("Generation was cancelled");
+        }
         match &mut *guard {
-            State::Ready(llm) => {
-                let text = doc.get_source()?;
-                let response = llm.generate(&text, &GenerateOptions::default())?;
-                let response = response.trim().to_string();
-                doc.set_translation(response)
+            State::Ready(translator) => {
+                let sources = doc.get_sources()?;
+                let translations = translator
+                    .translate_batch(&sources, context, &mut |index, text| {
+                        on_progress(index, text);
+                        match cancelled.load(Ordering::Relaxed) {
+                            true => ControlFlow::Break(()),
+                            false => ControlFlow::Continue(()),
+                        }
+                    })
+                    .await?;
+                if cancelled.load(Ordering::Relaxed) {
+                    anyhow::bail!("Generation was cancelled");
+                }
+                doc.set_translations(translations)
             }
             State::Loading => Err(anyhow::anyhow!("Model is still loading")),
             State::Failed(e) => Err(anyhow::anyhow!("Model failed to load: {e}")),
@@ -165,3 +713,173 @@ impl Model {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echoes numbered prompts upper-cased but drops the second line.
+    #[derive(Default)]
+    struct DroppingTranslator {
+        calls: usize,
+    }
+
+    impl Translator for DroppingTranslator {
+        fn translate<'a>(
+            &'a mut self,
+            source: &'a str,
+            _context: &'a TranslationContext,
+        ) -> BoxFuture<'a, anyhow::Result<String>> {
+            self.calls += 1;
+            Box::pin(async move {
+                Ok(source
+                    .lines()
+                    .filter(|line| !line.starts_with("[2]"))
+                    .map(str::to_uppercase)
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            })
+        }
+    }
+
+    #[test]
+    fn numbered_lines() {
+        let response = "[1] one\n2. two\nwrapped\n【4】four\n[4] again";
+        assert_eq!(
+            parse_numbered_lines(response, 4),
+            vec![
+                Some("one".to_string()),
+                Some("two\nwrapped".to_string()),
+                None,
+                None
+            ]
+        );
+        assert_eq!(
+            parse_numbered_lines("one\ntwo", 2),
+            vec![Some("one".to_string()), Some("two".to_string())]
+        );
+        assert_eq!(parse_numbered_lines("one", 2), vec![None, None]);
+    }
+
+    #[tokio::test]
+    async fn misaligned_lines_are_retried() -> anyhow::Result<()> {
+        let sources = ["a", "", "b", "c"].map(str::to_string);
+        let mut progress = Vec::new();
+        let translations = DroppingTranslator::default()
+            .translate_batch(
+                &sources,
+                &TranslationContext::default(),
+                &mut |index, text| {
+                    progress.push((index, text.to_string()));
+                    ControlFlow::Continue(())
+                },
+            )
+            .await?;
+        assert_eq!(translations, vec!["A", "", "B", "C"]);
+        assert_eq!(progress.first(), Some(&(0, "A".to_string())));
+        assert_eq!(progress.last(), Some(&(2, "B".to_string())));
+        Ok(())
+    }
+
+    /// Like [`DroppingTranslator`], for a model that cannot be told about numbers.
+    #[derive(Default)]
+    struct UnnumberedTranslator(DroppingTranslator);
+
+    impl Translator for UnnumberedTranslator {
+        fn keeps_line_numbers(&self) -> bool {
+            false
+        }
+
+        fn translate<'a>(
+            &'a mut self,
+            source: &'a str,
+            context: &'a TranslationContext,
+        ) -> BoxFuture<'a, anyhow::Result<String>> {
+            self.0.translate(source, context)
+        }
+    }
+
+    #[tokio::test]
+    async fn translates_alone_without_numbers() -> anyhow::Result<()> {
+        let sources = ["a", "b", "c"].map(str::to_string);
+        let mut translator = UnnumberedTranslator::default();
+        let translations = translator
+            .translate_batch(&sources, &TranslationContext::default(), &mut |_, _| {
+                ControlFlow::Continue(())
+            })
+            .await?;
+        assert_eq!(translator.0.calls, 3);
+        assert_eq!(translations, vec!["A", "B", "C"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_without_retrying() -> anyhow::Result<()> {
+        let sources = ["a", "b", "c"].map(str::to_string);
+        let mut translator = DroppingTranslator::default();
+        let translations = translator
+            .translate_batch(&sources, &TranslationContext::default(), &mut |_, _| {
+                ControlFlow::Break(())
+            })
+            .await?;
+        assert_eq!(translator.calls, 1);
+        assert_eq!(translations, vec!["A", "", "C"]);
+        Ok(())
+    }
+
+    #[test]
+    fn lists_translators_without_secrets() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("koharu-translators-{}.json", std::process::id()));
+        let model = Model::with_config(false, path.clone());
+        model.add_translator(TranslatorConfig::OpenAi(OpenAiConfig {
+            id: "openai".to_string(),
+            base_url: "http://localhost:8080/v1".to_string(),
+            model: "test-model".to_string(),
+            api_key: Some("secret".to_string()),
+            headers: [("x-token".to_string(), "secret".to_string())].into(),
+            languages: Vec::new(),
+            system_prompt: None,
+            temperature: None,
+        }))?;
+        model.add_translator(TranslatorConfig::DeepL(MtConfig {
+            id: "deepl".to_string(),
+            base_url: None,
+            api_key: None,
+            source_language: None,
+            languages: Vec::new(),
+        }))?;
+
+        for id in ["", "../prompts", ".hidden", "a/b", "a\\b"] {
+            let mut config = model.translator("deepl").expect("added above");
+            if let TranslatorConfig::DeepL(config) = &mut config {
+                config.id = id.to_string();
+            }
+            assert!(model.add_translator(config).is_err(), "{id:?} was accepted");
+        }
+
+        let listed = serde_json::to_string(
+            &model
+                .translators()
+                .iter()
+                .map(TranslatorConfig::redacted)
+                .collect::<Vec<_>>(),
+        )?;
+        assert!(!listed.contains("secret"));
+        let listed: Vec<serde_json::Value> = serde_json::from_str(&listed)?;
+        assert_eq!(listed[0]["provider"], "openai");
+        assert_eq!(listed[0]["hasApiKey"], true);
+        assert_eq!(listed[1]["hasApiKey"], false);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&path)?.permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
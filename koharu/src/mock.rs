@@ -0,0 +1,86 @@
+//! A local stand-in for the translation services, used by the tests of the
+//! translators. Each service upper-cases the texts it gets.
+
+use anyhow::Result;
+use axum::{
+    Json, Router,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+};
+use serde_json::{Value, json};
+
+/// Serve the mock services on a free port and return their base URL. DeepL,
+/// Google and LibreTranslate answer on their usual paths, the OpenAI chat
+/// completions API under `/v1/`. Google refuses requests without a key header.
+pub async fn mock_server() -> Result<String> {
+    fn upper(texts: &Value) -> Vec<String> {
+        texts
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|text| text.as_str().unwrap_or_default().to_uppercase())
+            .collect()
+    }
+
+    // replies with the model, the authorization header, the number of messages
+    // and the last one upper-cased, then the temperature if one was sent
+    async fn completions(headers: HeaderMap, Json(body): Json<Value>) -> Json<Value> {
+        let auth = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let messages = body["messages"].as_array().cloned().unwrap_or_default();
+        let source = messages
+            .last()
+            .and_then(|message| message["content"].as_str())
+            .unwrap_or_default();
+        let temperature = body
+            .get("temperature")
+            .map(|temperature| format!(" {temperature}"))
+            .unwrap_or_default();
+        Json(json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": format!("{} {auth} {} {}", body["model"].as_str().unwrap_or_default(), messages.len(), source.to_uppercase()) + &temperature,
+                }
+            }]
+        }))
+    }
+
+    let app = Router::new()
+        .route(
+            "/v2/translate",
+            post(|Json(body): Json<Value>| async move {
+                let items = upper(&body["text"])
+                    .into_iter()
+                    .map(|text| json!({ "text": text }))
+                    .collect::<Vec<_>>();
+                Json(json!({ "translations": items }))
+            }),
+        )
+        .route(
+            "/language/translate/v2",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                if !headers.contains_key("x-goog-api-key") {
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                let items = upper(&body["q"])
+                    .into_iter()
+                    .map(|text| json!({ "translatedText": text }))
+                    .collect::<Vec<_>>();
+                Ok(Json(json!({ "data": { "translations": items } })))
+            }),
+        )
+        .route(
+            "/translate",
+            post(|Json(body): Json<Value>| async move {
+                Json(json!({ "translatedText": upper(&body["q"]) }))
+            }),
+        )
+        .route("/v1/chat/completions", post(completions));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok(format!("http://{addr}"))
+}
@@ -0,0 +1,302 @@
+use std::ops::ControlFlow;
+
+use anyhow::{Context, Result, anyhow};
+use futures::future::BoxFuture;
+use koharu_ml::llm::{SUPPORTED_LANGUAGES, TranslationContext};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::llm::{OnProgress, Translator};
+
+// Machine translation services with a REST API. Each takes a list of texts, so a
+// whole document goes out in one request and comes back block by block.
+
+/// Most texts DeepL accepts in one request, the other services take more.
+const MAX_TEXTS_PER_REQUEST: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtService {
+    DeepL,
+    Google,
+    LibreTranslate,
+}
+
+/// A machine translation service, persisted in the translators file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MtConfig {
+    /// Id shown in `llm_list` and passed to `llm_load`.
+    pub id: String,
+    /// Endpoint of the service, the public API when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Language code of the source text, detected by the service when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_language: Option<String>,
+    /// Target languages shown in the UI, all known languages when empty.
+    #[serde(default)]
+    pub languages: Vec<String>,
+}
+
+pub struct MtTranslator {
+    client: reqwest::Client,
+    service: MtService,
+    config: MtConfig,
+}
+
+impl MtTranslator {
+    pub fn new(service: MtService, config: MtConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            service,
+            config,
+        }
+    }
+
+    async fn request(&self, texts: &[String], target: &str, context: &str) -> Result<Vec<String>> {
+        let key = self.config.api_key.as_deref();
+        let source = self.config.source_language.as_deref();
+
+        let request = match self.service {
+            MtService::DeepL => {
+                let key = key.context("DeepL needs an API key")?;
+                // free plan keys end with `:fx` and have their own endpoint
+                let default = if key.ends_with(":fx") {
+                    "https://api-free.deepl.com"
+                } else {
+                    "https://api.deepl.com"
+                };
+                let mut body = json!({ "text": texts, "target_lang": deepl_code(target) });
+                if let Some(source) = source {
+                    body["source_lang"] = json!(source.to_ascii_uppercase());
+                }
+                if !context.is_empty() {
+                    body["context"] = json!(context);
+                }
+                self.client
+                    .post(format!("{}/v2/translate", self.base_url(default)))
+                    .header("Authorization", format!("DeepL-Auth-Key {key}"))
+                    .json(&body)
+            }
+            MtService::Google => {
+                let key = key.context("Google Translate needs an API key")?;
+                let mut body =
+                    json!({ "q": texts, "target": google_code(target), "format": "text" });
+                if let Some(source) = source {
+                    body["source"] = json!(source);
+                }
+                self.client
+                    .post(format!(
+                        "{}/language/translate/v2",
+                        self.base_url("https://translation.googleapis.com")
+                    ))
+                    // a key in the query would end up in the URL of request errors
+                    .header("X-goog-api-key", key)
+                    .json(&body)
+            }
+            MtService::LibreTranslate => {
+                let mut body = json!({
+                    "q": texts,
+                    "source": source.unwrap_or("auto"),
+                    "target": libretranslate_code(target),
+                    "format": "text",
+                });
+                if let Some(key) = key {
+                    body["api_key"] = json!(key);
+                }
+                self.client
+                    .post(format!(
+                        "{}/translate",
+                        self.base_url("http://localhost:5000")
+                    ))
+                    .json(&body)
+            }
+        };
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("{:?} returned {status}: {body}", self.service));
+        }
+        let body: Value = response.json().await?;
+
+        let translations = match self.service {
+            MtService::DeepL => body["translations"]
+                .as_array()
+                .map(|items| items.iter().map(|item| &item["text"]).collect::<Vec<_>>()),
+            MtService::Google => body["data"]["translations"].as_array().map(|items| {
+                items
+                    .iter()
+                    .map(|item| &item["translatedText"])
+                    .collect::<Vec<_>>()
+            }),
+            MtService::LibreTranslate => body["translatedText"]
+                .as_array()
+                .map(|items| items.iter().collect::<Vec<_>>()),
+        }
+        .ok_or_else(|| anyhow!("Unexpected response from {:?}: {body}", self.service))?;
+
+        let translations = translations
+            .into_iter()
+            .map(|text| text.as_str().unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        if translations.len() != texts.len() {
+            return Err(anyhow!(
+                "{:?} returned {} translations for {} texts",
+                self.service,
+                translations.len(),
+                texts.len()
+            ));
+        }
+        Ok(translations)
+    }
+
+    fn base_url<'a>(&'a self, default: &'a str) -> &'a str {
+        self.config
+            .base_url
+            .as_deref()
+            .unwrap_or(default)
+            .trim_end_matches('/')
+    }
+}
+
+impl Translator for MtTranslator {
+    fn translate<'a>(
+        &'a mut self,
+        source: &'a str,
+        context: &'a TranslationContext,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let sources = [source.to_string()];
+            let translations = self
+                .translate_batch(&sources, context, &mut |_, _| ControlFlow::Continue(()))
+                .await?;
+            Ok(translations.into_iter().next().unwrap_or_default())
+        })
+    }
+
+    fn translate_batch<'a>(
+        &'a mut self,
+        sources: &'a [String],
+        context: &'a TranslationContext,
+        on_progress: &'a mut OnProgress<'_>,
+    ) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(async move {
+            let target = language_code(&context.language())?;
+            // only DeepL takes context, as plain source text that is not translated.
+            // Glossaries live server side for these services, so terms are only
+            // checked after translation.
+            let context = context
+                .history
+                .iter()
+                .map(|pair| pair.source.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut translations = Vec::with_capacity(sources.len());
+            for chunk in sources.chunks(MAX_TEXTS_PER_REQUEST) {
+                let mut flow = ControlFlow::Continue(());
+                for translation in self.request(chunk, target, &context).await? {
+                    if on_progress(translations.len(), &translation).is_break() {
+                        flow = ControlFlow::Break(());
+                    }
+                    translations.push(translation);
+                }
+                if flow.is_break() {
+                    break;
+                }
+            }
+            Ok(translations)
+        })
+    }
+}
+
+/// Language code for a language name as used by the LLM prompts, e.g. `English`.
+fn language_code(name: &str) -> Result<&'static str> {
+    SUPPORTED_LANGUAGES
+        .iter()
+        .find(|(code, language)| *language == name || *code == name)
+        .map(|(code, _)| *code)
+        .ok_or_else(|| anyhow!("No language code for the target language `{name}`"))
+}
+
+fn deepl_code(code: &str) -> String {
+    match code {
+        "en" => "EN-US".to_string(),
+        "pt" => "PT-BR".to_string(),
+        "zh" => "ZH-HANS".to_string(),
+        "zh-Hant" => "ZH-HANT".to_string(),
+        code => code.to_ascii_uppercase(),
+    }
+}
+
+fn google_code(code: &str) -> &str {
+    match code {
+        "zh" => "zh-CN",
+        "zh-Hant" => "zh-TW",
+        code => code,
+    }
+}
+
+fn libretranslate_code(code: &str) -> &str {
+    match code {
+        "zh-Hant" => "zt",
+        code => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::mock_server;
+
+    #[tokio::test]
+    async fn translate_batch_with_mock_server() -> Result<()> {
+        let base_url = mock_server().await?;
+        let sources = (0..120).map(|i| format!("text {i}")).collect::<Vec<_>>();
+
+        for service in [
+            MtService::DeepL,
+            MtService::Google,
+            MtService::LibreTranslate,
+        ] {
+            let mut translator = MtTranslator::new(
+                service,
+                MtConfig {
+                    id: "mock".to_string(),
+                    base_url: Some(base_url.clone()),
+                    api_key: Some("key".to_string()),
+                    source_language: None,
+                    languages: Vec::new(),
+                },
+            );
+            let translations = translator
+                .translate_batch(&sources, &TranslationContext::default(), &mut |_, _| {
+                    ControlFlow::Continue(())
+                })
+                .await?;
+            assert_eq!(translations.len(), sources.len());
+            assert_eq!(translations[119], "TEXT 119");
+
+            let context = TranslationContext {
+                language: Some("Klingon".to_string()),
+                ..Default::default()
+            };
+            assert!(
+                translator
+                    .translate_batch(&sources, &context, &mut |_, _| ControlFlow::Continue(()))
+                    .await
+                    .is_err()
+            );
+        }
+        Ok(())
+    }
+    #[test]
+    fn maps_language_names_to_codes() {
+        assert_eq!(language_code("English").ok(), Some("en"));
+        assert_eq!(language_code("ja").ok(), Some("ja"));
+        assert!(language_code("Klingon").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, anyhow};
+use futures::future::BoxFuture;
+use koharu_ml::llm::{GenerateOptions, GlossaryEntry, TranslationContext};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::llm::Translator;
+
+// Client for servers speaking the OpenAI chat completions protocol, such as the
+// OpenAI API itself, llama.cpp's `llama-server`, vLLM or Ollama.
+
+const DEFAULT_SYSTEM_PROMPT: &str = "You are a professional manga translator. Translate the text into {language}. Reply with the translation only, without explanations. When the lines are numbered like `[1]`, translate each line on its own and keep its number.";
+
+/// A chat completions endpoint, persisted in the translators file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenAiConfig {
+    /// Id shown in `llm_list` and passed to `llm_load`.
+    pub id: String,
+    /// Base URL including the version segment, e.g. `http://localhost:8080/v1`.
+    pub base_url: String,
+    /// Model name sent with every request.
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Extra headers sent with every request.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Target languages shown in the UI, the model is assumed to handle any.
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// System prompt, `{language}` is replaced by the target language.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+pub struct OpenAiTranslator {
+    client: reqwest::Client,
+    config: OpenAiConfig,
+}
+
+impl OpenAiTranslator {
+    pub fn new(config: OpenAiConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Some(key) = &config.api_key {
+            headers.insert(
+                reqwest::header::AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {key}"))?,
+            );
+        }
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+        Ok(Self { client, config })
+    }
+
+    /// Send a chat and return the content of the first choice.
+    /// Sends the messages, `options` take precedence over the configured temperature.
+    pub async fn chat(
+        &self,
+        messages: &[ChatMessage],
+        options: Option<&GenerateOptions>,
+    ) -> Result<String> {
+        let url = format!(
+            "{}/chat/completions",
+            self.config.base_url.trim_end_matches('/')
+        );
+        let request = ChatRequest {
+            model: &self.config.model,
+            messages,
+            temperature: options
+                .map(|options| options.temperature)
+                .or(self.config.temperature),
+            top_p: options.and_then(|options| options.top_p),
+            max_tokens: options.map(|options| options.max_tokens),
+            seed: options.map(|options| options.seed),
+            stream: false,
+        };
+
+        let response = self.client.post(&url).json(&request).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("{url} returned {status}: {body}"));
+        }
+
+        let response: ChatResponse = response
+            .json()
+            .await
+            .with_context(|| format!("Unexpected response from {url}"))?;
+        response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .ok_or_else(|| anyhow!("{url} returned no choices"))
+    }
+
+    fn messages(&self, source: &str, context: &TranslationContext) -> Vec<ChatMessage> {
+        let system = self
+            .config
+            .system_prompt
+            .as_deref()
+            .unwrap_or(DEFAULT_SYSTEM_PROMPT)
+            .replace("{language}", &context.language());
+        let mut messages = vec![ChatMessage::new(
+            "system",
+            system + &glossary_prompt(&GlossaryEntry::relevant(&context.glossary, source)),
+        )];
+        for pair in &context.history {
+            messages.push(ChatMessage::new("user", pair.source.as_str()));
+            messages.push(ChatMessage::new("assistant", pair.translation.as_str()));
+        }
+        messages.push(ChatMessage::new("user", source));
+        messages
+    }
+}
+
+/// Glossary section appended to the system prompt, empty without terms.
+fn glossary_prompt(glossary: &[&GlossaryEntry]) -> String {
+    if glossary.is_empty() {
+        return String::new();
+    }
+    let terms = glossary
+        .iter()
+        .map(|entry| match &entry.note {
+            Some(note) => format!("- {} -> {} ({note})", entry.source, entry.target),
+            None => format!("- {} -> {}", entry.source, entry.target),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("\n\nAlways translate these terms as given:\n{terms}")
+}
+
+impl Translator for OpenAiTranslator {
+    fn translate<'a>(
+        &'a mut self,
+        source: &'a str,
+        context: &'a TranslationContext,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            self.chat(&self.messages(source, context), context.options.as_ref())
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use koharu_ml::llm::TranslationPair;
+
+    use super::*;
+    use crate::mock::mock_server;
+
+    #[tokio::test]
+    async fn translate_with_mock_server() -> Result<()> {
+        let mut translator = OpenAiTranslator::new(OpenAiConfig {
+            id: "mock".to_string(),
+            base_url: format!("{}/v1/", mock_server().await?),
+            model: "test-model".to_string(),
+            api_key: Some("secret".to_string()),
+            headers: HashMap::new(),
+            languages: Vec::new(),
+            system_prompt: None,
+            temperature: None,
+        })?;
+
+        let translation = translator
+            .translate("hello", &TranslationContext::default())
+            .await?;
+        assert_eq!(translation, "test-model Bearer secret 2 HELLO");
+
+        let context = TranslationContext {
+            history: vec![TranslationPair {
+                source: "before".to_string(),
+                translation: "BEFORE".to_string(),
+            }],
+            glossary: Vec::new(),
+            options: Some(GenerateOptions {
+                temperature: 0.0,
+                ..Default::default()
+            }),
+            language: Some("Japanese".to_string()),
+        };
+        let translation = translator.translate("hello", &context).await?;
+        assert_eq!(translation, "test-model Bearer secret 4 HELLO 0.0");
+        assert!(
+            translator.messages("hello", &context)[0]
+                .content
+                .contains("into Japanese.")
+        );
+        Ok(())
+    }
+}
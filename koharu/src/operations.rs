@@ -1,23 +1,50 @@
-use std::{io::Cursor, path::PathBuf, str::FromStr, sync::Arc};
+use std::{
+    io::{Cursor, Read, Seek},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, atomic::AtomicBool},
+    time::SystemTime,
+};
 
-use image::{self, GenericImageView, ImageFormat, RgbaImage};
-use koharu_ml::{llm::ModelId, set_locale};
+use image::{self, GenericImageView, ImageFormat, RgbaImage, codecs::jpeg::JpegEncoder};
+use koharu_ml::{
+    llm::{GenerateOptions, GlossaryEntry, ModelId, TranslationContext, TranslationPair},
+    set_locale,
+};
 use koharu_renderer::renderer::TextShaderEffect;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use sys_locale::get_locale;
 use tracing::instrument;
 
 use crate::{
+    archive::{has_extension, is_zip, read_archive, unique_names, write_cbz},
+    book::{BookOptions, BookPage, comic_info_field, write_epub, write_pdf},
+    glossary::{self, GlossaryIssue},
     image::SerializableDynamicImage,
-    khr::{deserialize_khr, has_khr_magic, serialize_khr},
+    khr::{KhrReader, has_khr_magic, is_khr_file, open_khr, save_khr, serialize_khr},
     llm, ml,
+    psd::{PsdLayer, PsdText, write_psd},
     renderer::Renderer,
     result::Result,
-    state::{AppState, Document, TextBlock, TextStyle},
+    script::{
+        ImportOptions, ImportReport, Script, ScriptOptions, apply_script, read_script, write_script,
+    },
+    sort::{PageKey, PageOrder},
+    state::{
+        AppState, Document, DocumentRef, LoadedDocuments, ProjectMeta, TextBlock, TextStyle,
+        update_document,
+    },
 };
 
+const BOOK_JPEG_QUALITY: u8 = 90;
+/// Font size of PSD type layers whose style leaves the size to the renderer.
+const PSD_FONT_SIZE: f32 = 24.0;
+
+/// Extensions of the files that can be opened, archives and .khr projects included.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["khr", "png", "jpg", "jpeg", "webp", "cbz", "zip"];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InpaintRegion {
@@ -31,6 +58,33 @@ pub struct InpaintRegion {
 pub struct DocumentInput {
     pub path: PathBuf,
     pub bytes: Vec<u8>,
+    /// Modification time of the file on disk, unknown for uploads.
+    pub modified: Option<SystemTime>,
+    /// A .khr project on disk, read from `path` chunk by chunk. `bytes` is empty.
+    pub khr_file: bool,
+}
+
+impl DocumentInput {
+    pub fn read(path: PathBuf) -> std::io::Result<Self> {
+        let khr_file = is_khr_file(&path)?;
+        let bytes = match khr_file {
+            true => Vec::new(),
+            false => std::fs::read(&path)?,
+        };
+        let modified = std::fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        Ok(Self {
+            path,
+            bytes,
+            modified,
+            khr_file,
+        })
+    }
+
+    fn is_khr(&self) -> bool {
+        self.khr_file || has_khr_magic(&self.bytes)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,11 +109,16 @@ fn clamp_region(region: &InpaintRegion, width: u32, height: u32) -> Option<(u32,
     Some((x0, y0, w, h))
 }
 
-pub fn load_documents_from_paths(paths: Vec<PathBuf>) -> Result<Vec<Document>> {
-    let inputs = paths
+/// Read documents from files and directories, a directory opens the supported
+/// files directly inside it.
+pub fn load_documents_from_paths(
+    paths: Vec<PathBuf>,
+    order: &PageOrder,
+) -> Result<LoadedDocuments> {
+    let inputs = expand_directories(paths)?
         .into_iter()
-        .filter_map(|path| match std::fs::read(&path) {
-            Ok(bytes) => Some(DocumentInput { path, bytes }),
+        .filter_map(|path| match DocumentInput::read(path.clone()) {
+            Ok(input) => Some(input),
             Err(err) => {
                 tracing::warn!(?err, "Failed to read document at {:?}", path);
                 None
@@ -67,45 +126,135 @@ pub fn load_documents_from_paths(paths: Vec<PathBuf>) -> Result<Vec<Document>> {
         })
         .collect();
 
-    load_documents(inputs)
+    load_documents(inputs, order)
+}
+
+fn expand_directories(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path);
+            continue;
+        }
+        files.extend(
+            std::fs::read_dir(&path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file() && has_extension(path, SUPPORTED_EXTENSIONS)),
+        );
+    }
+    Ok(files)
 }
 
-pub fn load_documents(inputs: Vec<DocumentInput>) -> Result<Vec<Document>> {
+pub fn load_documents(inputs: Vec<DocumentInput>, order: &PageOrder) -> Result<LoadedDocuments> {
     if inputs.is_empty() {
-        return Ok(vec![]);
+        return Ok(LoadedDocuments::default());
     }
 
-    if inputs.len() == 1 {
-        let input = &inputs[0];
-        if has_khr_magic(&input.bytes) {
-            return Ok(deserialize_khr(&input.bytes)
-                .map_err(|e| anyhow::anyhow!("Failed to load documents: {e}"))?);
-        }
+    // a single project or archive that fails to load should report why
+    if inputs.len() == 1 && (inputs[0].is_khr() || is_zip(&inputs[0].bytes)) {
+        let input = inputs.into_iter().next().expect("one input");
+        return Ok(load_input(input, order)
+            .map_err(|e| anyhow::anyhow!("Failed to load documents: {e}"))?);
     }
 
-    let mut documents = inputs
+    let mut groups = inputs
         .into_par_iter()
-        .filter_map(
-            |input| match Document::from_bytes(input.path, input.bytes) {
-                Ok(docs) => Some(docs),
+        .filter_map(|input| {
+            let key = PageKey::new(&input.path, input.modified, &input.bytes, order.sort_by);
+            match load_input(input, order) {
+                Ok(loaded) => Some((key, loaded)),
                 Err(err) => {
                     tracing::warn!(?err, "Failed to parse document");
                     None
                 }
-            },
-        )
-        .flatten()
+            }
+        })
         .collect::<Vec<_>>();
 
-    documents.sort_by_key(|doc| doc.name.clone());
+    // pages of a .khr or an archive stay together, in their own order
+    order.sort(&mut groups);
+
+    let mut loaded = LoadedDocuments::default();
+    for (_, group) in groups {
+        loaded.documents.extend(group.documents);
+        loaded.meta.merge(group.meta);
+    }
+    Ok(loaded)
+}
+
+fn load_input(input: DocumentInput, order: &PageOrder) -> anyhow::Result<LoadedDocuments> {
+    if is_zip(&input.bytes) {
+        let archive = read_archive(&input.bytes)?;
+        let pages = archive
+            .pages
+            .into_iter()
+            .map(|(path, bytes)| DocumentInput {
+                path,
+                bytes,
+                modified: None,
+                khr_file: false,
+            })
+            .collect();
+        let mut loaded = load_documents(pages, order)?;
+        loaded.meta.comic_info = archive.comic_info;
+        return Ok(loaded);
+    }
+
+    if input.khr_file {
+        return read_khr(open_khr(&input.path)?);
+    }
+    if has_khr_magic(&input.bytes) {
+        return read_khr(KhrReader::new(Cursor::new(input.bytes))?);
+    }
+
+    Ok(LoadedDocuments {
+        documents: vec![Document::from_bytes(input.path, input.bytes)?],
+        meta: ProjectMeta::default(),
+    })
+}
+
+fn read_khr<R: Read + Seek>(reader: KhrReader<R>) -> anyhow::Result<LoadedDocuments> {
+    let meta = reader.meta().clone();
+    Ok(LoadedDocuments {
+        documents: reader.read_all()?,
+        meta,
+    })
+}
+
+pub async fn set_documents(state: &AppState, loaded: LoadedDocuments) -> Result<Vec<Document>> {
+    let mut guard = state.write().await;
+    guard.set_documents(loaded);
+    Ok(guard.documents.clone())
+}
+
+pub async fn insert_documents(
+    state: &AppState,
+    position: Option<usize>,
+    loaded: LoadedDocuments,
+) -> Result<Vec<Document>> {
+    let mut guard = state.write().await;
+    guard.insert_documents(position, loaded.documents)?;
+    guard.meta.merge(loaded.meta);
+    Ok(guard.documents.clone())
+}
+
+pub async fn add_documents(state: &AppState, loaded: LoadedDocuments) -> Result<Vec<Document>> {
+    let mut guard = state.write().await;
+    guard.add_documents(loaded.documents);
+    guard.meta.merge(loaded.meta);
+    Ok(guard.documents.clone())
+}
 
-    Ok(documents)
+pub async fn remove_document(state: &AppState, document: DocumentRef) -> Result<Vec<Document>> {
+    let mut guard = state.write().await;
+    guard.remove_document(&document)?;
+    Ok(guard.documents.clone())
 }
 
-pub async fn set_documents(state: &AppState, documents: Vec<Document>) -> Result<Vec<Document>> {
+pub async fn reorder_documents(state: &AppState, ids: Vec<String>) -> Result<Vec<Document>> {
     let mut guard = state.write().await;
-    guard.documents = documents.clone();
-    Ok(documents)
+    guard.reorder_documents(&ids)?;
+    Ok(guard.documents.clone())
 }
 
 pub async fn get_documents(state: &AppState) -> Result<Vec<Document>> {
@@ -115,13 +264,26 @@ pub async fn get_documents(state: &AppState) -> Result<Vec<Document>> {
 
 pub async fn serialize_state(state: &AppState) -> Result<Vec<u8>> {
     let guard = state.read().await;
-    let bytes = serialize_khr(&guard.documents)
+    let bytes = serialize_khr(&guard.documents, &guard.meta)
         .map_err(|e| anyhow::anyhow!("Failed to serialize documents: {e}"))?;
     Ok(bytes)
 }
 
+pub async fn save_state(state: &AppState, path: PathBuf) -> Result<()> {
+    let (documents, meta) = {
+        let guard = state.read().await;
+        (guard.documents.clone(), guard.meta.clone())
+    };
+    // encoding the layers takes a while, keep it off the state lock and the runtime
+    tokio::task::spawn_blocking(move || save_khr(&path, &documents, &meta))
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(|e| anyhow::anyhow!("Failed to save documents: {e}"))?;
+    Ok(())
+}
+
 pub fn serialize_documents(documents: &[Document]) -> Result<Vec<u8>> {
-    let bytes = serialize_khr(documents)
+    let bytes = serialize_khr(documents, &ProjectMeta::default())
         .map_err(|e| anyhow::anyhow!("Failed to serialize documents: {e}"))?;
     Ok(bytes)
 }
@@ -144,12 +306,9 @@ pub async fn default_khr_filename(state: &AppState) -> Option<String> {
     }
 }
 
-pub async fn export_document(state: &AppState, index: usize) -> Result<ExportedDocument> {
+pub async fn export_document(state: &AppState, document: DocumentRef) -> Result<ExportedDocument> {
     let guard = state.read().await;
-    let document = guard
-        .documents
-        .get(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let document = guard.document(&document)?;
 
     let document_ext = document
         .path
@@ -198,15 +357,292 @@ pub async fn export_all_documents(state: &AppState) -> Result<Vec<ExportedDocume
     Ok(exports)
 }
 
+/// Pack the pages into a CBZ, keeping their original file names and the
+/// `ComicInfo.xml` of the source archive. Pages that were not rendered yet are
+/// written as the original image.
+pub async fn export_cbz(state: &AppState) -> Result<ExportedDocument> {
+    let guard = state.read().await;
+    let mut names = Vec::with_capacity(guard.documents.len());
+    let mut images = Vec::with_capacity(guard.documents.len());
+
+    for document in &guard.documents {
+        let document_ext = document
+            .path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("jpg");
+        names.push(
+            document
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| format!("{}.{document_ext}", document.name)),
+        );
+
+        let image = document.rendered.as_ref().unwrap_or(&document.image);
+        images.push(encode_image(image, document_ext)?);
+    }
+    // pages from different folders or archives may share a name
+    let pages: Vec<_> = unique_names(names).into_iter().zip(images).collect();
+
+    let bytes = write_cbz(&pages, guard.meta.comic_info.as_deref())?;
+    Ok(ExportedDocument {
+        filename: "export.cbz".to_string(),
+        bytes,
+    })
+}
+
+/// Export a document as a layered PSD: the original, the inpainted clean plate,
+/// the brush layer and one type layer per translated text block, placed at the
+/// block and named after its translation. Blocks translated since the last render
+/// are rendered for the export.
+pub async fn export_psd(
+    state: &AppState,
+    renderer: &Arc<Renderer>,
+    document: DocumentRef,
+) -> Result<ExportedDocument> {
+    let document = state.read().await.document(&document)?.clone();
+    let renderer = renderer.clone();
+    let filename = format!("{}_koharu.psd", document.name);
+
+    let bytes = tokio::task::spawn_blocking(move || psd_bytes(&renderer, &document))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(ExportedDocument { filename, bytes })
+}
+
+fn psd_bytes(renderer: &Renderer, document: &Document) -> Result<Vec<u8>> {
+    let full_layer = |name: &str, image: &SerializableDynamicImage| PsdLayer {
+        name: name.to_string(),
+        left: 0,
+        top: 0,
+        image: image.to_rgba8(),
+        visible: true,
+        text: None,
+    };
+    let mut layers = vec![full_layer("Original", &document.image)];
+    if let Some(inpainted) = &document.inpainted {
+        layers.push(full_layer("Inpainted", inpainted));
+    }
+    if let Some(brush_layer) = &document.brush_layer {
+        layers.push(full_layer("Brush", brush_layer));
+    }
+
+    let mut text = Document {
+        text_blocks: document.text_blocks.clone(),
+        ..Default::default()
+    };
+    for index in 0..text.text_blocks.len() {
+        if text.text_blocks[index].rendered.is_none() {
+            renderer.render(&mut text, Some(index), TextShaderEffect::default())?;
+        }
+    }
+    let mut untranslated = 0;
+    for (index, block) in text.text_blocks.iter().enumerate() {
+        // blocks without a translation have nothing to typeset
+        let (Some(rendered), Some(translation)) = (&block.rendered, &block.translation) else {
+            untranslated += 1;
+            continue;
+        };
+        let name = Some(translation.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|text| !text.is_empty())
+            .unwrap_or_else(|| format!("Text {}", index + 1));
+        let style = block.style.clone().unwrap_or_default();
+        layers.push(PsdLayer {
+            name,
+            left: block.x as i32,
+            top: block.y as i32,
+            image: rendered.to_rgba8(),
+            visible: true,
+            text: Some(PsdText {
+                text: translation.clone(),
+                font: style.font_families.first().cloned().unwrap_or_default(),
+                font_size: style.font_size.unwrap_or(PSD_FONT_SIZE),
+                color: style.color,
+                width: block.width,
+                height: block.height,
+            }),
+        });
+    }
+    if untranslated > 0 {
+        tracing::warn!(
+            "{untranslated} untranslated text blocks of {} are not exported",
+            document.name
+        );
+    }
+
+    let composite = document
+        .rendered
+        .as_ref()
+        .unwrap_or(&document.image)
+        .to_rgba8();
+    Ok(write_psd(&layers, &composite)?)
+}
+
+/// Export the text of all documents as a translation script.
+pub async fn export_script(state: &AppState, options: ScriptOptions) -> Result<ExportedDocument> {
+    let guard = state.read().await;
+    let script = Script::from_documents(&guard.documents);
+    let bytes = write_script(&script, &options)?;
+    Ok(ExportedDocument {
+        filename: format!("script.{}", options.format.extension()),
+        bytes,
+    })
+}
+
+/// Fill translations from a script and render the updated documents again.
+pub async fn import_script(
+    state: &AppState,
+    renderer: &Arc<Renderer>,
+    bytes: &[u8],
+    options: ImportOptions,
+) -> Result<ImportReport> {
+    let script = read_script(bytes, options.format)?;
+    let mut documents = state.read().await.documents.clone();
+
+    let mut report = ImportReport::default();
+    let changed = apply_script(&mut documents, &script, &options, &mut report);
+
+    let mut updated = documents
+        .into_iter()
+        .filter(|document| changed.contains(&document.id))
+        .collect::<Vec<_>>();
+    for document in &mut updated {
+        renderer.render(document, None, TextShaderEffect::default())?;
+    }
+
+    for document in updated {
+        update_document(state, document).await?;
+    }
+    report.documents = changed;
+
+    Ok(report)
+}
+
+/// Assemble the project into a fixed-layout EPUB 3.
+pub async fn export_epub(state: &AppState, options: BookOptions) -> Result<ExportedDocument> {
+    let (documents, meta) = {
+        let guard = state.read().await;
+        (guard.documents.clone(), guard.meta.clone())
+    };
+    let title = book_title(&meta, &options);
+    let language = options
+        .language
+        .clone()
+        .or_else(get_locale)
+        .unwrap_or_else(|| "en".to_string());
+    let right_to_left = right_to_left(&meta, &options);
+    let filename = format!("{}.epub", sanitize_filename(&title));
+
+    let bytes = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let pages = book_pages(&documents, options.include_originals)?;
+        Ok(write_epub(&pages, &title, &language, right_to_left)?)
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(ExportedDocument { filename, bytes })
+}
+
+/// Assemble the project into a PDF with one page per image.
+pub async fn export_pdf(state: &AppState, options: BookOptions) -> Result<ExportedDocument> {
+    let (documents, meta) = {
+        let guard = state.read().await;
+        (guard.documents.clone(), guard.meta.clone())
+    };
+    let title = book_title(&meta, &options);
+    let right_to_left = right_to_left(&meta, &options);
+    let filename = format!("{}.pdf", sanitize_filename(&title));
+
+    let bytes = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let pages = book_pages(&documents, options.include_originals)?;
+        Ok(write_pdf(&pages, &title, right_to_left)?)
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(ExportedDocument { filename, bytes })
+}
+
+fn book_title(meta: &ProjectMeta, options: &BookOptions) -> String {
+    options
+        .title
+        .clone()
+        .filter(|title| !title.trim().is_empty())
+        .or_else(|| {
+            let xml = meta.comic_info.as_deref()?;
+            comic_info_field(xml, "Title").or_else(|| comic_info_field(xml, "Series"))
+        })
+        .unwrap_or_else(|| "Koharu".to_string())
+}
+
+fn right_to_left(meta: &ProjectMeta, options: &BookOptions) -> bool {
+    options.right_to_left.unwrap_or_else(|| {
+        meta.comic_info
+            .as_deref()
+            .and_then(|xml| comic_info_field(xml, "Manga"))
+            .is_none_or(|manga| manga == "YesAndRightToLeft")
+    })
+}
+
+/// Translated pages in project order, each preceded by its original when
+/// `include_originals` is set. Pages that were not rendered yet are kept as is.
+fn book_pages(documents: &[Document], include_originals: bool) -> Result<Vec<BookPage>> {
+    let pages = documents
+        .par_iter()
+        .map(|document| {
+            let mut pages = Vec::with_capacity(2);
+            if let Some(rendered) = &document.rendered {
+                if include_originals {
+                    pages.push(book_page(
+                        format!("{} (original)", document.name),
+                        &document.image,
+                    )?);
+                }
+                pages.push(book_page(document.name.clone(), rendered)?);
+            } else {
+                pages.push(book_page(document.name.clone(), &document.image)?);
+            }
+            Ok(pages)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(pages.into_iter().flatten().collect())
+}
+
+fn book_page(name: String, image: &SerializableDynamicImage) -> Result<BookPage> {
+    // JPEG has no alpha, both formats embed the encoded page as is
+    let rgb = image.to_rgb8();
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, BOOK_JPEG_QUALITY)
+        .encode_image(&rgb)
+        .map_err(|e| anyhow::anyhow!("Failed to encode image: {e}"))?;
+    Ok(BookPage {
+        name,
+        jpeg,
+        width: rgb.width(),
+        height: rgb.height(),
+    })
+}
+
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
 #[instrument(level = "info", skip_all)]
-pub async fn detect(state: &AppState, model: &Arc<ml::Model>, index: usize) -> Result<Document> {
+pub async fn detect(
+    state: &AppState,
+    model: &Arc<ml::Model>,
+    document: DocumentRef,
+) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let (text_blocks, segment) = model.detect_dialog(&snapshot.image).await?;
@@ -247,25 +683,18 @@ pub async fn detect(state: &AppState, model: &Arc<ml::Model>, index: usize) -> R
         }
     }
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 #[instrument(level = "info", skip_all)]
-pub async fn ocr(state: &AppState, model: &Arc<ml::Model>, index: usize) -> Result<Document> {
+pub async fn ocr(
+    state: &AppState,
+    model: &Arc<ml::Model>,
+    document: DocumentRef,
+) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let text_blocks = model.ocr(&snapshot.image, &snapshot.text_blocks).await?;
@@ -273,25 +702,18 @@ pub async fn ocr(state: &AppState, model: &Arc<ml::Model>, index: usize) -> Resu
     let mut updated = snapshot;
     updated.text_blocks = text_blocks;
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 #[instrument(level = "info", skip_all)]
-pub async fn inpaint(state: &AppState, model: &Arc<ml::Model>, index: usize) -> Result<Document> {
+pub async fn inpaint(
+    state: &AppState,
+    model: &Arc<ml::Model>,
+    document: DocumentRef,
+) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let segment = snapshot
@@ -332,30 +754,19 @@ pub async fn inpaint(state: &AppState, model: &Arc<ml::Model>, index: usize) ->
     let mut updated = snapshot;
     updated.inpainted = Some(inpainted);
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 #[instrument(level = "info", skip_all)]
 pub async fn update_inpaint_mask(
     state: &AppState,
-    index: usize,
+    document: DocumentRef,
     mask: Vec<u8>,
     region: Option<InpaintRegion>,
 ) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let update_image = image::load_from_memory(&mask)
@@ -424,30 +835,19 @@ pub async fn update_inpaint_mask(
     let mut updated = snapshot;
     updated.segment = Some(image::DynamicImage::ImageRgba8(base_mask).into());
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 #[instrument(level = "info", skip_all)]
 pub async fn update_brush_layer(
     state: &AppState,
-    index: usize,
+    document: DocumentRef,
     patch: Vec<u8>,
     region: InpaintRegion,
 ) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let (img_width, img_height) = (snapshot.width, snapshot.height);
@@ -489,30 +889,19 @@ pub async fn update_brush_layer(
     let mut updated = snapshot;
     updated.brush_layer = Some(image::DynamicImage::ImageRgba8(brush_layer).into());
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 #[instrument(level = "info", skip_all)]
 pub async fn inpaint_partial(
     state: &AppState,
     model: &Arc<ml::Model>,
-    index: usize,
+    document: DocumentRef,
     region: InpaintRegion,
 ) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let mask_image = snapshot
@@ -552,8 +941,9 @@ pub async fn inpaint_partial(
     }
 
     let image_crop =
-        SerializableDynamicImage(snapshot.image.crop_imm(x0, y0, crop_width, crop_height));
-    let mask_crop = SerializableDynamicImage(mask_image.crop_imm(x0, y0, crop_width, crop_height));
+        SerializableDynamicImage::from(snapshot.image.crop_imm(x0, y0, crop_width, crop_height));
+    let mask_crop =
+        SerializableDynamicImage::from(mask_image.crop_imm(x0, y0, crop_width, crop_height));
 
     let inpainted_crop = model.inpaint(&image_crop, &mask_crop).await?;
 
@@ -582,31 +972,20 @@ pub async fn inpaint_partial(
     let mut updated = snapshot;
     updated.inpainted = Some(image::DynamicImage::ImageRgba8(stitched).into());
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 #[instrument(level = "info", skip_all)]
 pub async fn render(
     state: &AppState,
     renderer: &Arc<Renderer>,
-    index: usize,
+    document: DocumentRef,
     text_block_index: Option<usize>,
     shader_effect: Option<TextShaderEffect>,
 ) -> Result<Document> {
     let snapshot = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        guard.document(&document)?.clone()
     };
 
     let mut updated = snapshot;
@@ -616,30 +995,29 @@ pub async fn render(
         shader_effect.unwrap_or_default(),
     )?;
 
-    let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
-
-    Ok(document.clone())
+    Ok(update_document(state, updated).await?)
 }
 
 pub async fn update_text_blocks(
     state: &AppState,
-    index: usize,
+    document: DocumentRef,
     text_blocks: Vec<TextBlock>,
 ) -> Result<Document> {
-    let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let mut guard = state.write().await;
+    let mut updated = guard.document(&document)?.clone();
+    updated.text_blocks = text_blocks;
+
+    Ok(guard.update_document(updated)?)
+}
 
-    document.text_blocks = text_blocks;
+pub async fn undo(state: &AppState, document: DocumentRef) -> Result<Document> {
+    let mut guard = state.write().await;
+    Ok(guard.undo(&document)?)
+}
 
-    Ok(document.clone())
+pub async fn redo(state: &AppState, document: DocumentRef) -> Result<Document> {
+    let mut guard = state.write().await;
+    Ok(guard.redo(&document)?)
 }
 
 pub fn list_font_families(renderer: &Arc<Renderer>) -> Result<Vec<String>> {
@@ -672,16 +1050,42 @@ pub fn llm_list(model: &Arc<llm::Model>) -> Vec<llm::ModelInfo> {
         ModelId::HunyuanMT7B => 500 / non_zh_en_locale_factor,
     });
 
-    models.into_iter().map(llm::ModelInfo::new).collect()
+    // configured translators are set up on purpose, so they come first
+    model
+        .translators()
+        .iter()
+        .map(llm::TranslatorConfig::info)
+        .chain(models.into_iter().map(llm::ModelInfo::new))
+        .collect()
 }
 
 #[instrument(level = "info", skip_all)]
 pub async fn llm_load(model: &Arc<llm::Model>, id: String) -> Result<()> {
+    if let Some(config) = model.translator(&id) {
+        model.load_translator(&config).await?;
+        return Ok(());
+    }
     let id = ModelId::from_str(&id)?;
     model.load(id).await;
     Ok(())
 }
 
+pub fn llm_translators(model: &Arc<llm::Model>) -> Vec<llm::TranslatorSummary> {
+    model
+        .translators()
+        .iter()
+        .map(llm::TranslatorConfig::redacted)
+        .collect()
+}
+
+pub fn llm_add_translator(model: &Arc<llm::Model>, config: llm::TranslatorConfig) -> Result<()> {
+    Ok(model.add_translator(config)?)
+}
+
+pub fn llm_remove_translator(model: &Arc<llm::Model>, id: &str) -> Result<bool> {
+    Ok(model.remove_translator(id)?)
+}
+
 pub async fn llm_offload(model: &Arc<llm::Model>) -> Result<()> {
     model.offload().await;
     Ok(())
@@ -691,21 +1095,92 @@ pub async fn llm_ready(model: &Arc<llm::Model>) -> Result<bool> {
     Ok(model.ready().await)
 }
 
-#[instrument(level = "info", skip_all)]
+/// Stop the translations clients requested on a document of the project, or on
+/// all of its documents. Their documents are left unchanged.
+pub async fn llm_cancel(state: &AppState, document: Option<DocumentRef>) -> Result<()> {
+    let guard = state.read().await;
+    let document_id = document
+        .map(|document| anyhow::Ok(guard.document(&document)?.id.clone()))
+        .transpose()?;
+    guard.cancel_translations(document_id.as_deref());
+    Ok(())
+}
+
+/// Wait for the LLM to be ready, loading the preferred model first if none is loaded.
+pub async fn llm_ensure_ready(model: &Arc<llm::Model>) -> Result<()> {
+    if matches!(
+        *model.get().await,
+        llm::State::Empty | llm::State::Failed(_)
+    ) {
+        let id = llm_list(model)
+            .first()
+            .map(|model| model.id.clone())
+            .ok_or_else(|| anyhow::anyhow!("No LLM model available"))?;
+        llm_load(model, id).await?;
+    }
+    model.wait_ready().await?;
+    Ok(())
+}
+
+/// Translate a document on behalf of a client, cancelled through [`llm_cancel`].
 pub async fn llm_generate(
     state: &AppState,
     model: &Arc<llm::Model>,
-    index: usize,
+    document: DocumentRef,
     text_block_index: Option<usize>,
     language: Option<String>,
+    context_pages: Option<usize>,
+    options: Option<GenerateOptions>,
 ) -> Result<Document> {
-    let snapshot = {
+    let cancelled = {
+        let mut guard = state.write().await;
+        let document_id = guard.document(&document)?.id.clone();
+        guard.translation_flag(&document_id)
+    };
+    llm_generate_cancellable(
+        state,
+        model,
+        document,
+        text_block_index,
+        language,
+        context_pages,
+        options,
+        &cancelled,
+    )
+    .await
+}
+
+/// Translate a document, stopping without changes once `cancelled` is set.
+#[instrument(level = "info", skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn llm_generate_cancellable(
+    state: &AppState,
+    model: &Arc<llm::Model>,
+    document: DocumentRef,
+    text_block_index: Option<usize>,
+    language: Option<String>,
+    context_pages: Option<usize>,
+    options: Option<GenerateOptions>,
+    cancelled: &AtomicBool,
+) -> Result<Document> {
+    let (snapshot, context) = {
         let guard = state.read().await;
-        guard
-            .documents
-            .get(index)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Document not found"))?
+        let options = options.or_else(|| guard.meta.generate_options.clone());
+        if let Some(options) = options.as_ref() {
+            options.validate()?;
+        }
+        let position = guard.position(&document)?;
+        let history = match context_pages {
+            Some(pages) => translation_context(&guard.documents, position, pages, text_block_index),
+            None => Vec::new(),
+        };
+        let context = TranslationContext {
+            history,
+            glossary: guard.meta.glossary.clone(),
+            options,
+            language: language.clone(),
+        };
+        (guard.documents[position].clone(), context)
     };
 
     if let Some(locale) = language.as_ref() {
@@ -713,6 +1188,14 @@ pub async fn llm_generate(
     }
 
     let mut updated = snapshot;
+    let document_id = updated.id.clone();
+    let on_progress = |index: usize, text: &str| {
+        model.report(llm::TranslationProgress {
+            document_id: document_id.clone(),
+            text_block_index: text_block_index.unwrap_or(0) + index,
+            text: text.to_string(),
+        })
+    };
 
     match text_block_index {
         Some(bi) => {
@@ -721,21 +1204,100 @@ pub async fn llm_generate(
                 .get_mut(bi)
                 .ok_or_else(|| anyhow::anyhow!("Text block not found"))?;
 
-            model.generate(text_block).await?;
+            model
+                .generate(text_block, &context, cancelled, on_progress)
+                .await?;
         }
         None => {
-            model.generate(&mut updated).await?;
+            model
+                .generate(&mut updated, &context, cancelled, on_progress)
+                .await?;
+        }
+    }
+
+    for issue in glossary::check(std::slice::from_ref(&updated), &context.glossary) {
+        if text_block_index.is_none_or(|index| index == issue.index) {
+            tracing::warn!(
+                "Block {} misses the glossary term {} -> {}",
+                issue.index + 1,
+                issue.source,
+                issue.target
+            );
         }
     }
 
+    Ok(update_document(state, updated).await?)
+}
+
+pub async fn get_glossary(state: &AppState) -> Vec<GlossaryEntry> {
+    state.read().await.meta.glossary.clone()
+}
+
+/// Replace the project glossary, returning it as stored.
+pub async fn set_glossary(state: &AppState, entries: Vec<GlossaryEntry>) -> Vec<GlossaryEntry> {
+    let mut guard = state.write().await;
+    guard.meta.glossary = glossary::normalize(entries);
+    guard.meta.glossary.clone()
+}
+
+/// Sampling settings saved with the project, `None` when it uses the translator's defaults.
+pub async fn get_generate_options(state: &AppState) -> Option<GenerateOptions> {
+    state.read().await.meta.generate_options.clone()
+}
+
+/// Replace the project's sampling settings, `None` goes back to the defaults.
+pub async fn set_generate_options(
+    state: &AppState,
+    options: Option<GenerateOptions>,
+) -> Result<Option<GenerateOptions>> {
+    if let Some(options) = options.as_ref() {
+        options.validate()?;
+    }
     let mut guard = state.write().await;
-    let document = guard
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    *document = updated.clone();
+    guard.meta.generate_options = options;
+    Ok(guard.meta.generate_options.clone())
+}
+
+/// Translated blocks missing a glossary term, of one document or the whole project.
+pub async fn check_glossary(
+    state: &AppState,
+    document: Option<DocumentRef>,
+) -> Result<Vec<GlossaryIssue>> {
+    let guard = state.read().await;
+    let documents = match document {
+        Some(document) => std::slice::from_ref(guard.document(&document)?),
+        None => guard.documents.as_slice(),
+    };
+    Ok(glossary::check(documents, &guard.meta.glossary))
+}
 
-    Ok(document.clone())
+/// Translated blocks of the `pages` documents before `position`, and of the
+/// blocks before `text_block_index` on the page itself, in reading order.
+fn translation_context(
+    documents: &[Document],
+    position: usize,
+    pages: usize,
+    text_block_index: Option<usize>,
+) -> Vec<TranslationPair> {
+    let previous = documents[position.saturating_sub(pages)..position]
+        .iter()
+        .flat_map(|document| document.text_blocks.iter());
+    let current = documents[position]
+        .text_blocks
+        .iter()
+        .take(text_block_index.unwrap_or(0));
+
+    previous
+        .chain(current)
+        .filter_map(|block| {
+            let source = block.text.as_deref()?.trim();
+            let translation = block.translation.as_deref()?.trim();
+            (!source.is_empty() && !translation.is_empty()).then(|| TranslationPair {
+                source: source.to_string(),
+                translation: translation.to_string(),
+            })
+        })
+        .collect()
 }
 
 fn encode_image(image: &SerializableDynamicImage, ext: &str) -> Result<Vec<u8>> {
@@ -743,7 +1305,6 @@ fn encode_image(image: &SerializableDynamicImage, ext: &str) -> Result<Vec<u8>>
     let mut cursor = Cursor::new(&mut buf);
     let format = ImageFormat::from_extension(ext).unwrap_or(ImageFormat::Jpeg);
     image
-        .0
         .write_to(&mut cursor, format)
         .map_err(|e| anyhow::anyhow!("Failed to encode image: {e}"))?;
     Ok(buf)
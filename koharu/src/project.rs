@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task::AbortHandle};
+use tracing::warn;
+
+use crate::{
+    autosave::Autosave,
+    jobs::Jobs,
+    state::{AppState, State},
+};
+
+pub const DEFAULT_PROJECT: &str = "default";
+/// Named projects, saved next to their journals so they can be restored.
+const REGISTRY_FILE: &str = "projects.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RegistryEntry {
+    id: String,
+    name: String,
+}
+
+/// An open project, a document list with its own undo history and autosave journal.
+pub struct Project {
+    pub id: String,
+    pub name: String,
+    pub state: AppState,
+    pub autosave: Arc<Autosave>,
+    autosave_task: std::sync::Mutex<Option<AbortHandle>>,
+}
+
+impl Project {
+    fn new(id: String, name: String, autosave_dir: PathBuf) -> Self {
+        let state = Arc::new(RwLock::new(State::default()));
+        let autosave = Arc::new(Autosave::new(autosave_dir, state.clone()));
+        Self {
+            id,
+            name,
+            state,
+            autosave,
+            autosave_task: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Start the autosave loop of this project.
+    pub fn spawn_autosave(&self) {
+        let mut task = self.autosave_task.lock().unwrap();
+        if task.is_none() {
+            *task = Some(self.autosave.spawn());
+        }
+    }
+
+    fn stop_autosave(&self) {
+        if let Some(task) = self.autosave_task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    pub async fn info(&self) -> ProjectInfo {
+        ProjectInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            documents: self.state.read().await.documents.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectInfo {
+    pub id: String,
+    pub name: String,
+    pub documents: usize,
+}
+
+/// Projects held by the server, the GUI and the unscoped API routes work on the
+/// default project.
+pub struct Projects {
+    autosave_root: PathBuf,
+    default: Arc<Project>,
+    projects: RwLock<HashMap<String, Arc<Project>>>,
+}
+
+impl Projects {
+    pub fn new(autosave_root: PathBuf) -> Self {
+        let default = Arc::new(Project::new(
+            DEFAULT_PROJECT.to_string(),
+            "Default".to_string(),
+            autosave_root.join(DEFAULT_PROJECT),
+        ));
+        let projects = HashMap::from([(DEFAULT_PROJECT.to_string(), default.clone())]);
+        Self {
+            autosave_root,
+            default,
+            projects: RwLock::new(projects),
+        }
+    }
+
+    pub fn default_project(&self) -> &Arc<Project> {
+        &self.default
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Arc<Project>> {
+        self.projects.read().await.get(id).cloned()
+    }
+
+    pub async fn list(&self) -> Vec<ProjectInfo> {
+        let projects = self
+            .projects
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut infos = Vec::with_capacity(projects.len());
+        for project in projects {
+            infos.push(project.info().await);
+        }
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+
+    /// Create an empty project with its own autosave journal.
+    pub async fn create(&self, name: String) -> Result<Arc<Project>> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let hash = blake3::hash(format!("{name}:{nanos}").as_bytes()).to_hex();
+        let id = hash[..12].to_string();
+
+        let project = Arc::new(Project::new(id.clone(), name, self.autosave_root.join(&id)));
+        let mut projects = self.projects.write().await;
+        projects.insert(id, project.clone());
+        self.save_registry(&projects).await?;
+        project.spawn_autosave();
+        Ok(project)
+    }
+
+    /// Close a project, cancel its jobs and discard its autosave journal, the
+    /// default project cannot be closed.
+    pub async fn remove(&self, id: &str, jobs: &Jobs) -> Result<()> {
+        if id == DEFAULT_PROJECT {
+            anyhow::bail!("The default project cannot be closed");
+        }
+        let project = {
+            let mut projects = self.projects.write().await;
+            let project = projects
+                .remove(id)
+                .ok_or_else(|| anyhow::anyhow!("Project {id} not found"))?;
+            self.save_registry(&projects).await?;
+            project
+        };
+        jobs.cancel_project(id);
+        project.stop_autosave();
+        project.autosave.reset().await?;
+        Ok(())
+    }
+
+    /// Reopen the named projects of a previous run with their journaled documents.
+    pub async fn restore(&self) -> Result<()> {
+        let bytes = match tokio::fs::read(self.autosave_root.join(REGISTRY_FILE)).await {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let entries: Vec<RegistryEntry> = serde_json::from_slice(&bytes)?;
+
+        let mut projects = self.projects.write().await;
+        for entry in entries {
+            let project = Arc::new(Project::new(
+                entry.id.clone(),
+                entry.name,
+                self.autosave_root.join(&entry.id),
+            ));
+            // saved sessions come back too, only the default project asks first
+            if project.autosave.has_journal() {
+                match project.autosave.recover().await {
+                    Ok(loaded) => project.state.write().await.set_documents(loaded),
+                    Err(err) => warn!(?err, "Failed to recover project {}", entry.id),
+                }
+            }
+            project.spawn_autosave();
+            projects.insert(entry.id, project);
+        }
+        Ok(())
+    }
+
+    async fn save_registry(&self, projects: &HashMap<String, Arc<Project>>) -> Result<()> {
+        let mut entries = projects
+            .values()
+            .filter(|project| project.id != DEFAULT_PROJECT)
+            .map(|project| RegistryEntry {
+                id: project.id.clone(),
+                name: project.name.clone(),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+
+        tokio::fs::create_dir_all(&self.autosave_root).await?;
+        let path = self.autosave_root.join(REGISTRY_FILE);
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(&entries)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Document, LoadedDocuments};
+
+    #[tokio::test]
+    async fn restores_named_projects() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("koharu-projects-{}", std::process::id()));
+        let jobs = Jobs::new();
+
+        let projects = Projects::new(root.clone());
+        let kept = projects.create("Kept".to_string()).await?;
+        let closed = projects.create("Closed".to_string()).await?;
+        projects.remove(&closed.id, &jobs).await?;
+        assert!(projects.remove(DEFAULT_PROJECT, &jobs).await.is_err());
+
+        // an edited and then saved project is journaled, but nothing to recover
+        let document = Document {
+            id: "page".to_string(),
+            image: image::DynamicImage::new_rgba8(2, 2).into(),
+            width: 2,
+            height: 2,
+            ..Default::default()
+        };
+        {
+            let mut state = kept.state.write().await;
+            state.set_documents(LoadedDocuments {
+                documents: vec![document.clone()],
+                ..Default::default()
+            });
+            state.update_document(document)?;
+        }
+        kept.autosave.mark_saved().await?;
+        assert!(kept.autosave.recoverable().is_none());
+
+        let restored = Projects::new(root.clone());
+        restored.restore().await?;
+        let names = restored
+            .list()
+            .await
+            .into_iter()
+            .map(|info| (info.id, info.name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                (DEFAULT_PROJECT.to_string(), "Default".to_string()),
+                (kept.id.clone(), "Kept".to_string()),
+            ]
+        );
+        assert!(restored.get(&closed.id).await.is_none());
+        let restored_kept = restored.get(&kept.id).await.expect("restored");
+        assert_eq!(restored_kept.info().await.documents, 1);
+
+        for project in [&kept, restored.get(&kept.id).await.as_ref().unwrap()] {
+            project.stop_autosave();
+        }
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,621 @@
+use anyhow::{Result, ensure};
+use image::RgbaImage;
+
+// Minimal writer for 8-bit RGB Photoshop documents with raster layers. Text layers
+// keep their rendered pixels and add a type tool block, Photoshop opens them as
+// editable paragraph text while GIMP and Krita show the pixels. Channel data is
+// PackBits compressed, which keeps the mostly transparent brush and text layers small.
+
+const MAX_DIMENSION: u32 = 30_000;
+const COMPRESSION_RLE: u16 = 1;
+/// Layer flag hiding the layer.
+const FLAG_HIDDEN: u8 = 0x02;
+
+/// A raster layer placed at `left`/`top` of the canvas.
+#[derive(Debug, Clone)]
+pub struct PsdLayer {
+    pub name: String,
+    pub left: i32,
+    pub top: i32,
+    pub image: RgbaImage,
+    pub visible: bool,
+    /// Makes the layer a type layer, its `image` is the text as rendered.
+    pub text: Option<PsdText>,
+}
+
+/// Editable text of a type layer, centred in a `width` by `height` box at the
+/// origin of the layer.
+#[derive(Debug, Clone)]
+pub struct PsdText {
+    pub text: String,
+    /// PostScript name of the font, editors substitute fonts they do not have.
+    pub font: String,
+    pub font_size: f32,
+    pub color: [u8; 4],
+    pub width: f32,
+    pub height: f32,
+}
+
+/// Write a PSD with `layers` ordered bottom to top, `composite` is the flattened
+/// image shown by viewers that do not read layers.
+pub fn write_psd(layers: &[PsdLayer], composite: &RgbaImage) -> Result<Vec<u8>> {
+    let (width, height) = composite.dimensions();
+    ensure!(
+        width > 0 && height > 0 && width <= MAX_DIMENSION && height <= MAX_DIMENSION,
+        "PSD dimensions must be between 1 and {MAX_DIMENSION} pixels, got {width}x{height}"
+    );
+
+    let mut out = Vec::new();
+
+    // header
+    out.extend_from_slice(b"8BPS");
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&[0; 6]);
+    out.extend_from_slice(&3u16.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&8u16.to_be_bytes());
+    out.extend_from_slice(&3u16.to_be_bytes()); // RGB
+
+    // color mode data and image resources
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(&0u32.to_be_bytes());
+
+    let layer_info = layer_info(layers)?;
+    let section_len = 4 + layer_info.len() + 4;
+    out.extend_from_slice(&u32::try_from(section_len)?.to_be_bytes());
+    out.extend_from_slice(&u32::try_from(layer_info.len())?.to_be_bytes());
+    out.extend_from_slice(&layer_info);
+    out.extend_from_slice(&0u32.to_be_bytes()); // global layer mask
+
+    // merged image, all row counts of all channels come before the data
+    let channels = (0..3)
+        .map(|channel| rle_channel(composite, channel))
+        .collect::<Vec<_>>();
+    out.extend_from_slice(&COMPRESSION_RLE.to_be_bytes());
+    for (counts, _) in &channels {
+        out.extend_from_slice(counts);
+    }
+    for (_, data) in &channels {
+        out.extend_from_slice(data);
+    }
+
+    Ok(out)
+}
+
+fn layer_info(layers: &[PsdLayer]) -> Result<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut channel_data = Vec::new();
+
+    for layer in layers {
+        let (width, height) = layer.image.dimensions();
+        let (top, left) = (layer.top, layer.left);
+        records.extend_from_slice(&top.to_be_bytes());
+        records.extend_from_slice(&left.to_be_bytes());
+        records.extend_from_slice(&(top + i32::try_from(height)?).to_be_bytes());
+        records.extend_from_slice(&(left + i32::try_from(width)?).to_be_bytes());
+
+        records.extend_from_slice(&4u16.to_be_bytes());
+        // alpha is channel -1, then red, green and blue
+        for (id, channel) in [(-1i16, 3usize), (0, 0), (1, 1), (2, 2)] {
+            let (counts, data) = rle_channel(&layer.image, channel);
+            records.extend_from_slice(&id.to_be_bytes());
+            records.extend_from_slice(&u32::try_from(2 + counts.len() + data.len())?.to_be_bytes());
+
+            channel_data.extend_from_slice(&COMPRESSION_RLE.to_be_bytes());
+            channel_data.extend_from_slice(&counts);
+            channel_data.extend_from_slice(&data);
+        }
+
+        records.extend_from_slice(b"8BIMnorm");
+        records.push(255); // opacity
+        records.push(0); // clipping
+        records.push(if layer.visible { 0 } else { FLAG_HIDDEN });
+        records.push(0);
+
+        let mut extra = Vec::new();
+        extra.extend_from_slice(&0u32.to_be_bytes()); // layer mask
+        extra.extend_from_slice(&0u32.to_be_bytes()); // blending ranges
+        extra.extend_from_slice(&pascal_name(&layer.name));
+        extra.extend_from_slice(&unicode_name(&layer.name)?);
+        if let Some(text) = &layer.text {
+            extra.extend_from_slice(&type_tool(layer, text)?);
+        }
+        records.extend_from_slice(&u32::try_from(extra.len())?.to_be_bytes());
+        records.extend_from_slice(&extra);
+    }
+
+    let mut info = Vec::new();
+    info.extend_from_slice(&i16::try_from(layers.len())?.to_be_bytes());
+    info.extend_from_slice(&records);
+    info.extend_from_slice(&channel_data);
+    if info.len() % 2 == 1 {
+        info.push(0);
+    }
+    Ok(info)
+}
+
+/// Legacy layer name, a Pascal string padded to a multiple of 4 bytes. Characters
+/// outside ASCII are replaced, the full name is in the `luni` block.
+fn pascal_name(name: &str) -> Vec<u8> {
+    let ascii = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c as u8
+            } else {
+                b'_'
+            }
+        })
+        .take(255)
+        .collect::<Vec<_>>();
+    let mut bytes = vec![ascii.len() as u8];
+    bytes.extend_from_slice(&ascii);
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+    bytes
+}
+
+fn unicode_name(name: &str) -> Result<Vec<u8>> {
+    let units = name.encode_utf16().collect::<Vec<_>>();
+    let mut data = Vec::with_capacity(4 + units.len() * 2);
+    data.extend_from_slice(&u32::try_from(units.len())?.to_be_bytes());
+    for unit in units {
+        data.extend_from_slice(&unit.to_be_bytes());
+    }
+    data.resize(data.len().div_ceil(4) * 4, 0);
+
+    let mut block = Vec::with_capacity(12 + data.len());
+    block.extend_from_slice(b"8BIMluni");
+    block.extend_from_slice(&u32::try_from(data.len())?.to_be_bytes());
+    block.extend_from_slice(&data);
+    Ok(block)
+}
+
+/// `TySh` block of a type layer: the transform placing the text, its descriptor
+/// with the engine data Photoshop lays the text out from, and an empty warp.
+fn type_tool(layer: &PsdLayer, text: &PsdText) -> Result<Vec<u8>> {
+    // Photoshop separates paragraphs with carriage returns
+    let content = text.text.replace("\r\n", "\r").replace('\n', "\r");
+    let (width, height) = (f64::from(text.width), f64::from(text.height));
+    let bounds = |class| {
+        Value::Object(
+            class,
+            vec![
+                ("Left", Value::Points(0.0)),
+                ("Top ", Value::Points(0.0)),
+                ("Rght", Value::Points(width)),
+                ("Btom", Value::Points(height)),
+            ],
+        )
+    };
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&1u16.to_be_bytes());
+    let transform = [
+        1.0,
+        0.0,
+        0.0,
+        1.0,
+        f64::from(layer.left),
+        f64::from(layer.top),
+    ];
+    for value in transform {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    data.extend_from_slice(&50u16.to_be_bytes()); // text version
+    data.extend_from_slice(&16u32.to_be_bytes()); // descriptor version
+    descriptor(
+        &mut data,
+        "TxLr",
+        &[
+            ("Txt ", Value::Text(&content)),
+            ("textGridding", Value::Enum("textGridding", "None")),
+            ("Ornt", Value::Enum("Ornt", "Hrzn")),
+            ("AntA", Value::Enum("Annt", "AnSm")),
+            ("bounds", bounds("bounds")),
+            ("boundingBox", bounds("boundingBox")),
+            ("TextIndex", Value::Long(0)),
+            (
+                "EngineData",
+                Value::Data(engine_data(text, &format!("{content}\r"))),
+            ),
+        ],
+    )?;
+    data.extend_from_slice(&1u16.to_be_bytes()); // warp version
+    data.extend_from_slice(&16u32.to_be_bytes());
+    descriptor(
+        &mut data,
+        "warp",
+        &[
+            ("warpStyle", Value::Enum("warpStyle", "warpNone")),
+            ("warpValue", Value::Double(0.0)),
+            ("warpPerspective", Value::Double(0.0)),
+            ("warpPerspectiveOther", Value::Double(0.0)),
+            ("warpRotate", Value::Enum("Ornt", "Hrzn")),
+        ],
+    )?;
+    let right = layer.left + width.ceil() as i32;
+    let bottom = layer.top + height.ceil() as i32;
+    for value in [layer.left, layer.top, right, bottom] {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    data.resize(data.len().div_ceil(4) * 4, 0);
+
+    let mut block = Vec::with_capacity(12 + data.len());
+    block.extend_from_slice(b"8BIMTySh");
+    block.extend_from_slice(&u32::try_from(data.len())?.to_be_bytes());
+    block.extend_from_slice(&data);
+    Ok(block)
+}
+
+/// Item of an action descriptor.
+enum Value<'a> {
+    Text(&'a str),
+    Enum(&'a str, &'a str),
+    Double(f64),
+    Long(i32),
+    Points(f64),
+    Object(&'a str, Vec<(&'a str, Value<'a>)>),
+    Data(Vec<u8>),
+}
+
+/// Action descriptor with an empty name, the structure Photoshop stores layer
+/// settings in.
+fn descriptor(out: &mut Vec<u8>, class: &str, items: &[(&str, Value)]) -> Result<()> {
+    descriptor_text(out, "")?;
+    descriptor_key(out, class)?;
+    out.extend_from_slice(&u32::try_from(items.len())?.to_be_bytes());
+    for (key, value) in items {
+        descriptor_key(out, key)?;
+        match value {
+            Value::Text(text) => {
+                out.extend_from_slice(b"TEXT");
+                descriptor_text(out, text)?;
+            }
+            Value::Enum(kind, value) => {
+                out.extend_from_slice(b"enum");
+                descriptor_key(out, kind)?;
+                descriptor_key(out, value)?;
+            }
+            Value::Double(value) => {
+                out.extend_from_slice(b"doub");
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Value::Long(value) => {
+                out.extend_from_slice(b"long");
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Value::Points(value) => {
+                out.extend_from_slice(b"UntF#Pnt");
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Value::Object(class, items) => {
+                out.extend_from_slice(b"Objc");
+                descriptor(out, class, items)?;
+            }
+            Value::Data(data) => {
+                out.extend_from_slice(b"tdta");
+                out.extend_from_slice(&u32::try_from(data.len())?.to_be_bytes());
+                out.extend_from_slice(data);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Four character keys are written with a zero length, longer ones with theirs.
+fn descriptor_key(out: &mut Vec<u8>, key: &str) -> Result<()> {
+    let len = if key.len() == 4 { 0 } else { key.len() };
+    out.extend_from_slice(&u32::try_from(len)?.to_be_bytes());
+    out.extend_from_slice(key.as_bytes());
+    Ok(())
+}
+
+/// UTF-16 string with its length, including the terminating null.
+fn descriptor_text(out: &mut Vec<u8>, text: &str) -> Result<()> {
+    let units = text.encode_utf16().chain([0]).collect::<Vec<_>>();
+    out.extend_from_slice(&u32::try_from(units.len())?.to_be_bytes());
+    for unit in units {
+        out.extend_from_slice(&unit.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Engine data of a type layer, a PostScript-like dictionary with one paragraph
+/// and one style run over all of `content`.
+fn engine_data(text: &PsdText, content: &str) -> Vec<u8> {
+    let length = content.encode_utf16().count();
+    let [red, green, blue, alpha] = text.color.map(|value| f32::from(value) / 255.0);
+    let style = format!(
+        "<< /Font 0 /FontSize {:.3} /AutoLeading true /FillColor << /Type 1 /Values [ {alpha:.3} {red:.3} {green:.3} {blue:.3} ] >> >>",
+        text.font_size
+    );
+    // centred like the rendered text
+    let paragraph = "<< /Justification 2 >>";
+
+    let mut resources = Vec::new();
+    resources.extend_from_slice(b"<< /FontSet [ << /Name ");
+    engine_string(&mut resources, &text.font);
+    resources
+        .extend_from_slice(b" /Script 0 /FontType 0 /Synthetic 0 >> ] /StyleSheetSet [ << /Name ");
+    engine_string(&mut resources, "Normal RGB");
+    resources.extend_from_slice(
+        format!(" /StyleSheetData {style} >> ] /ParagraphSheetSet [ << /Name ").as_bytes(),
+    );
+    engine_string(&mut resources, "Normal RGB");
+    resources.extend_from_slice(
+        format!(" /DefaultStyleSheet 0 /Properties {paragraph} >> ] >>").as_bytes(),
+    );
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"<< /EngineDict << /Editor << /Text ");
+    engine_string(&mut out, content);
+    out.extend_from_slice(
+        format!(
+            " >> /ParagraphRun << /RunArray [ << /ParagraphSheet << /DefaultStyleSheet 0 /Properties {paragraph} >> >> ] /RunLengthArray [ {length} ] /IsJoinable 1 >> \
+             /StyleRun << /RunArray [ << /StyleSheet << /StyleSheetData {style} >> >> ] /RunLengthArray [ {length} ] /IsJoinable 2 >> \
+             /Rendered << /Version 1 /Shapes << /WritingDirection 0 /Children [ << /ShapeType 1 /Procession 0 /Lines << /WritingDirection 0 /Children [ ] >> \
+             /Cookie << /Photoshop << /ShapeType 1 /BoxBounds [ 0.0 0.0 {:.3} {:.3} ] /Base << /ShapeType 1 /TransformPoint0 [ 1.0 0.0 ] /TransformPoint1 [ 0.0 1.0 ] /TransformPoint2 [ 0.0 0.0 ] >> >> >> >> ] >> >> >> \
+             /ResourceDict ",
+            text.width, text.height
+        )
+        .as_bytes(),
+    );
+    out.extend_from_slice(&resources);
+    out.extend_from_slice(b" /DocumentResources ");
+    out.extend_from_slice(&resources);
+    out.extend_from_slice(b" >>");
+    out
+}
+
+/// Engine data string, UTF-16 with a byte order mark in parentheses. Parentheses
+/// and backslashes among the encoded bytes are escaped.
+fn engine_string(out: &mut Vec<u8>, text: &str) {
+    out.push(b'(');
+    let bytes = text.encode_utf16().flat_map(u16::to_be_bytes);
+    for byte in [0xFE, 0xFF].into_iter().chain(bytes) {
+        if matches!(byte, b'(' | b')' | b'\\') {
+            out.push(b'\\');
+        }
+        out.push(byte);
+    }
+    out.push(b')');
+}
+
+/// PackBits compress one channel row by row, returning the per-row byte counts
+/// and the packed data.
+fn rle_channel(image: &RgbaImage, channel: usize) -> (Vec<u8>, Vec<u8>) {
+    let (width, height) = image.dimensions();
+    let mut counts = Vec::with_capacity(height as usize * 2);
+    let mut data = Vec::new();
+    let mut row = Vec::with_capacity(width as usize);
+
+    for y in 0..height {
+        row.clear();
+        row.extend((0..width).map(|x| image.get_pixel(x, y).0[channel]));
+        let start = data.len();
+        packbits(&row, &mut data);
+        // a row of at most 30000 bytes never packs to more than 30235
+        counts.extend_from_slice(&((data.len() - start) as u16).to_be_bytes());
+    }
+    (counts, data)
+}
+
+fn packbits(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let mut run = 1;
+        while i + run < row.len() && run < 128 && row[i + run] == row[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push((1 - run as i16) as u8);
+            out.push(row[i]);
+            i += run;
+            continue;
+        }
+
+        let start = i;
+        while i < row.len() && i - start < 128 && (i + 1 >= row.len() || row[i] != row[i + 1]) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&row[start..i]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    fn unpackbits(mut data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let [header, rest @ ..] = data {
+            let header = *header as i8;
+            if header >= 0 {
+                let len = header as usize + 1;
+                out.extend_from_slice(&rest[..len]);
+                data = &rest[len..];
+            } else {
+                out.extend(std::iter::repeat_n(rest[0], (1 - header as isize) as usize));
+                data = &rest[1..];
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn packs_runs_and_literals() {
+        let mut out = Vec::new();
+        packbits(&[1, 1, 1, 2, 3], &mut out);
+        assert_eq!(out, [0xFE, 1, 0x01, 2, 3]);
+
+        let rows: [Vec<u8>; 5] = [
+            vec![7],
+            vec![0; 300],
+            (0..=255).collect(),
+            (0..400).map(|i| (i / 3) as u8).collect(),
+            (0..300)
+                .map(|i| if i % 50 < 25 { 9 } else { i as u8 })
+                .collect(),
+        ];
+        for row in rows {
+            let mut out = Vec::new();
+            packbits(&row, &mut out);
+            assert_eq!(unpackbits(&out), row);
+            // the worst case adds one header byte per 128 literals
+            assert!(out.len() <= row.len() + row.len().div_ceil(128));
+        }
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> usize {
+        u16::from_be_bytes([bytes[at], bytes[at + 1]]) as usize
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> usize {
+        u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn section_lengths_match() -> Result<()> {
+        let composite = RgbaImage::from_pixel(5, 3, Rgba([10, 20, 30, 255]));
+        let layers = [
+            PsdLayer {
+                name: "Original".to_string(),
+                left: 0,
+                top: 0,
+                image: composite.clone(),
+                visible: true,
+                text: None,
+            },
+            PsdLayer {
+                name: "こんにちは".to_string(),
+                left: 1,
+                top: 2,
+                image: RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 128])),
+                visible: false,
+                text: None,
+            },
+        ];
+        let psd = write_psd(&layers, &composite)?;
+
+        assert_eq!(&psd[..4], b"8BPS");
+        assert_eq!((u32_at(&psd, 14), u32_at(&psd, 18)), (3, 5));
+        // empty color mode data and image resources
+        assert_eq!((u32_at(&psd, 26), u32_at(&psd, 30)), (0, 0));
+
+        let section = 34;
+        let section_len = u32_at(&psd, section);
+        let info = section + 4;
+        let info_len = u32_at(&psd, info);
+        assert_eq!(section_len, 4 + info_len + 4);
+        assert_eq!(info_len % 2, 0);
+        assert_eq!(u16_at(&psd, info + 4), 2);
+
+        // walk the layer records, the channel data follows the last one
+        let mut at = info + 6;
+        let mut channel_lens = Vec::new();
+        for layer in &layers {
+            let (width, height) = layer.image.dimensions();
+            assert_eq!(
+                (
+                    u32_at(&psd, at + 8) - u32_at(&psd, at),
+                    u32_at(&psd, at + 12) - u32_at(&psd, at + 4)
+                ),
+                (height as usize, width as usize)
+            );
+            assert_eq!(u16_at(&psd, at + 16), 4);
+            at += 18;
+            for _ in 0..4 {
+                channel_lens.push((u32_at(&psd, at + 2), height as usize));
+                at += 6;
+            }
+            assert_eq!(&psd[at..at + 8], b"8BIMnorm");
+            at += 12;
+            at += 4 + u32_at(&psd, at);
+        }
+        for (len, height) in channel_lens {
+            assert_eq!(u16_at(&psd, at), COMPRESSION_RLE as usize);
+            let counts = (0..height)
+                .map(|row| u16_at(&psd, at + 2 + row * 2))
+                .sum::<usize>();
+            assert_eq!(len, 2 + height * 2 + counts);
+            at += len;
+        }
+        assert!(at <= info + 4 + info_len && info + 4 + info_len - at <= 1);
+
+        // merged image: compression, row counts of all channels, then the data
+        let merged = section + 4 + section_len;
+        assert_eq!(u16_at(&psd, merged), COMPRESSION_RLE as usize);
+        let counts = (0..9)
+            .map(|row| u16_at(&psd, merged + 2 + row * 2))
+            .sum::<usize>();
+        assert_eq!(psd.len(), merged + 2 + 18 + counts);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_type_layers() -> Result<()> {
+        let composite = RgbaImage::new(40, 30);
+        let layer = PsdLayer {
+            name: "Hi".to_string(),
+            left: 4,
+            top: 6,
+            image: RgbaImage::new(20, 10),
+            visible: true,
+            text: Some(PsdText {
+                text: "Hi (\\)\nthere".to_string(),
+                font: "ArialMT".to_string(),
+                font_size: 12.0,
+                color: [0, 0, 0, 255],
+                width: 20.0,
+                height: 10.0,
+            }),
+        };
+        let psd = write_psd(std::slice::from_ref(&layer), &composite)?;
+
+        let at = psd
+            .windows(8)
+            .position(|window| window == b"8BIMTySh")
+            .expect("type tool block")
+            + 8;
+        let len = u32_at(&psd, at);
+        assert_eq!(len % 4, 0);
+        let data = &psd[at + 4..at + 4 + len];
+        assert_eq!(u16_at(data, 0), 1);
+        let transform = (0..6)
+            .map(|index| {
+                f64::from_be_bytes(data[2 + index * 8..10 + index * 8].try_into().unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(transform, [1.0, 0.0, 0.0, 1.0, 4.0, 6.0]);
+        assert_eq!((u16_at(data, 50), u32_at(data, 52)), (50, 16));
+
+        // empty name, class, item count, then the text as the first item
+        let descriptor = &data[56..];
+        assert_eq!(u32_at(descriptor, 0), 1);
+        assert_eq!(&descriptor[6..14], b"\0\0\0\0TxLr");
+        assert_eq!(u32_at(descriptor, 14), 8);
+        assert_eq!(&descriptor[18..30], b"\0\0\0\0Txt TEXT");
+        let units = "Hi (\\)\rthere"
+            .encode_utf16()
+            .chain([0])
+            .collect::<Vec<_>>();
+        assert_eq!(u32_at(descriptor, 30), units.len());
+        let text = (0..units.len())
+            .map(|index| u16_at(descriptor, 34 + index * 2) as u16)
+            .collect::<Vec<_>>();
+        assert_eq!(text, units);
+
+        let mut expected = Vec::new();
+        engine_string(&mut expected, "Hi (\\)\rthere\r");
+        assert!(
+            data.windows(expected.len())
+                .any(|window| window == expected)
+        );
+        assert_eq!(&expected[..14], b"(\xFE\xFF\0H\0i\0 \0\\(\0\\");
+        Ok(())
+    }
+}
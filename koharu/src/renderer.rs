@@ -78,12 +78,12 @@ impl Renderer {
                 };
                 imageops::overlay(
                     &mut rendered,
-                    &block.0,
+                    &**block,
                     text_block.x as i64,
                     text_block.y as i64,
                 );
             }
-            document.rendered = Some(SerializableDynamicImage(DynamicImage::ImageRgba8(rendered)));
+            document.rendered = Some(DynamicImage::ImageRgba8(rendered).into());
         }
         Ok(())
     }
@@ -160,7 +160,7 @@ impl Renderer {
             },
         )?;
 
-        text_block.rendered = Some(SerializableDynamicImage(DynamicImage::ImageRgba8(rendered)));
+        text_block.rendered = Some(DynamicImage::ImageRgba8(rendered).into());
         Ok(())
     }
 
@@ -0,0 +1,886 @@
+use std::{collections::HashMap, fmt::Write as _};
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use crate::{book::escape_xml, state::Document};
+
+// Translation scripts carry the text of every block without any image data, so
+// proofreaders can work on them in spreadsheets and CAT tools. Blocks are keyed
+// by document id and their index in `Document::text_blocks`.
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EnumString,
+    Display,
+    ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+#[strum(serialize_all = "camelCase")]
+pub enum ScriptFormat {
+    /// Structured JSON, one entry per page.
+    #[default]
+    Json,
+    /// One row per text block.
+    Csv,
+    /// XLIFF 1.2, one `<file>` per page.
+    Xliff12,
+    /// XLIFF 2.0, one `<file>` per page.
+    Xliff20,
+    /// Page and bubble numbered plain text with the translations only.
+    Txt,
+}
+
+impl ScriptFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Xliff12 | Self::Xliff20 => "xlf",
+            Self::Txt => "txt",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Xliff12 | Self::Xliff20 => "application/xliff+xml",
+            Self::Txt => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptOptions {
+    #[serde(default)]
+    pub format: ScriptFormat,
+    /// Source language written to XLIFF files, `ja` when omitted.
+    pub source_language: Option<String>,
+    /// Target language written to XLIFF files, `en` when omitted.
+    pub target_language: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Script {
+    pub pages: Vec<ScriptPage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScriptPage {
+    pub document_id: String,
+    pub name: String,
+    /// Page number, starting at 1.
+    pub page: usize,
+    pub blocks: Vec<ScriptBlock>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScriptBlock {
+    /// Index of the block in the document, starting at 0.
+    pub index: usize,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub text: Option<String>,
+    pub translation: Option<String>,
+}
+
+/// A CSV row, the flattened form of a block and its page.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptRow {
+    pub document_id: String,
+    pub page: usize,
+    pub name: String,
+    pub block: usize,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub text: Option<String>,
+    pub translation: Option<String>,
+}
+
+impl Script {
+    pub fn from_documents(documents: &[Document]) -> Self {
+        let pages = documents
+            .iter()
+            .enumerate()
+            .map(|(index, document)| ScriptPage {
+                document_id: document.id.clone(),
+                name: document.name.clone(),
+                page: index + 1,
+                blocks: document
+                    .text_blocks
+                    .iter()
+                    .enumerate()
+                    .map(|(index, block)| ScriptBlock {
+                        index,
+                        x: block.x,
+                        y: block.y,
+                        width: block.width,
+                        height: block.height,
+                        text: block.text.clone(),
+                        translation: block.translation.clone(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self { pages }
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = ScriptRow> + '_ {
+        self.pages.iter().flat_map(|page| {
+            page.blocks.iter().map(|block| ScriptRow {
+                document_id: page.document_id.clone(),
+                page: page.page,
+                name: page.name.clone(),
+                block: block.index,
+                x: block.x,
+                y: block.y,
+                width: block.width,
+                height: block.height,
+                text: block.text.clone(),
+                translation: block.translation.clone(),
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportOptions {
+    /// Format of the script, detected from its content when omitted.
+    pub format: Option<ScriptFormat>,
+    /// Replace the OCR text of each block with the source text of the script.
+    #[serde(default)]
+    pub update_text: bool,
+    /// Apply entries even when their source text no longer matches the block.
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    /// Number of blocks whose text or translation changed.
+    pub updated: usize,
+    /// Number of entries that matched a block without changing it.
+    pub unchanged: usize,
+    /// Entries whose document or block does not exist.
+    pub unmatched: Vec<ImportIssue>,
+    /// Entries that were skipped because they disagree with the project or with
+    /// an earlier entry for the same block.
+    pub conflicts: Vec<ImportIssue>,
+    /// Ids of the documents that were updated and rendered again.
+    pub documents: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportIssue {
+    pub document_id: String,
+    pub index: usize,
+    pub reason: String,
+}
+
+impl ImportIssue {
+    fn new(document_id: &str, index: usize, reason: impl Into<String>) -> Self {
+        Self {
+            document_id: document_id.to_string(),
+            index,
+            reason: reason.into(),
+        }
+    }
+}
+
+pub fn write_script(script: &Script, options: &ScriptOptions) -> Result<Vec<u8>> {
+    let source = options.source_language.as_deref().unwrap_or("ja");
+    let target = options.target_language.as_deref().unwrap_or("en");
+
+    match options.format {
+        ScriptFormat::Json => Ok(serde_json::to_vec_pretty(script)?),
+        ScriptFormat::Csv => write_csv(script),
+        ScriptFormat::Xliff12 => Ok(write_xliff12(script, source, target)?.into_bytes()),
+        ScriptFormat::Xliff20 => Ok(write_xliff20(script, source, target)?.into_bytes()),
+        ScriptFormat::Txt => Ok(write_txt(script)?.into_bytes()),
+    }
+}
+
+fn write_csv(script: &Script) -> Result<Vec<u8>> {
+    // the byte order mark makes spreadsheet apps pick UTF-8 instead of the
+    // system code page
+    let mut writer = csv::Writer::from_writer(b"\xEF\xBB\xBF".to_vec());
+    for row in script.rows() {
+        writer.serialize(row)?;
+    }
+    Ok(writer.into_inner().map_err(|err| err.into_error())?)
+}
+
+/// Identifier of a block in XLIFF 1.2, where unit ids span all files.
+fn xliff_unit_id(document_id: &str, index: usize) -> String {
+    format!("{document_id}:{index}")
+}
+
+fn write_xliff12(script: &Script, source: &str, target: &str) -> Result<String> {
+    let mut xml = String::new();
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        xml,
+        r#"<xliff version="1.2" xmlns="urn:oasis:names:tc:xliff:document:1.2">"#
+    )?;
+    for page in &script.pages {
+        writeln!(
+            xml,
+            r#"  <file original="{}" source-language="{}" target-language="{}" datatype="plaintext">"#,
+            escape_xml(&page.name),
+            escape_xml(source),
+            escape_xml(target)
+        )?;
+        writeln!(xml, "    <body>")?;
+        for block in &page.blocks {
+            writeln!(
+                xml,
+                r#"      <trans-unit id="{}">"#,
+                escape_xml(&xliff_unit_id(&page.document_id, block.index))
+            )?;
+            writeln!(
+                xml,
+                "        <source>{}</source>",
+                escape_xml(block.text.as_deref().unwrap_or_default())
+            )?;
+            if let Some(translation) = &block.translation {
+                writeln!(xml, "        <target>{}</target>", escape_xml(translation))?;
+            }
+            writeln!(xml, "        <note>{}</note>", location(page, block))?;
+            writeln!(xml, "      </trans-unit>")?;
+        }
+        writeln!(xml, "    </body>")?;
+        writeln!(xml, "  </file>")?;
+    }
+    writeln!(xml, "</xliff>")?;
+    Ok(xml)
+}
+
+fn write_xliff20(script: &Script, source: &str, target: &str) -> Result<String> {
+    let mut xml = String::new();
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        xml,
+        r#"<xliff xmlns="urn:oasis:names:tc:xliff:document:2.0" version="2.0" srcLang="{}" trgLang="{}">"#,
+        escape_xml(source),
+        escape_xml(target)
+    )?;
+    for page in &script.pages {
+        writeln!(
+            xml,
+            r#"  <file id="{}" original="{}">"#,
+            escape_xml(&page.document_id),
+            escape_xml(&page.name)
+        )?;
+        for block in &page.blocks {
+            writeln!(xml, r#"    <unit id="{}">"#, block.index)?;
+            writeln!(
+                xml,
+                r#"      <notes><note category="location">{}</note></notes>"#,
+                location(page, block)
+            )?;
+            writeln!(xml, "      <segment>")?;
+            writeln!(
+                xml,
+                "        <source>{}</source>",
+                escape_xml(block.text.as_deref().unwrap_or_default())
+            )?;
+            if let Some(translation) = &block.translation {
+                writeln!(xml, "        <target>{}</target>", escape_xml(translation))?;
+            }
+            writeln!(xml, "      </segment>")?;
+            writeln!(xml, "    </unit>")?;
+        }
+        writeln!(xml, "  </file>")?;
+    }
+    writeln!(xml, "</xliff>")?;
+    Ok(xml)
+}
+
+fn location(page: &ScriptPage, block: &ScriptBlock) -> String {
+    format!(
+        "page {}, bubble {}, x={} y={} width={} height={}",
+        page.page,
+        block.index + 1,
+        block.x.round(),
+        block.y.round(),
+        block.width.round(),
+        block.height.round()
+    )
+}
+
+/// Page header of the plain text format, followed by the document id.
+const TXT_PAGE_PREFIX: &str = "# Page ";
+
+// The plain text format numbers bubbles from 1, as typesetters count them:
+//
+//   # Page 1: 001.png [<document id>]
+//
+//   [1] First bubble
+//   spanning two lines
+//
+//   [2] Second bubble
+//
+// Bubbles are separated by a blank line, so a translation may contain lines of
+// its own that start with `[n]`.
+fn write_txt(script: &Script) -> Result<String> {
+    let mut txt = String::new();
+    for (index, page) in script.pages.iter().enumerate() {
+        if index > 0 {
+            txt.push('\n');
+        }
+        writeln!(
+            txt,
+            "{TXT_PAGE_PREFIX}{}: {} [{}]",
+            page.page, page.name, page.document_id
+        )?;
+        for block in &page.blocks {
+            let translation = block.translation.as_deref().unwrap_or_default();
+            let line = format!("[{}] {}", block.index + 1, translation.trim());
+            writeln!(txt, "\n{}", line.trim_end())?;
+        }
+    }
+    Ok(txt)
+}
+
+/// Parse a script, detecting the format from its content when `format` is `None`.
+pub fn read_script(bytes: &[u8], format: Option<ScriptFormat>) -> Result<Script> {
+    let content = std::str::from_utf8(bytes).context("Script is not valid UTF-8")?;
+    let content = content.trim_start_matches('\u{feff}');
+
+    match format.unwrap_or_else(|| detect_format(content)) {
+        ScriptFormat::Json => Ok(serde_json::from_str(content)?),
+        ScriptFormat::Csv => read_csv(content),
+        ScriptFormat::Xliff12 | ScriptFormat::Xliff20 => read_xliff(content),
+        ScriptFormat::Txt => read_txt(content),
+    }
+}
+
+fn detect_format(content: &str) -> ScriptFormat {
+    let content = content.trim_start();
+    if content.starts_with('{') {
+        ScriptFormat::Json
+    } else if content.starts_with('<') {
+        // both XLIFF versions share a reader
+        ScriptFormat::Xliff12
+    } else if content.starts_with(TXT_PAGE_PREFIX.trim_end()) {
+        ScriptFormat::Txt
+    } else {
+        ScriptFormat::Csv
+    }
+}
+
+fn read_csv(content: &str) -> Result<Script> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let mut script = Script::default();
+    for row in reader.deserialize() {
+        let row: ScriptRow = row?;
+        if script
+            .pages
+            .last()
+            .is_none_or(|page| page.document_id != row.document_id)
+        {
+            script.pages.push(ScriptPage {
+                document_id: row.document_id,
+                name: row.name,
+                page: row.page,
+                blocks: Vec::new(),
+            });
+        }
+        if let Some(page) = script.pages.last_mut() {
+            page.blocks.push(ScriptBlock {
+                index: row.block,
+                x: row.x,
+                y: row.y,
+                width: row.width,
+                height: row.height,
+                text: row.text,
+                translation: row.translation,
+            });
+        }
+    }
+    Ok(script)
+}
+
+fn read_xliff(content: &str) -> Result<Script> {
+    let xml = roxmltree::Document::parse(content)?;
+    let root = xml.root_element();
+    if root.tag_name().name() != "xliff" {
+        bail!("Not an XLIFF file");
+    }
+    let legacy = root.attribute("version").unwrap_or("1.2").starts_with('1');
+
+    let mut script = Script::default();
+    for (index, file) in root
+        .children()
+        .filter(|node| node.tag_name().name() == "file")
+        .enumerate()
+    {
+        let mut page = ScriptPage {
+            document_id: file.attribute("id").unwrap_or_default().to_string(),
+            name: file.attribute("original").unwrap_or_default().to_string(),
+            page: index + 1,
+            blocks: Vec::new(),
+        };
+        let unit_tag = if legacy { "trans-unit" } else { "unit" };
+        for unit in file
+            .descendants()
+            .filter(|node| node.tag_name().name() == unit_tag)
+        {
+            let id = unit
+                .attribute("id")
+                .ok_or_else(|| anyhow!("XLIFF unit without an id"))?;
+            // 1.2 units carry the document id, 2.0 units are scoped by their file
+            let block = if legacy {
+                let (document_id, block) = id
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow!("Unexpected XLIFF unit id `{id}`"))?;
+                page.document_id = document_id.to_string();
+                block
+            } else {
+                id
+            };
+            page.blocks.push(ScriptBlock {
+                index: block
+                    .parse()
+                    .with_context(|| format!("Unexpected XLIFF unit id `{id}`"))?,
+                text: element_text(unit, "source"),
+                translation: element_text(unit, "target"),
+                ..Default::default()
+            });
+        }
+        script.pages.push(page);
+    }
+    Ok(script)
+}
+
+/// Text of all `name` elements below `node`, segments are joined in order.
+fn element_text(node: roxmltree::Node, name: &str) -> Option<String> {
+    let parts = node
+        .descendants()
+        .filter(|child| child.tag_name().name() == name)
+        .map(|child| {
+            child
+                .descendants()
+                .filter(|text| text.is_text())
+                .filter_map(|text| text.text())
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
+    (!parts.is_empty()).then(|| parts.concat())
+}
+
+fn read_txt(content: &str) -> Result<Script> {
+    let mut script = Script::default();
+    let mut current: Option<ScriptBlock> = None;
+
+    let flush = |page: Option<&mut ScriptPage>, block: Option<ScriptBlock>| {
+        if let (Some(page), Some(mut block)) = (page, block) {
+            block.translation = block.translation.map(|text| text.trim_end().to_string());
+            page.blocks.push(block);
+        }
+    };
+
+    let mut separated = true;
+    for (number, line) in content.lines().enumerate() {
+        let after_blank = std::mem::replace(&mut separated, line.trim().is_empty());
+        if let Some(header) = line.strip_prefix(TXT_PAGE_PREFIX) {
+            separated = true;
+            flush(script.pages.last_mut(), current.take());
+            let (page, rest) = header.split_once(':').unwrap_or((header, ""));
+            let (name, document_id) = rest
+                .rsplit_once('[')
+                .and_then(|(name, id)| Some((name, id.trim_end().strip_suffix(']')?)))
+                .ok_or_else(|| anyhow!("Missing document id on line {}", number + 1))?;
+            script.pages.push(ScriptPage {
+                document_id: document_id.to_string(),
+                name: name.trim().to_string(),
+                page: page.trim().parse().unwrap_or(script.pages.len() + 1),
+                ..Default::default()
+            });
+        } else if let Some((bubble, text)) = parse_bubble(line).filter(|_| after_blank) {
+            if script.pages.is_empty() {
+                bail!("Bubble on line {} comes before any page header", number + 1);
+            }
+            flush(script.pages.last_mut(), current.take());
+            current = Some(ScriptBlock {
+                index: bubble - 1,
+                translation: Some(text.to_string()),
+                ..Default::default()
+            });
+        } else if let Some(block) = current.as_mut()
+            && let Some(translation) = block.translation.as_mut()
+        {
+            translation.push('\n');
+            translation.push_str(line);
+        }
+    }
+    flush(script.pages.last_mut(), current.take());
+
+    Ok(script)
+}
+
+/// `[3] text` starts bubble 3.
+fn parse_bubble(line: &str) -> Option<(usize, &str)> {
+    let (number, text) = line.strip_prefix('[')?.split_once(']')?;
+    let number = number.trim().parse().ok().filter(|&number| number > 0)?;
+    Some((number, text.strip_prefix(' ').unwrap_or(text)))
+}
+
+/// Fill the blocks of `documents` from `script` and return the ids of the
+/// documents that changed. Blank translations are skipped instead of clearing
+/// the block.
+pub fn apply_script(
+    documents: &mut [Document],
+    script: &Script,
+    options: &ImportOptions,
+    report: &mut ImportReport,
+) -> Vec<String> {
+    let positions = documents
+        .iter()
+        .enumerate()
+        .map(|(index, document)| (document.id.clone(), index))
+        .collect::<HashMap<_, _>>();
+    let mut seen = HashMap::new();
+    let mut changed = Vec::new();
+
+    for page in &script.pages {
+        let Some(&position) = positions.get(&page.document_id) else {
+            report.unmatched.extend(page.blocks.iter().map(|block| {
+                ImportIssue::new(&page.document_id, block.index, "Document not found")
+            }));
+            continue;
+        };
+        let document = &mut documents[position];
+
+        for entry in &page.blocks {
+            let Some(block) = document.text_blocks.get_mut(entry.index) else {
+                report.unmatched.push(ImportIssue::new(
+                    &page.document_id,
+                    entry.index,
+                    "Text block not found",
+                ));
+                continue;
+            };
+            let translation = entry
+                .translation
+                .as_deref()
+                .map(str::trim)
+                .filter(|text| !text.is_empty());
+            let source = entry
+                .text
+                .as_deref()
+                .map(str::trim)
+                .filter(|text| !text.is_empty());
+
+            let key = (page.document_id.as_str(), entry.index);
+            if let Some(previous) = seen.insert(key, translation) {
+                if previous != translation {
+                    report.conflicts.push(ImportIssue::new(
+                        &page.document_id,
+                        entry.index,
+                        "Block appears more than once with different translations",
+                    ));
+                }
+                continue;
+            }
+
+            let current = block.text.as_deref().map(str::trim).unwrap_or_default();
+            if !options.update_text
+                && !options.force
+                && let Some(source) = source
+                && source != current
+            {
+                report.conflicts.push(ImportIssue::new(
+                    &page.document_id,
+                    entry.index,
+                    "Source text differs from the text of the block",
+                ));
+                continue;
+            }
+
+            let mut updated = false;
+            if options.update_text
+                && let Some(source) = source
+                && block.text.as_deref() != Some(source)
+            {
+                block.text = Some(source.to_string());
+                updated = true;
+            }
+            if let Some(translation) = translation
+                && block.translation.as_deref() != Some(translation)
+            {
+                block.translation = Some(translation.to_string());
+                updated = true;
+            }
+
+            if updated {
+                report.updated += 1;
+                if !changed.contains(&document.id) {
+                    changed.push(document.id.clone());
+                }
+            } else {
+                report.unchanged += 1;
+            }
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::TextBlock;
+
+    fn script() -> Script {
+        let block = |index: usize, text: &str, translation: Option<&str>| ScriptBlock {
+            index,
+            x: 10.0 * index as f32,
+            y: 20.5,
+            width: 100.0,
+            height: 48.25,
+            text: Some(text.to_string()),
+            translation: translation.map(str::to_string),
+        };
+        Script {
+            pages: vec![
+                ScriptPage {
+                    document_id: "3f2a".to_string(),
+                    name: "001".to_string(),
+                    page: 1,
+                    blocks: vec![
+                        block(0, "こんにちは", Some("Hello")),
+                        block(1, "「あ」<と>&", Some("\"Ah\" <and> &\nsecond line")),
+                    ],
+                },
+                ScriptPage {
+                    document_id: "9c1e".to_string(),
+                    name: "002, extra".to_string(),
+                    page: 2,
+                    blocks: vec![block(0, "さようなら", Some("Goodbye"))],
+                },
+            ],
+        }
+    }
+
+    fn round_trip(script: &Script, format: ScriptFormat) -> Result<Script> {
+        let options = ScriptOptions {
+            format,
+            ..Default::default()
+        };
+        let bytes = write_script(script, &options)?;
+        // the format is detected from the content on import
+        read_script(&bytes, None)
+    }
+
+    /// XLIFF keeps the position only in a note for translators.
+    fn without_geometry(mut script: Script) -> Script {
+        for block in script.pages.iter_mut().flat_map(|page| &mut page.blocks) {
+            *block = ScriptBlock {
+                index: block.index,
+                text: block.text.take(),
+                translation: block.translation.take(),
+                ..Default::default()
+            };
+        }
+        script
+    }
+
+    #[test]
+    fn round_trips_json() -> Result<()> {
+        assert_eq!(round_trip(&script(), ScriptFormat::Json)?, script());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_csv() -> Result<()> {
+        let mut script = script();
+        script.pages[1].blocks[0].translation = None;
+        assert_eq!(round_trip(&script, ScriptFormat::Csv)?, script);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_xliff12() -> Result<()> {
+        let mut script = script();
+        script.pages[1].blocks[0].translation = None;
+        assert_eq!(
+            round_trip(&script, ScriptFormat::Xliff12)?,
+            without_geometry(script)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_xliff20() -> Result<()> {
+        let mut script = script();
+        script.pages[1].blocks[0].translation = None;
+        assert_eq!(
+            round_trip(&script, ScriptFormat::Xliff20)?,
+            without_geometry(script)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_txt() -> Result<()> {
+        // plain text carries the translations only
+        let mut expected = without_geometry(script());
+        for block in expected.pages.iter_mut().flat_map(|page| &mut page.blocks) {
+            block.text = None;
+        }
+        assert_eq!(round_trip(&script(), ScriptFormat::Txt)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_bubble_markers_inside_translations() -> Result<()> {
+        let txt = "# Page 1: 001 [3f2a]\n[1] First\n[2] still the first\n\n[2] Second\n\n\n[3]\n";
+        let script = read_script(txt.as_bytes(), None)?;
+        let blocks = &script.pages[0].blocks;
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| (block.index, block.translation.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                (0, Some("First\n[2] still the first")),
+                (1, Some("Second")),
+                (2, Some("")),
+            ]
+        );
+        Ok(())
+    }
+
+    fn documents() -> Vec<Document> {
+        let block = |text: &str| TextBlock {
+            text: Some(text.to_string()),
+            ..Default::default()
+        };
+        vec![
+            Document {
+                id: "3f2a".to_string(),
+                text_blocks: vec![block("こんにちは"), block("「あ」<と>&")],
+                ..Default::default()
+            },
+            Document {
+                id: "9c1e".to_string(),
+                text_blocks: vec![block("さようなら")],
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn import(documents: &mut [Document], script: &Script) -> ImportReport {
+        let mut report = ImportReport::default();
+        report.documents = apply_script(documents, script, &ImportOptions::default(), &mut report);
+        report
+    }
+
+    fn issues(issues: &[ImportIssue]) -> Vec<(&str, usize)> {
+        issues
+            .iter()
+            .map(|issue| (issue.document_id.as_str(), issue.index))
+            .collect()
+    }
+
+    #[test]
+    fn applies_translations() {
+        let mut documents = documents();
+        let report = import(&mut documents, &script());
+        assert_eq!(report.updated, 3);
+        assert_eq!(report.documents, ["3f2a", "9c1e"]);
+        assert!(report.unmatched.is_empty() && report.conflicts.is_empty());
+        assert_eq!(
+            documents[1].text_blocks[0].translation.as_deref(),
+            Some("Goodbye")
+        );
+
+        // applying the same script again changes nothing
+        let report = import(&mut documents, &script());
+        assert_eq!((report.updated, report.unchanged), (0, 3));
+        assert!(report.documents.is_empty());
+    }
+
+    #[test]
+    fn reports_duplicates_only_when_they_differ() {
+        let mut script = script();
+        let page = &mut script.pages[0];
+        page.blocks.push(page.blocks[0].clone());
+        let mut differing = page.blocks[1].clone();
+        differing.translation = Some("Something else".to_string());
+        page.blocks.push(differing);
+
+        let mut documents = documents();
+        let report = import(&mut documents, &script);
+        assert_eq!(issues(&report.conflicts), [("3f2a", 1)]);
+        assert_eq!(report.updated, 3);
+        // the first entry of a block wins
+        assert_eq!(
+            documents[0].text_blocks[1].translation.as_deref(),
+            Some("\"Ah\" <and> &\nsecond line")
+        );
+    }
+
+    #[test]
+    fn reports_changed_source_text() {
+        let mut script = script();
+        script.pages[1].blocks[0].text = Some("またね".to_string());
+
+        let mut documents = documents();
+        let report = import(&mut documents, &script);
+        assert_eq!(issues(&report.conflicts), [("9c1e", 0)]);
+        assert_eq!(documents[1].text_blocks[0].translation, None);
+
+        // forced imports apply it anyway
+        let mut report = ImportReport::default();
+        let options = ImportOptions {
+            force: true,
+            ..Default::default()
+        };
+        apply_script(&mut documents, &script, &options, &mut report);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(
+            documents[1].text_blocks[0].translation.as_deref(),
+            Some("Goodbye")
+        );
+    }
+
+    #[test]
+    fn reports_unknown_documents_and_blocks() {
+        let mut script = script();
+        script.pages[0].document_id = "missing".to_string();
+        script.pages[1].blocks[0].index = 5;
+
+        let mut documents = documents();
+        let report = import(&mut documents, &script);
+        assert_eq!(
+            issues(&report.unmatched),
+            [("missing", 0), ("missing", 1), ("9c1e", 5)]
+        );
+        assert_eq!(report.updated, 0);
+        assert!(report.documents.is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+use std::{cmp::Ordering, io::Cursor, path::Path, time::SystemTime};
+
+use clap::ValueEnum;
+use image::{ImageDecoder, ImageReader};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+/// Compare names the way people number pages, so `page2` sorts before `page10`.
+/// Digit runs are compared by value, everything else case-insensitively.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    // case and leading zeros only decide between otherwise equal names
+    let mut tiebreak = Ordering::Equal;
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return tiebreak,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let (vx, vy) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = vx.len().cmp(&vy.len()).then_with(|| vx.cmp(vy));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                tiebreak = tiebreak.then(x.len().cmp(&y.len()));
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                tiebreak = tiebreak.then(x.cmp(&y));
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        number.push(c);
+    }
+    number
+}
+
+/// Key pages loaded together are sorted by.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EnumString,
+    Display,
+    ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+#[strum(serialize_all = "camelCase")]
+pub enum SortBy {
+    /// File name, with page numbers compared by value.
+    #[default]
+    Natural,
+    /// File modification time, oldest first.
+    Modified,
+    /// Capture date from the EXIF metadata, oldest first.
+    Exif,
+}
+
+/// How to order pages when opening or adding them. Pages listed in `order` come
+/// first in the given order, the rest follow sorted by `sort_by`.
+#[derive(Debug, Clone, Default)]
+pub struct PageOrder {
+    pub sort_by: SortBy,
+    pub order: Vec<String>,
+}
+
+impl PageOrder {
+    /// Parse an order file, one file name per line. Blank lines and lines starting
+    /// with `#` are ignored.
+    pub fn parse_order(content: &str) -> Vec<String> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                Path::new(line)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| line.to_string())
+            })
+            .collect()
+    }
+
+    pub fn read_order_file(path: &Path) -> anyhow::Result<Vec<String>> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(Self::parse_order(&content))
+    }
+
+    pub fn sort<T>(&self, pages: &mut [(PageKey, T)]) {
+        pages.sort_by(|(a, _), (b, _)| self.cmp(a, b));
+    }
+
+    fn cmp(&self, a: &PageKey, b: &PageKey) -> Ordering {
+        let listed = |key: &PageKey| {
+            self.order
+                .iter()
+                .position(|name| name == &key.name || name == &key.stem)
+        };
+        let by_order = match (listed(a), listed(b)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        by_order
+            .then_with(|| match self.sort_by {
+                SortBy::Natural => Ordering::Equal,
+                SortBy::Modified => known_first(&a.modified, &b.modified),
+                SortBy::Exif => known_first(&a.taken, &b.taken),
+            })
+            .then_with(|| natural_cmp(&a.path, &b.path))
+    }
+}
+
+/// Pages without the key sort after the ones that have it.
+fn known_first<T: Ord>(a: &Option<T>, b: &Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Sort key of one loaded file, computed before the file is decoded.
+#[derive(Debug, Clone)]
+pub struct PageKey {
+    /// Full path, so pages in separate folders stay grouped by folder.
+    path: String,
+    name: String,
+    stem: String,
+    modified: Option<SystemTime>,
+    taken: Option<String>,
+}
+
+impl PageKey {
+    pub fn new(path: &Path, modified: Option<SystemTime>, bytes: &[u8], sort_by: SortBy) -> Self {
+        let name = |part: Option<&std::ffi::OsStr>| {
+            part.map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        Self {
+            path: path.to_string_lossy().to_string(),
+            name: name(path.file_name()),
+            stem: name(path.file_stem()),
+            modified,
+            // only pay for reading the metadata when it is used
+            taken: (sort_by == SortBy::Exif)
+                .then(|| exif_date(bytes))
+                .flatten(),
+        }
+    }
+}
+
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+/// `DateTimeOriginal` of an image, falling back to `DateTime`. Both use the
+/// `YYYY:MM:DD HH:MM:SS` format, which sorts correctly as a string.
+fn exif_date(bytes: &[u8]) -> Option<String> {
+    let exif = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?
+        .exif_metadata()
+        .ok()??;
+    tiff_date(exif.strip_prefix(b"Exif\0\0").unwrap_or(&exif))
+}
+
+fn tiff_date(data: &[u8]) -> Option<String> {
+    let tiff = Tiff::new(data)?;
+    let ifd0 = tiff.u32(4)? as usize;
+
+    tiff.find(ifd0, TAG_EXIF_IFD)
+        .and_then(|entry| tiff.find(tiff.u32(entry + 8)? as usize, TAG_DATE_TIME_ORIGINAL))
+        .and_then(|entry| tiff.ascii(entry))
+        .or_else(|| tiff.ascii(tiff.find(ifd0, TAG_DATE_TIME)?))
+}
+
+/// Just enough of a TIFF reader to pull ASCII tags out of an EXIF block.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Offset of the entry with `tag` in the IFD at `ifd`.
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16(ifd)? as usize;
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    fn ascii(&self, entry: usize) -> Option<String> {
+        let count = self.u32(entry + 4)? as usize;
+        let offset = if count <= 4 {
+            entry + 8
+        } else {
+            self.u32(entry + 8)? as usize
+        };
+        let raw = self.data.get(offset..offset.checked_add(count)?)?;
+        let value = std::str::from_utf8(raw).ok()?.trim_end_matches('\0').trim();
+        (!value.is_empty()).then(|| value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A TIFF block with `DateTime` in IFD0 and `DateTimeOriginal` in the EXIF IFD.
+    fn tiff(big_endian: bool, date_time: Option<&str>, original: Option<&str>) -> Vec<u8> {
+        let u16 = |value: u16| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let u32 = |value: u32| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let ifd0_entries = date_time.is_some() as u32 + original.is_some() as u32;
+        let exif_ifd = 8 + 2 + 12 * ifd0_entries + 4;
+        let mut strings = exif_ifd + original.map_or(0, |_| 2 + 12 + 4);
+        let mut ascii = |data: &mut Vec<u8>, tag: u16, value: &str| {
+            data.extend(u16(tag));
+            data.extend(u16(2));
+            data.extend(u32(value.len() as u32 + 1));
+            data.extend(u32(strings));
+            strings += value.len() as u32 + 1;
+        };
+
+        let mut data = match big_endian {
+            true => b"MM\0*".to_vec(),
+            false => b"II*\0".to_vec(),
+        };
+        data.extend(u32(8));
+        data.extend(u16(ifd0_entries as u16));
+        if let Some(value) = date_time {
+            ascii(&mut data, TAG_DATE_TIME, value);
+        }
+        if original.is_some() {
+            data.extend(u16(TAG_EXIF_IFD));
+            data.extend(u16(4));
+            data.extend(u32(1));
+            data.extend(u32(exif_ifd));
+        }
+        data.extend(u32(0));
+        if let Some(value) = original {
+            data.extend(u16(1));
+            ascii(&mut data, TAG_DATE_TIME_ORIGINAL, value);
+            data.extend(u32(0));
+        }
+        for value in [date_time, original].into_iter().flatten() {
+            data.extend(value.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    fn key(path: &str, modified: Option<u64>, taken: Option<&str>) -> PageKey {
+        let mut key = PageKey::new(
+            Path::new(path),
+            modified.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            &[],
+            SortBy::Natural,
+        );
+        key.taken = taken.map(str::to_string);
+        key
+    }
+
+    fn sorted(order: &PageOrder, keys: Vec<PageKey>) -> Vec<String> {
+        let mut pages = keys.into_iter().map(|key| (key, ())).collect::<Vec<_>>();
+        order.sort(&mut pages);
+        pages.into_iter().map(|(key, _)| key.name).collect()
+    }
+
+    fn assert_sorted(names: &[&str]) {
+        for (i, a) in names.iter().enumerate() {
+            for (j, b) in names.iter().enumerate() {
+                assert_eq!(natural_cmp(a, b), i.cmp(&j), "{a} vs {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn compares_leading_zeros_by_value() {
+        assert_sorted(&["p0", "p1", "p01", "p001", "p2", "p10", "p010"]);
+        assert_sorted(&["p007", "p8", "p09", "p011"]);
+    }
+
+    #[test]
+    fn compares_each_digit_run() {
+        assert_sorted(&["ch2p9", "ch2p10", "ch10p1", "ch10p2"]);
+        assert_sorted(&["v1.9", "v1.10", "v2.0"]);
+        // longer than any integer type
+        assert_sorted(&["p99999999999999999999", "p100000000000000000000"]);
+    }
+
+    #[test]
+    fn compares_non_ascii_digits_as_text() {
+        // full-width digits are letters here, so `１０` sorts by its first character
+        assert_sorted(&["第１話", "第１０話", "第２話"]);
+        assert_sorted(&["p2", "p10", "p２"]);
+    }
+
+    #[test]
+    fn compares_equal_prefixes() {
+        assert_sorted(&["page", "Page1", "page1", "page1a", "page01b", "page2"]);
+        assert_eq!(natural_cmp("page", "page"), Ordering::Equal);
+        assert_eq!(natural_cmp("", "page"), Ordering::Less);
+    }
+
+    #[test]
+    fn reads_exif_dates() {
+        let original = "2024:05:01 10:00:00";
+        let date_time = "2024:06:01 12:30:00";
+        for big_endian in [false, true] {
+            assert_eq!(
+                tiff_date(&tiff(big_endian, Some(date_time), Some(original))).as_deref(),
+                Some(original)
+            );
+            assert_eq!(
+                tiff_date(&tiff(big_endian, Some(date_time), None)).as_deref(),
+                Some(date_time)
+            );
+            assert_eq!(tiff_date(&tiff(big_endian, None, None)), None);
+        }
+
+        let data = tiff(false, Some(date_time), Some(original));
+        // a cut off original falls back to the modification date
+        assert_eq!(
+            tiff_date(&data[..data.len() - 10]).as_deref(),
+            Some(date_time)
+        );
+        assert_eq!(tiff_date(b"not a tiff"), None);
+        assert_eq!(exif_date(b"not an image"), None);
+    }
+
+    #[test]
+    fn parses_order_files() {
+        let content = "# chapter 1\n\n  cover.jpg \nraws/p2.png\r\n#p9.png\n";
+        assert_eq!(PageOrder::parse_order(content), ["cover.jpg", "p2.png"]);
+    }
+
+    #[test]
+    fn sorts_pages() {
+        let keys = || {
+            vec![
+                key("b/p10.png", Some(1), Some("2024:01:03 00:00:00")),
+                key("b/p2.png", None, Some("2024:01:01 00:00:00")),
+                key("a/p3.png", Some(3), None),
+                key("b/p1.png", Some(2), Some("2024:01:02 00:00:00")),
+            ]
+        };
+        let order = |sort_by, order: &[&str]| PageOrder {
+            sort_by,
+            order: order.iter().map(|name| name.to_string()).collect(),
+        };
+
+        assert_eq!(
+            sorted(&order(SortBy::Natural, &[]), keys()),
+            ["p3.png", "p1.png", "p2.png", "p10.png"]
+        );
+        assert_eq!(
+            sorted(&order(SortBy::Modified, &[]), keys()),
+            ["p10.png", "p1.png", "p3.png", "p2.png"]
+        );
+        assert_eq!(
+            sorted(&order(SortBy::Exif, &[]), keys()),
+            ["p2.png", "p1.png", "p10.png", "p3.png"]
+        );
+        // listed pages first, by name or stem, the rest by the sort key
+        assert_eq!(
+            sorted(&order(SortBy::Modified, &["p2", "p3.png"]), keys()),
+            ["p2.png", "p3.png", "p10.png", "p1.png"]
+        );
+    }
+}
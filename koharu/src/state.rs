@@ -1,18 +1,29 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use anyhow::anyhow;
 use image::GenericImageView;
-use koharu_ml::font_detector::FontPrediction;
+use koharu_ml::{
+    font_detector::FontPrediction,
+    llm::{GenerateOptions, GlossaryEntry},
+};
 use koharu_renderer::renderer::TextShaderEffect;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::{
+    history::{History, Patch},
     image::SerializableDynamicImage,
-    khr::{deserialize_khr, has_khr_magic},
+    sort::natural_cmp,
 };
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TextBlock {
     pub x: f32,
@@ -27,7 +38,7 @@ pub struct TextBlock {
     pub rendered: Option<SerializableDynamicImage>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TextStyle {
     pub font_families: Vec<String>,
@@ -82,31 +93,23 @@ pub struct Document {
     pub inpainted: Option<SerializableDynamicImage>,
     pub rendered: Option<SerializableDynamicImage>,
     pub brush_layer: Option<SerializableDynamicImage>,
+    /// Bumped on every mutation, used by autosave to find changed documents.
+    #[serde(skip)]
+    pub revision: u64,
 }
 
 impl Document {
+    /// Read an image file. Projects and archives hold several documents and are
+    /// loaded by [`crate::operations::load_documents`].
     pub fn open(path: PathBuf) -> anyhow::Result<Self> {
         let bytes = std::fs::read(&path)?;
-
-        let documents = Self::from_bytes(path, bytes)?;
-        documents
-            .into_iter()
-            .next()
-            .ok_or_else(|| anyhow!("No document found in file"))
+        Self::from_bytes(path, bytes)
     }
 
-    pub fn from_bytes(path: impl Into<PathBuf>, bytes: Vec<u8>) -> anyhow::Result<Vec<Self>> {
+    /// Decode an image. The id is a hash of the bytes, byte-identical pages get a
+    /// suffix once they are added to a [`State`].
+    pub fn from_bytes(path: impl Into<PathBuf>, bytes: Vec<u8>) -> anyhow::Result<Self> {
         let path = path.into();
-
-        if has_khr_magic(&bytes) {
-            return deserialize_khr(&bytes)
-                .map_err(|err| anyhow!("Failed to load documents: {err}"));
-        }
-
-        Ok(vec![Self::image(path, bytes)?])
-    }
-
-    fn image(path: PathBuf, bytes: Vec<u8>) -> anyhow::Result<Self> {
         let img = image::load_from_memory(&bytes)?;
         let (width, height) = img.dimensions();
         let id = blake3::hash(&bytes).to_hex().to_string();
@@ -119,7 +122,7 @@ impl Document {
             id,
             path,
             name,
-            image: SerializableDynamicImage(img),
+            image: img.into(),
             width,
             height,
             ..Default::default()
@@ -127,9 +130,422 @@ impl Document {
     }
 }
 
+/// Addresses a document of a project, by its stable id or by its current position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocumentRef {
+    Id(String),
+    Index(usize),
+}
+
+impl DocumentRef {
+    /// Prefer the id when both are given, the index is kept for older clients.
+    pub fn new(id: Option<String>, index: Option<usize>) -> anyhow::Result<Self> {
+        match (id, index) {
+            (Some(id), _) => Ok(DocumentRef::Id(id)),
+            (None, Some(index)) => Ok(DocumentRef::Index(index)),
+            (None, None) => Err(anyhow!("Either a document id or an index is required")),
+        }
+    }
+}
+
+impl From<usize> for DocumentRef {
+    fn from(index: usize) -> Self {
+        DocumentRef::Index(index)
+    }
+}
+
+impl From<String> for DocumentRef {
+    fn from(id: String) -> Self {
+        DocumentRef::Id(id)
+    }
+}
+
+impl std::fmt::Display for DocumentRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DocumentRef::Id(id) => write!(f, "{id}"),
+            DocumentRef::Index(index) => write!(f, "#{index}"),
+        }
+    }
+}
+
+/// Project wide data that does not belong to a single page.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProjectMeta {
+    /// `ComicInfo.xml` of the archive the pages came from, written back on CBZ export.
+    pub comic_info: Option<String>,
+    /// Terms the translators have to use, checked after each translation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub glossary: Vec<GlossaryEntry>,
+    /// Sampling settings for translations, the translator's defaults when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generate_options: Option<GenerateOptions>,
+}
+
+impl ProjectMeta {
+    /// Take over fields from `other` that are not set yet.
+    pub fn merge(&mut self, other: ProjectMeta) {
+        if self.comic_info.is_none() {
+            self.comic_info = other.comic_info;
+        }
+        if self.glossary.is_empty() {
+            self.glossary = other.glossary;
+        }
+        if self.generate_options.is_none() {
+            self.generate_options = other.generate_options;
+        }
+    }
+}
+
+/// Documents read from files, with the project metadata stored alongside them.
+#[derive(Default, Debug, Clone)]
+pub struct LoadedDocuments {
+    pub documents: Vec<Document>,
+    pub meta: ProjectMeta,
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     pub documents: Vec<Document>,
+    #[serde(default)]
+    pub meta: ProjectMeta,
+    #[serde(skip)]
+    pub history: History,
+    /// Cancellation flags of the translations clients requested, by document id.
+    #[serde(skip)]
+    translations: HashMap<String, Weak<AtomicBool>>,
+}
+
+impl State {
+    /// Current position of a document.
+    pub fn position(&self, document: &DocumentRef) -> anyhow::Result<usize> {
+        let position = match document {
+            DocumentRef::Id(id) => self.documents.iter().position(|doc| &doc.id == id),
+            DocumentRef::Index(index) => (*index < self.documents.len()).then_some(*index),
+        };
+        position.ok_or_else(|| anyhow!("Document {document} not found"))
+    }
+
+    pub fn document(&self, document: &DocumentRef) -> anyhow::Result<&Document> {
+        let position = self.position(document)?;
+        Ok(&self.documents[position])
+    }
+
+    /// Replace the documents of the session, dropping the undo history of the old ones.
+    pub fn set_documents(&mut self, loaded: LoadedDocuments) {
+        let mut documents = loaded.documents;
+        unique_ids(&[], &mut documents);
+        self.documents = documents;
+        self.meta = loaded.meta;
+        self.history.clear();
+    }
+
+    /// Insert documents at `position`, or append them when omitted. Documents whose
+    /// id is already part of the project are skipped.
+    pub fn insert_documents(
+        &mut self,
+        position: Option<usize>,
+        documents: Vec<Document>,
+    ) -> anyhow::Result<()> {
+        let position = position.unwrap_or(self.documents.len());
+        if position > self.documents.len() {
+            return Err(anyhow!("Position {position} is out of range"));
+        }
+        let mut documents = documents;
+        documents.retain(|doc| !self.documents.iter().any(|existing| existing.id == doc.id));
+        unique_ids(&self.documents, &mut documents);
+        self.documents.splice(position..position, documents);
+        Ok(())
+    }
+
+    /// Merge documents into the project, skipping ids that are already present. Each
+    /// new page goes before the first existing page whose name sorts after it, so a
+    /// late drop of raws lands where it belongs without disturbing the existing
+    /// order. New pages that land between the same two pages keep the order they
+    /// were loaded in. Returns the number of documents added.
+    pub fn add_documents(&mut self, documents: Vec<Document>) -> usize {
+        let mut documents = documents;
+        documents.retain(|doc| !self.documents.iter().any(|existing| existing.id == doc.id));
+        unique_ids(&self.documents, &mut documents);
+        let mut pages = documents
+            .into_iter()
+            .map(|doc| {
+                let position = self
+                    .documents
+                    .iter()
+                    .position(|existing| natural_cmp(&existing.name, &doc.name).is_gt())
+                    .unwrap_or(self.documents.len());
+                (position, doc)
+            })
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|(position, _)| *position);
+
+        let added = pages.len();
+        // back to front, so the positions of the existing pages stay valid
+        for (position, doc) in pages.into_iter().rev() {
+            self.documents.insert(position, doc);
+        }
+        added
+    }
+
+    pub fn remove_document(&mut self, document: &DocumentRef) -> anyhow::Result<Document> {
+        let position = self.position(document)?;
+        let removed = self.documents.remove(position);
+        self.history.forget(&removed.id);
+        Ok(removed)
+    }
+
+    /// Reorder the documents to follow `ids`, which must list every document once.
+    pub fn reorder_documents(&mut self, ids: &[String]) -> anyhow::Result<()> {
+        if ids.len() != self.documents.len() {
+            return Err(anyhow!(
+                "Expected {} document ids, got {}",
+                self.documents.len(),
+                ids.len()
+            ));
+        }
+        let mut positions = Vec::with_capacity(ids.len());
+        for id in ids {
+            let position = self.position(&DocumentRef::Id(id.clone()))?;
+            if positions.contains(&position) {
+                return Err(anyhow!("Document {id} is listed twice"));
+            }
+            positions.push(position);
+        }
+
+        let mut documents = std::mem::take(&mut self.documents)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.documents = positions
+            .into_iter()
+            .filter_map(|position| documents[position].take())
+            .collect();
+        Ok(())
+    }
+
+    /// Store an updated copy of a document, bump its revision and record the change
+    /// as an undo step. The document is looked up by id, so pages added, removed or
+    /// reordered while an operation was running do not matter.
+    pub fn update_document(&mut self, document: Document) -> anyhow::Result<Document> {
+        let update = self.prepare_update(document)?;
+        self.finish_update(update)
+    }
+
+    /// Diff an updated copy of a document against the stored one for the undo
+    /// history, the slow part of [`State::update_document`] that only needs to read.
+    pub fn prepare_update(&self, document: Document) -> anyhow::Result<PreparedUpdate> {
+        let current = self.document(&DocumentRef::Id(document.id.clone()))?;
+        Ok(PreparedUpdate {
+            patch: Patch::diff(current, &document),
+            revision: current.revision,
+            document,
+        })
+    }
+
+    /// Store a prepared update, diffing again if the document changed meanwhile.
+    pub fn finish_update(&mut self, update: PreparedUpdate) -> anyhow::Result<Document> {
+        let position = self.position(&DocumentRef::Id(update.document.id.clone()))?;
+        let current = &mut self.documents[position];
+        match current.revision == update.revision {
+            true => self.history.push(&current.id, update.patch),
+            false => self.history.record(current, &update.document),
+        }
+        let revision = current.revision + 1;
+        *current = Document {
+            revision,
+            ..update.document
+        };
+        Ok(current.clone())
+    }
+
+    /// Cancellation flag for a translation of a document requested by a client,
+    /// shared by the translations running on that document at the same time.
+    pub fn translation_flag(&mut self, document_id: &str) -> Arc<AtomicBool> {
+        self.translations.retain(|_, flag| flag.strong_count() > 0);
+        if let Some(flag) = self.translations.get(document_id).and_then(Weak::upgrade)
+            && !flag.load(Ordering::Relaxed)
+        {
+            return flag;
+        }
+        let flag = Arc::new(AtomicBool::new(false));
+        self.translations
+            .insert(document_id.to_string(), Arc::downgrade(&flag));
+        flag
+    }
+
+    /// Stop the translations clients requested on a document, or on every document
+    /// when `document_id` is omitted.
+    pub fn cancel_translations(&self, document_id: Option<&str>) {
+        self.translations
+            .iter()
+            .filter(|(id, _)| document_id.is_none_or(|document_id| *id == document_id))
+            .filter_map(|(_, flag)| flag.upgrade())
+            .for_each(|flag| flag.store(true, Ordering::Relaxed));
+    }
+
+    /// Revert the last change of a document.
+    pub fn undo(&mut self, document: &DocumentRef) -> anyhow::Result<Document> {
+        let position = self.position(document)?;
+        let document = &mut self.documents[position];
+        if !self.history.undo(document) {
+            return Err(anyhow!("Nothing to undo"));
+        }
+        document.revision += 1;
+        Ok(document.clone())
+    }
+
+    /// Re-apply the last undone change of a document.
+    pub fn redo(&mut self, document: &DocumentRef) -> anyhow::Result<Document> {
+        let position = self.position(document)?;
+        let document = &mut self.documents[position];
+        if !self.history.redo(document) {
+            return Err(anyhow!("Nothing to redo"));
+        }
+        document.revision += 1;
+        Ok(document.clone())
+    }
 }
 
 pub type AppState = Arc<RwLock<State>>;
+
+/// An updated document with its undo step, see [`State::prepare_update`].
+pub struct PreparedUpdate {
+    document: Document,
+    /// Revision of the stored document the patch was computed against.
+    revision: u64,
+    patch: Patch,
+}
+
+/// Store an updated document, diffing it for the undo history under the read
+/// lock so other readers are not held up.
+pub async fn update_document(state: &AppState, document: Document) -> anyhow::Result<Document> {
+    let update = state.read().await.prepare_update(document)?;
+    state.write().await.finish_update(update)
+}
+
+/// Byte-identical pages hash to the same id. Number the repeats, `<hash>-2` and so
+/// on, so every page of a project can be edited, reordered and journaled on its own.
+fn unique_ids(existing: &[Document], documents: &mut [Document]) {
+    let mut taken = existing
+        .iter()
+        .map(|doc| doc.id.clone())
+        .collect::<HashSet<_>>();
+    for doc in documents {
+        if taken.insert(doc.id.clone()) {
+            continue;
+        }
+        let id = (2..)
+            .map(|n| format!("{}-{n}", doc.id))
+            .find(|id| !taken.contains(id))
+            .expect("a free suffix");
+        taken.insert(id.clone());
+        doc.id = id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(name: &str) -> Document {
+        Document {
+            id: name.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn names(state: &State) -> Vec<&str> {
+        state
+            .documents
+            .iter()
+            .map(|doc| doc.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn numbers_identical_pages() -> anyhow::Result<()> {
+        let mut bytes = Vec::new();
+        image::DynamicImage::new_rgba8(2, 2).write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )?;
+        let page = |name: &str| Document::from_bytes(format!("{name}.png"), bytes.clone());
+
+        let mut state = State::default();
+        state.set_documents(LoadedDocuments {
+            documents: vec![page("p1")?, page("p2")?],
+            ..Default::default()
+        });
+        let ids = state
+            .documents
+            .iter()
+            .map(|doc| doc.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids[1], format!("{}-2", ids[0]));
+
+        // an edit by id reaches the second page only
+        let mut edited = state.documents[1].clone();
+        edited.text_blocks.push(TextBlock::default());
+        state.update_document(edited)?;
+        assert!(state.documents[0].text_blocks.is_empty());
+        assert_eq!(state.documents[1].text_blocks.len(), 1);
+
+        state.reorder_documents(&[ids[1].clone(), ids[0].clone()])?;
+        assert_eq!(names(&state), ["p2", "p1"]);
+
+        // pages already in the project are still skipped when dropped again
+        assert_eq!(state.add_documents(vec![page("p1")?]), 0);
+        state.insert_documents(None, vec![page("p3")?])?;
+        assert_eq!(state.documents.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cancels_translations_of_a_document() {
+        let mut state = State::default();
+        let (a, b) = (state.translation_flag("a"), state.translation_flag("b"));
+        assert!(Arc::ptr_eq(&a, &state.translation_flag("a")));
+
+        state.cancel_translations(Some("a"));
+        assert!(a.load(Ordering::Relaxed));
+        assert!(!b.load(Ordering::Relaxed));
+        // a new translation is not cancelled along with the earlier one
+        assert!(!state.translation_flag("a").load(Ordering::Relaxed));
+
+        state.cancel_translations(None);
+        assert!(b.load(Ordering::Relaxed));
+        drop((a, b));
+        state.translation_flag("c");
+        assert_eq!(state.translations.len(), 1);
+    }
+
+    #[test]
+    fn adds_documents_in_natural_order() {
+        let mut state = State::default();
+        let open = ["p1", "p2", "p4", "p6", "p10"];
+        state.add_documents(open.into_iter().map(document).collect());
+
+        let added = state.add_documents(vec![
+            document("p5"),
+            document("p11"),
+            document("p3"),
+            document("p2"),
+        ]);
+        assert_eq!(added, 3);
+        assert_eq!(
+            names(&state),
+            ["p1", "p2", "p3", "p4", "p5", "p6", "p10", "p11"]
+        );
+
+        // pages landing in the same gap keep the order they were loaded in
+        state.add_documents(vec![document("p9"), document("p7")]);
+        assert_eq!(
+            names(&state),
+            ["p1", "p2", "p3", "p4", "p5", "p6", "p9", "p7", "p10", "p11"]
+        );
+        assert_eq!(state.add_documents(vec![document("p1")]), 0);
+    }
+}